@@ -0,0 +1,258 @@
+#!/usr/bin/env python3
+"""Reference logits of the tiny checkpoints used by the tests of src/llama.rs and src/neox.rs.
+
+The models are written out in plain Python with float64, following the Hugging Face
+implementations, so the expected values don't depend on this crate nor on torch. The weights are
+the pseudo random values of src/testing.rs: tensor k of the checkpoint uses the seed 7 * k + 1.
+
+usage: python3 scripts/reference_logits.py llama|mixtral|neox|neox-sequential
+"""
+import math
+import sys
+
+IDS = [1, 5, 9, 2, 7]
+
+
+def pseudo_random(rows, cols, seed):
+    return [[math.sin((i * 31 + j * 17 + seed) * 0.37) * 0.2 for j in range(cols)] for i in range(rows)]
+
+
+def checkpoint(shapes, init):
+    weights = {}
+    for k, (name, shape) in enumerate(shapes):
+        seed = 7 * k + 1
+        if len(shape) == 1:
+            weights[name] = [init(name, x) for x in pseudo_random(1, shape[0], seed)[0]]
+        else:
+            weights[name] = [[init(name, x) for x in row] for row in pseudo_random(*shape, seed)]
+    return weights
+
+
+def linear(x, w, b=None):
+    # x is (tokens, in) and w is (out, in) like the weights of torch.nn.Linear
+    return [[sum(a * c for a, c in zip(row, wr)) + (b[o] if b else 0.0) for o, wr in enumerate(w)] for row in x]
+
+
+def add(*xs):
+    return [[sum(values) for values in zip(*rows)] for rows in zip(*xs)]
+
+
+def rms_norm(x, w, eps):
+    out = []
+    for row in x:
+        s = math.sqrt(sum(v * v for v in row) / len(row) + eps)
+        out.append([v / s * g for v, g in zip(row, w)])
+    return out
+
+
+def layer_norm(x, w, b, eps):
+    out = []
+    for row in x:
+        m = sum(row) / len(row)
+        s = math.sqrt(sum((v - m) ** 2 for v in row) / len(row) + eps)
+        out.append([(v - m) / s * g + c for v, g, c in zip(row, w, b)])
+    return out
+
+
+def rope(vec, pos, rotary_dim, base):
+    # rotate_half on the first rotary_dim dimensions, the others are left as they are
+    half = rotary_dim // 2
+    out = list(vec)
+    for i in range(half):
+        f = 1.0 / base ** (2 * i / rotary_dim)
+        c, s = math.cos(pos * f), math.sin(pos * f)
+        x1, x2 = vec[i], vec[i + half]
+        out[i] = x1 * c - x2 * s
+        out[i + half] = x2 * c + x1 * s
+    return out
+
+
+def causal_attention(qs, ks, vs):
+    # one head, qs ks and vs are (tokens, head_dim) with the rotary embeddings applied
+    out = []
+    for t, q in enumerate(qs):
+        scores = [sum(a * b for a, b in zip(q, ks[u])) / math.sqrt(len(q)) for u in range(t + 1)]
+        m = max(scores)
+        e = [math.exp(s - m) for s in scores]
+        z = sum(e)
+        out.append([sum(e[u] / z * vs[u][d] for u in range(t + 1)) for d in range(len(q))])
+    return out
+
+
+def swiglu(x, w, prefix, names):
+    gate = linear(x, w[prefix + names[0]])
+    up = linear(x, w[prefix + names[1]])
+    y = [[g / (1 + math.exp(-g)) * u for g, u in zip(gr, ur)] for gr, ur in zip(gate, up)]
+    return linear(y, w[prefix + names[2]])
+
+
+def llama(num_experts=None, top_k=2):
+    # with num_experts it is a Mixtral, the MLP of every block is a sparse mixture of experts
+    h, nh, nkv, inter, layers, vocab = 16, 4, 2, 24, 2, 12
+    hd = h // nh
+    eps, theta = 1e-6, 10000.0
+
+    shapes = [("model.embed_tokens.weight", (vocab, h))]
+    for l in range(layers):
+        p = f"model.layers.{l}."
+        shapes += [
+            (p + "input_layernorm.weight", (h,)),
+            (p + "self_attn.q_proj.weight", (nh * hd, h)),
+            (p + "self_attn.k_proj.weight", (nkv * hd, h)),
+            (p + "self_attn.v_proj.weight", (nkv * hd, h)),
+            (p + "self_attn.o_proj.weight", (h, nh * hd)),
+            (p + "post_attention_layernorm.weight", (h,)),
+        ]
+        if num_experts is None:
+            shapes += [
+                (p + "mlp.gate_proj.weight", (inter, h)),
+                (p + "mlp.up_proj.weight", (inter, h)),
+                (p + "mlp.down_proj.weight", (h, inter)),
+            ]
+        else:
+            shapes.append((p + "block_sparse_moe.gate.weight", (num_experts, h)))
+            for e in range(num_experts):
+                shapes += [
+                    (p + f"block_sparse_moe.experts.{e}.w1.weight", (inter, h)),
+                    (p + f"block_sparse_moe.experts.{e}.w3.weight", (inter, h)),
+                    (p + f"block_sparse_moe.experts.{e}.w2.weight", (h, inter)),
+                ]
+    shapes += [("model.norm.weight", (h,)), ("lm_head.weight", (vocab, h))]
+
+    def init(name, x):
+        # the norm weights are around 1, the router has larger scores so that it picks clear winners
+        if name.endswith("norm.weight"):
+            return x + 1.0
+        return x * 10.0 if name.endswith("gate.weight") else x
+
+    w = checkpoint(shapes, init)
+
+    x = [list(w["model.embed_tokens.weight"][t]) for t in IDS]
+    n = len(IDS)
+    for l in range(layers):
+        p = f"model.layers.{l}."
+        y = rms_norm(x, w[p + "input_layernorm.weight"], eps)
+        q = linear(y, w[p + "self_attn.q_proj.weight"])
+        k = linear(y, w[p + "self_attn.k_proj.weight"])
+        v = linear(y, w[p + "self_attn.v_proj.weight"])
+        heads = [[] for _ in range(n)]
+        for head in range(nh):
+            # grouped-query attention, consecutive query heads share a key and value head
+            kv = head // (nh // nkv)
+            qs = [rope(q[t][head * hd:(head + 1) * hd], t, hd, theta) for t in range(n)]
+            ks = [rope(k[t][kv * hd:(kv + 1) * hd], t, hd, theta) for t in range(n)]
+            vs = [v[t][kv * hd:(kv + 1) * hd] for t in range(n)]
+            for t, row in enumerate(causal_attention(qs, ks, vs)):
+                heads[t] += row
+        x = add(x, linear(heads, w[p + "self_attn.o_proj.weight"]))
+        y = rms_norm(x, w[p + "post_attention_layernorm.weight"], eps)
+        if num_experts is None:
+            x = add(x, swiglu(y, w, p + "mlp.", ["gate_proj.weight", "up_proj.weight", "down_proj.weight"]))
+            continue
+        # the top_k experts of every token, weighted by the softmax of the router logits over all the
+        # experts renormalized over the chosen ones
+        out = []
+        for row, logits in zip(y, linear(y, w[p + "block_sparse_moe.gate.weight"])):
+            m = max(logits)
+            probs = [math.exp(v - m) for v in logits]
+            chosen = sorted(range(num_experts), key=lambda e: -probs[e])[:top_k]
+            total = sum(probs[e] for e in chosen)
+            mixed = [0.0] * h
+            for e in chosen:
+                names = ["w1.weight", "w3.weight", "w2.weight"]
+                expert = swiglu([row], w, p + f"block_sparse_moe.experts.{e}.", names)[0]
+                mixed = [a + probs[e] / total * b for a, b in zip(mixed, expert)]
+            out.append(mixed)
+        x = add(x, out)
+    x = rms_norm(x, w["model.norm.weight"], eps)
+    return linear(x, w["lm_head.weight"])
+
+
+def neox(parallel):
+    h, nh, inter, layers, vocab = 16, 4, 32, 2, 12
+    hd = h // nh
+    rotary_dim = int(hd * 0.5)
+    eps, base = 1e-5, 10000.0
+
+    shapes = [("gpt_neox.embed_in.weight", (vocab, h))]
+    for l in range(layers):
+        p = f"gpt_neox.layers.{l}."
+        shapes += [
+            (p + "input_layernorm.weight", (h,)),
+            (p + "input_layernorm.bias", (h,)),
+            (p + "post_attention_layernorm.weight", (h,)),
+            (p + "post_attention_layernorm.bias", (h,)),
+            (p + "attention.query_key_value.weight", (3 * h, h)),
+            (p + "attention.query_key_value.bias", (3 * h,)),
+            (p + "attention.dense.weight", (h, h)),
+            (p + "attention.dense.bias", (h,)),
+            (p + "mlp.dense_h_to_4h.weight", (inter, h)),
+            (p + "mlp.dense_h_to_4h.bias", (inter,)),
+            (p + "mlp.dense_4h_to_h.weight", (h, inter)),
+            (p + "mlp.dense_4h_to_h.bias", (h,)),
+        ]
+    shapes += [
+        ("gpt_neox.final_layer_norm.weight", (h,)),
+        ("gpt_neox.final_layer_norm.bias", (h,)),
+        ("embed_out.weight", (vocab, h)),
+    ]
+
+    def init(name, x):
+        # layer norm weights around 1, biases small, larger embeddings and output layer
+        if name.endswith("norm.weight"):
+            return x + 1.0
+        if name == "gpt_neox.embed_in.weight":
+            return x * 5.0
+        if name == "embed_out.weight":
+            return x * 10.0
+        return x * 0.1 if name.endswith("bias") else x
+
+    w = checkpoint(shapes, init)
+
+    def gelu(v):
+        return 0.5 * v * (1 + math.erf(v / math.sqrt(2)))
+
+    x = [list(w["gpt_neox.embed_in.weight"][t]) for t in IDS]
+    n = len(IDS)
+    for l in range(layers):
+        p = f"gpt_neox.layers.{l}."
+        y = layer_norm(x, w[p + "input_layernorm.weight"], w[p + "input_layernorm.bias"], eps)
+        qkv = linear(y, w[p + "attention.query_key_value.weight"], w[p + "attention.query_key_value.bias"])
+        heads = [[] for _ in range(n)]
+        for head in range(nh):
+            # the fused projection is interleaved per head, [q, k, v] for every head
+            start = head * 3 * hd
+            qs = [rope(qkv[t][start:start + hd], t, rotary_dim, base) for t in range(n)]
+            ks = [rope(qkv[t][start + hd:start + 2 * hd], t, rotary_dim, base) for t in range(n)]
+            vs = [qkv[t][start + 2 * hd:start + 3 * hd] for t in range(n)]
+            for t, row in enumerate(causal_attention(qs, ks, vs)):
+                heads[t] += row
+        attention = linear(heads, w[p + "attention.dense.weight"], w[p + "attention.dense.bias"])
+
+        def mlp(y):
+            y = layer_norm(y, w[p + "post_attention_layernorm.weight"], w[p + "post_attention_layernorm.bias"], eps)
+            y = linear(y, w[p + "mlp.dense_h_to_4h.weight"], w[p + "mlp.dense_h_to_4h.bias"])
+            y = [[gelu(v) for v in row] for row in y]
+            return linear(y, w[p + "mlp.dense_4h_to_h.weight"], w[p + "mlp.dense_4h_to_h.bias"])
+
+        if parallel:
+            x = add(x, attention, mlp(x))
+        else:
+            x = add(x, attention)
+            x = add(x, mlp(x))
+    x = layer_norm(x, w["gpt_neox.final_layer_norm.weight"], w["gpt_neox.final_layer_norm.bias"], eps)
+    return linear(x, w["embed_out.weight"])
+
+
+MODELS = {
+    "llama": llama,
+    "mixtral": lambda: llama(num_experts=3),
+    "neox": lambda: neox(parallel=True),
+    "neox-sequential": lambda: neox(parallel=False),
+}
+
+if __name__ == "__main__":
+    if len(sys.argv) != 2 or sys.argv[1] not in MODELS:
+        sys.exit(__doc__)
+    for row in MODELS[sys.argv[1]]():
+        print("[" + ", ".join(f"{v:.6g}" for v in row) + "],")
@@ -0,0 +1,88 @@
+use ndarray::{Array, ArrayView, Ix2};
+
+// sequences of different lengths padded on the left to the same length, so that the last
+// column holds the last token of every sequence
+pub struct Batch {
+    ids: Array<usize, Ix2>, // (batch, seq)
+    mask: Array<bool, Ix2>, // (batch, seq), false for the padding tokens
+}
+
+impl Batch {
+    pub fn new(sequences: &[Vec<usize>]) -> Batch {
+        let seq_len = sequences.iter().map(|ids| ids.len()).max().unwrap_or(0);
+        let padding = |ids: &Vec<usize>| seq_len - ids.len();
+
+        // the padding id does not matter, padding tokens are never attended to
+        let ids = Array::from_shape_fn((sequences.len(), seq_len), |(i, j)| {
+            let ids = &sequences[i];
+            j.checked_sub(padding(ids)).map_or(0, |j| ids[j])
+        });
+        let mask = Array::from_shape_fn((sequences.len(), seq_len), |(i, j)| {
+            j >= padding(&sequences[i])
+        });
+
+        Batch { ids, mask }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.shape()[0]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn seq_len(&self) -> usize {
+        self.ids.shape()[1]
+    }
+
+    pub fn ids(&self) -> ArrayView<'_, usize, Ix2> {
+        self.ids.view()
+    }
+
+    pub fn mask(&self) -> ArrayView<'_, bool, Ix2> {
+        self.mask.view()
+    }
+}
+
+pub fn position_ids(mask: &ArrayView<bool, Ix2>) -> Array<usize, Ix2> {
+    // every sequence starts at position 0 after its padding, padding tokens get position 0 too
+    let mut positions = Array::zeros(mask.raw_dim());
+    for (mask, mut positions) in mask.outer_iter().zip(positions.outer_iter_mut()) {
+        let mut count = 0;
+        for (&keep, position) in mask.iter().zip(positions.iter_mut()) {
+            if keep {
+                *position = count;
+                count += 1;
+            }
+        }
+    }
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_left_padding() {
+        let batch = Batch::new(&[vec![5, 6, 7], vec![8], vec![9, 10]]);
+
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch.seq_len(), 3);
+        assert_eq!(batch.ids(), array![[5, 6, 7], [0, 0, 8], [0, 9, 10]]);
+        assert_eq!(
+            batch.mask(),
+            array![
+                [true, true, true],
+                [false, false, true],
+                [false, true, true]
+            ]
+        );
+        assert_eq!(
+            position_ids(&batch.mask()),
+            array![[0, 1, 2], [0, 0, 0], [0, 0, 1]]
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use crate::detokenizer::IncrementalDecoder;
+use crate::error::GenerateError;
+use crate::float::MyFloat;
+use crate::generation::{log_softmax, FinishReason};
+use crate::gpt2::ContextOverflow;
+use crate::model::CausalLM;
+use ndarray::{s, Array, Axis, Ix2};
+use tokenizers::Tokenizer;
+
+#[derive(Clone, Debug)]
+pub struct BeamSearchConfig {
+    pub num_beams: usize,
+    pub max_tokens: usize,
+    // the score of a hypothesis is its log probability divided by its length to this power.
+    // Above 0 longer hypotheses are favored, below 0 shorter ones
+    pub length_penalty: f32,
+    // stop as soon as num_beams hypotheses are finished, instead of when the running beams
+    // can't beat them anymore
+    pub early_stopping: bool,
+    // number of hypotheses returned, the best first
+    pub num_return: usize,
+    // the eos tokens of the model when None
+    pub eos_tokens: Option<Vec<usize>>,
+}
+
+impl Default for BeamSearchConfig {
+    fn default() -> Self {
+        BeamSearchConfig {
+            num_beams: 4,
+            max_tokens: 16,
+            length_penalty: 1.0,
+            early_stopping: false,
+            num_return: 1,
+            eos_tokens: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hypothesis {
+    // generated tokens, the eos token included
+    pub ids: Vec<usize>,
+    // text of the generated tokens, without the eos token
+    pub text: String,
+    // sum of the log probabilities of the tokens
+    pub logprob: f32,
+    pub score: f32,
+    pub finish_reason: FinishReason,
+}
+
+struct Beam {
+    ids: Vec<usize>,
+    logprob: f32,
+}
+
+struct Candidate {
+    beam: usize,
+    id: usize,
+    logprob: f32,
+}
+
+pub struct BeamSearch<'a, T>
+where
+    T: MyFloat,
+{
+    model: &'a dyn CausalLM<T>,
+    tokenizer: &'a Tokenizer,
+}
+
+impl<'a, T> BeamSearch<'a, T>
+where
+    T: MyFloat,
+{
+    pub fn new(model: &'a dyn CausalLM<T>, tokenizer: &'a Tokenizer) -> BeamSearch<'a, T> {
+        BeamSearch { model, tokenizer }
+    }
+
+    pub fn search(
+        &self,
+        prompt: &str,
+        config: &BeamSearchConfig,
+    ) -> Result<Vec<Hypothesis>, GenerateError> {
+        let encoding = self
+            .tokenizer
+            .encode(prompt, false)
+            .map_err(|e| GenerateError::Tokenizer(e.to_string()))?;
+        let prompt_ids = encoding.get_ids().iter().map(|&x| x as usize).collect();
+        self.search_ids(prompt_ids, config)
+    }
+
+    pub fn search_ids(
+        &self,
+        prompt_ids: Vec<usize>,
+        config: &BeamSearchConfig,
+    ) -> Result<Vec<Hypothesis>, GenerateError> {
+        let mut cache = self.model.new_cache();
+        self.model
+            .context_start(prompt_ids.len(), &cache, ContextOverflow::Error)?;
+        if config.max_tokens == 0 || config.num_beams == 0 {
+            return Ok(Vec::new());
+        }
+        let num_beams = config.num_beams;
+        let eos_tokens = config
+            .eos_tokens
+            .clone()
+            .unwrap_or_else(|| self.model.eos_tokens());
+        let score = |logprob: f32, len: usize| logprob / (len as f32).powf(config.length_penalty);
+
+        // the prompt goes through the model once, the beams are rows of a batch that starts with
+        // a single one. At every step the cache rows are copied from the parent of each new beam,
+        // so the common prefixes are never computed again
+        let output = self.model.forward_with_cache(&prompt_ids, &mut cache);
+        let mut logits = output.slice(s![-1.., ..]).to_owned(); // (beams, vocab)
+        let mut beams = vec![Beam {
+            ids: Vec::new(),
+            logprob: 0.0,
+        }];
+        let mut finished: Vec<Hypothesis> = Vec::new();
+
+        loop {
+            let step = beams[0].ids.len() + 1;
+            let length =
+                step >= config.max_tokens || prompt_ids.len() + step > self.model.n_positions();
+
+            // twice as many candidates as beams, so that there are enough of them left when
+            // some end with an eos token
+            let mut candidates = Vec::new();
+            for (i, (beam, logits)) in beams.iter().zip(logits.outer_iter()).enumerate() {
+                let logprobs = log_softmax(&logits);
+                let mut ids: Vec<usize> = (0..logprobs.len()).collect();
+                let k = (2 * num_beams).min(ids.len());
+                ids.select_nth_unstable_by(k - 1, |&a, &b| logprobs[b].total_cmp(&logprobs[a]));
+                candidates.extend(ids[..k].iter().map(|&id| Candidate {
+                    beam: i,
+                    id,
+                    logprob: beam.logprob + logprobs[id],
+                }));
+            }
+            candidates.sort_by(|a, b| b.logprob.total_cmp(&a.logprob));
+
+            let mut next = Vec::new();
+            let mut parents = Vec::new();
+            for (rank, candidate) in candidates.into_iter().enumerate() {
+                let mut ids = beams[candidate.beam].ids.clone();
+                ids.push(candidate.id);
+
+                if eos_tokens.contains(&candidate.id) {
+                    // an eos token that doesn't rank among the best beams is not kept
+                    if rank < num_beams {
+                        finished.push(Hypothesis {
+                            score: score(candidate.logprob, ids.len()),
+                            ids,
+                            text: String::new(),
+                            logprob: candidate.logprob,
+                            finish_reason: FinishReason::Eos,
+                        });
+                    }
+                } else {
+                    parents.push(candidate.beam);
+                    next.push(Beam {
+                        ids,
+                        logprob: candidate.logprob,
+                    });
+                    if next.len() == num_beams {
+                        break;
+                    }
+                }
+            }
+            finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+            if length || next.is_empty() {
+                finished.extend(next.into_iter().map(|beam| Hypothesis {
+                    score: score(beam.logprob, beam.ids.len()),
+                    ids: beam.ids,
+                    text: String::new(),
+                    logprob: beam.logprob,
+                    finish_reason: FinishReason::Length,
+                }));
+                break;
+            }
+            if finished.len() >= num_beams {
+                // the best running beam is the most likely to beat the finished hypotheses, if
+                // it can't the search is over
+                let worst = finished[num_beams - 1].score;
+                let best_running = score(next[0].logprob, next[0].ids.len());
+                if config.early_stopping || worst >= best_running {
+                    break;
+                }
+            }
+
+            cache.select(&parents, beams.len());
+            let ids: Vec<usize> = next.iter().map(|beam| *beam.ids.last().unwrap()).collect();
+            let ids = Array::from_shape_vec((ids.len(), 1), ids).unwrap();
+            let mask = Array::<bool, Ix2>::from_elem((ids.nrows(), cache.len() + 1), true);
+            logits = self
+                .model
+                .forward_batch_with_cache(&ids.view(), &mask.view(), &mut cache)
+                .index_axis_move(Axis(1), 0);
+            beams = next;
+        }
+
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(config.num_return);
+        for hypothesis in finished.iter_mut() {
+            hypothesis.text = self.decode(&prompt_ids, &hypothesis.ids, &eos_tokens);
+        }
+        Ok(finished)
+    }
+
+    fn decode(&self, prompt_ids: &[usize], ids: &[usize], eos_tokens: &[usize]) -> String {
+        let mut decoder = IncrementalDecoder::new_with_context(self.tokenizer, prompt_ids);
+        let mut text: String = ids
+            .iter()
+            .filter(|id| !eos_tokens.contains(id))
+            .map(|&id| decoder.push(id))
+            .collect();
+        text.push_str(&decoder.flush());
+        text
+    }
+}
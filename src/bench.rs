@@ -1,12 +1,13 @@
-use std::fs::File;
+#[path = "bin/common/mod.rs"]
+mod common;
+
 // use std::io;
-use std::io::prelude::*;
+use std::path::Path;
 use std::time::Instant;
 
-use rusty_llm::gpt2::GPT;
-
-use safetensors::SafeTensors;
-use tokenizers::Tokenizer;
+use common::{load_model, load_tokenizer, read_config};
+use rusty_llm::model::CausalLM;
+use rusty_llm::sampling::Greedy;
 
 // use std::env;
 // use std::process;
@@ -30,16 +31,9 @@ fn main() {
     //     }
     // };
 
-    let mut f = File::open("models/model.safetensors").unwrap();
-    let mut buffer = Vec::new();
-
-    // read the whole file
-    f.read_to_end(&mut buffer).unwrap();
-    let tensors: SafeTensors = SafeTensors::deserialize(&buffer).unwrap();
-
-    let gpt = GPT::<f32>::load_from_safe_tensors(&tensors, 12);
-
-    let tokenizer = Tokenizer::from_file("tokenizer/tokenizer.json").unwrap();
+    let config = read_config(Path::new("models/config.json"));
+    let model = load_model::<f32>(Path::new("models/model.safetensors"), &config);
+    let tokenizer = load_tokenizer(Path::new("tokenizer/tokenizer.json"));
 
     // println!("========== GPT 2 ================");
     //
@@ -66,8 +60,11 @@ fn main() {
 
     let start = Instant::now();
 
+    let mut cache = model.new_cache();
+
     for _ in 0..number {
-        let new_word_id = gpt.generate(&ids);
+        // only the tokens that are not in the cache yet need to go through the model
+        let new_word_id = model.generate_with_cache(&ids[cache.len()..], &mut cache, &mut Greedy);
 
         ids.push(new_word_id);
 
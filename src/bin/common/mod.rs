@@ -0,0 +1,102 @@
+// what the binaries share to find, load and run a model. It is not part of the library, which
+// returns its errors instead of exiting, and every binary only uses part of it
+#![allow(dead_code)]
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clap::{Args, ValueEnum};
+
+use rusty_llm::config::ModelConfig;
+use rusty_llm::convert::SafeTensorsFile;
+use rusty_llm::float::MyFloat;
+use rusty_llm::model::{load_from_safe_tensors, CausalLM};
+
+use tokenizers::Tokenizer;
+
+/// Where the model is and the type it runs in
+#[derive(Args, Debug)]
+pub struct ModelArgs {
+    /// Weights of the model
+    #[arg(
+        short = 'm',
+        long = "model",
+        default_value = "models/model.safetensors"
+    )]
+    pub weights: PathBuf,
+
+    /// Hugging Face config.json of the model, its model_type picks the architecture. Defaults to
+    /// the config.json next to the weights, GPT-2 small is assumed when it doesn't exist
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Hugging Face tokenizer.json
+    #[arg(short, long, default_value = "tokenizer/tokenizer.json")]
+    pub tokenizer: PathBuf,
+
+    /// Type used for the weights and the computations
+    #[arg(long, value_enum, default_value_t = Dtype::F32)]
+    pub dtype: Dtype,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Dtype {
+    F32,
+    F16,
+    Bf16,
+}
+
+impl ModelArgs {
+    pub fn config_path(&self) -> PathBuf {
+        self.config
+            .clone()
+            .unwrap_or_else(|| self.weights.with_file_name("config.json"))
+    }
+
+    pub fn read_config(&self) -> ModelConfig {
+        read_config(&self.config_path())
+    }
+
+    pub fn load_tokenizer(&self) -> Tokenizer {
+        load_tokenizer(&self.tokenizer)
+    }
+
+    pub fn load_model<T: MyFloat>(&self, config: &ModelConfig) -> Box<dyn CausalLM<T>> {
+        load_model(&self.weights, config)
+    }
+}
+
+pub fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+pub fn read_config(path: &Path) -> ModelConfig {
+    // only a missing file falls back to GPT-2 small, a config that can't be used is an error. The
+    // warning goes to stderr even with --quiet, it doesn't mix with the output
+    match ModelConfig::from_file(path) {
+        Ok(config) => config,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!(
+                "Could not read {} ({}), assuming GPT-2 small",
+                path.display(),
+                e
+            );
+            ModelConfig::default()
+        }
+        Err(e) => fail(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+pub fn load_tokenizer(path: &Path) -> Tokenizer {
+    Tokenizer::from_file(path)
+        .unwrap_or_else(|e| fail(format!("Failed to load {}: {}", path.display(), e)))
+}
+
+pub fn load_model<T: MyFloat>(path: &Path, config: &ModelConfig) -> Box<dyn CausalLM<T>> {
+    // the weights are memory mapped, they are only copied when they need a dtype conversion
+    SafeTensorsFile::open(path)
+        .and_then(|file| load_from_safe_tensors::<T, _>(&file.tensors()?, config))
+        .unwrap_or_else(|e| fail(format!("Failed to load {}: {}", path.display(), e)))
+}
@@ -0,0 +1,144 @@
+mod common;
+
+use std::fs;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use clap::Parser;
+use half::{bf16, f16};
+
+use common::{fail, Dtype, ModelArgs};
+use rusty_llm::config::ModelConfig;
+use rusty_llm::eval::{Example, Scorer};
+use rusty_llm::float::MyFloat;
+
+use tokenizers::Tokenizer;
+
+/// Measure the accuracy of a GPT-2, LLaMA, Mixtral or GPT-NeoX model on a multiple-choice benchmark
+///
+/// Every line of the dataset is a JSON object with a context, its choices and the index of the
+/// right one: {"context": "...", "choices": ["...", "..."], "label": 0}
+#[derive(Parser, Debug)]
+struct Cli {
+    /// JSONL dataset
+    file: PathBuf,
+
+    #[command(flatten)]
+    model: ModelArgs,
+
+    /// Only evaluate the first examples of the dataset
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Write the prediction for every example to this JSONL file
+    #[arg(long)]
+    predictions: Option<PathBuf>,
+}
+
+fn read_examples(text: &str) -> Result<Vec<Example>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let example: Example =
+                serde_json::from_str(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+            if example.label >= example.choices.len() {
+                return Err(format!(
+                    "line {}: label {} but {} choices",
+                    i + 1,
+                    example.label,
+                    example.choices.len()
+                ));
+            }
+            Ok(example)
+        })
+        .collect()
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let config = cli.model.read_config();
+    let tokenizer = cli.model.load_tokenizer();
+
+    match cli.model.dtype {
+        Dtype::F32 => run::<f32>(&cli, &config, &tokenizer),
+        Dtype::F16 => run::<f16>(&cli, &config, &tokenizer),
+        Dtype::Bf16 => run::<bf16>(&cli, &config, &tokenizer),
+    }
+}
+
+fn run<T: MyFloat>(cli: &Cli, config: &ModelConfig, tokenizer: &Tokenizer) {
+    let model = cli.model.load_model::<T>(config);
+
+    let text = fs::read_to_string(&cli.file)
+        .unwrap_or_else(|e| fail(format!("Failed to read {}: {}", cli.file.display(), e)));
+    let mut examples = read_examples(&text)
+        .unwrap_or_else(|e| fail(format!("Invalid dataset {}: {}", cli.file.display(), e)));
+    examples.truncate(cli.limit.unwrap_or(examples.len()));
+    if examples.is_empty() {
+        fail(format!("{} has no examples", cli.file.display()));
+    }
+
+    let mut predictions = cli.predictions.as_ref().map(|path| {
+        File::create(path)
+            .map(BufWriter::new)
+            .unwrap_or_else(|e| fail(format!("Failed to create {}: {}", path.display(), e)))
+    });
+
+    let scorer = Scorer::new(&*model, tokenizer);
+    let (mut correct, mut correct_normalized) = (0, 0);
+    for (i, example) in examples.iter().enumerate() {
+        let prediction = scorer
+            .predict(example)
+            .unwrap_or_else(|e| fail(format!("Failed to score example {}: {}", i, e)));
+        correct += (prediction.prediction == prediction.label) as usize;
+        correct_normalized += (prediction.normalized_prediction == prediction.label) as usize;
+
+        if let Some(file) = predictions.as_mut() {
+            writeln!(file, "{}", serde_json::to_string(&prediction).unwrap())
+                .unwrap_or_else(|e| fail(format!("Failed to write the predictions: {}", e)));
+        }
+    }
+    if let Some(mut file) = predictions {
+        file.flush()
+            .unwrap_or_else(|e| fail(format!("Failed to write the predictions: {}", e)));
+    }
+
+    let total = examples.len() as f64;
+    println!("examples:            {}", examples.len());
+    println!("accuracy:            {:.4}", correct as f64 / total);
+    println!(
+        "normalized accuracy: {:.4}",
+        correct_normalized as f64 / total
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_read_examples() {
+        let text = r#"{"context": "The cat", "choices": ["sat", "flew"], "label": 0}
+
+{"context": "A dog", "choices": ["ran", "swam", "read"], "label": 2, "id": "ex-2"}
+"#;
+        let examples = read_examples(text).unwrap();
+        assert_eq!(examples.len(), 2);
+        assert_eq!(examples[1].choices, ["ran", "swam", "read"]);
+        assert_eq!(examples[1].label, 2);
+
+        let error = read_examples(r#"{"context": "x", "choices": ["a"], "label": 1}"#);
+        assert_eq!(error.err().unwrap(), "line 1: label 1 but 1 choices");
+        assert!(read_examples(r#"{"context": "x"}"#).is_err());
+    }
+}
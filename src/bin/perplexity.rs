@@ -0,0 +1,92 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+
+use clap::Parser;
+use half::{bf16, f16};
+
+use common::{fail, Dtype, ModelArgs};
+use rusty_llm::config::ModelConfig;
+use rusty_llm::eval::perplexity;
+use rusty_llm::float::MyFloat;
+use rusty_llm::model::CausalLM;
+
+use tokenizers::Tokenizer;
+
+/// Measure the perplexity of a GPT-2, LLaMA, Mixtral or GPT-NeoX model on a text file
+#[derive(Parser, Debug)]
+struct Cli {
+    /// Text to evaluate the model on
+    file: PathBuf,
+
+    #[command(flatten)]
+    model: ModelArgs,
+
+    /// Number of tokens the model sees at once, defaults to the context size of the model
+    #[arg(long)]
+    window: Option<usize>,
+
+    /// Number of tokens between the starts of two windows, a smaller stride gives every
+    /// token more context but takes longer
+    #[arg(long, default_value_t = 512)]
+    stride: usize,
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let config = cli.model.read_config();
+    let tokenizer = cli.model.load_tokenizer();
+
+    match cli.model.dtype {
+        Dtype::F32 => run::<f32>(&cli, &config, &tokenizer),
+        Dtype::F16 => run::<f16>(&cli, &config, &tokenizer),
+        Dtype::Bf16 => run::<bf16>(&cli, &config, &tokenizer),
+    }
+}
+
+fn run<T: MyFloat>(cli: &Cli, config: &ModelConfig, tokenizer: &Tokenizer) {
+    let model = cli.model.load_model::<T>(config);
+
+    let text = fs::read_to_string(&cli.file)
+        .unwrap_or_else(|e| fail(format!("Failed to read {}: {}", cli.file.display(), e)));
+    let ids: Vec<usize> = tokenizer
+        .encode(text.as_str(), false)
+        .unwrap_or_else(|e| fail(format!("Failed to tokenize {}: {}", cli.file.display(), e)))
+        .get_ids()
+        .iter()
+        .map(|&id| id as usize)
+        .collect();
+
+    let window = cli.window.unwrap_or(model.n_positions());
+    let result = perplexity(&*model, &ids, window, cli.stride)
+        .unwrap_or_else(|e| fail(format!("Failed to evaluate the model: {}", e)));
+    if result.tokens == 0 {
+        fail(format!(
+            "{} is too short to be evaluated",
+            cli.file.display()
+        ));
+    }
+
+    println!("tokens:        {}", result.tokens);
+    println!("cross-entropy: {:.4} nats/token", result.cross_entropy());
+    println!("perplexity:    {:.4}", result.perplexity());
+    println!("bits per byte: {:.4}", result.bits_per_byte(text.len()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from(["perplexity", "wiki.txt", "--stride", "256"]).unwrap();
+        assert_eq!(cli.file, PathBuf::from("wiki.txt"));
+        assert_eq!(cli.stride, 256);
+        assert_eq!(cli.window, None);
+    }
+}
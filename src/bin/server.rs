@@ -0,0 +1,23 @@
+mod common;
+
+use common::{fail, load_model, load_tokenizer, read_config};
+use rusty_llm::server::Server;
+
+use std::env;
+use std::path::Path;
+
+fn main() {
+    // the only argument is the address to listen on
+    let args: Vec<String> = env::args().collect();
+    let addr = args.get(1).map_or("127.0.0.1:8080", |addr| addr.as_str());
+
+    let config = read_config(Path::new("models/config.json"));
+    let model = load_model::<f32>(Path::new("models/model.safetensors"), &config);
+    let tokenizer = load_tokenizer(Path::new("tokenizer/tokenizer.json"));
+
+    let server = Server::bind(addr, model, tokenizer, config.model_type())
+        .unwrap_or_else(|e| fail(format!("Failed to listen on {}: {}", addr, e)));
+
+    println!("Listening on http://{}", server.local_addr());
+    server.run();
+}
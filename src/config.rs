@@ -0,0 +1,610 @@
+use crate::error::LoadError;
+use crate::nn::block::Activation;
+use serde::{Deserialize, Deserializer};
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::Path;
+
+// subset of the Hugging Face GPT2Config, field names match config.json
+#[derive(Clone, Debug, Deserialize)]
+pub struct GPTConfig {
+    pub n_layer: usize,
+    pub n_head: usize,
+    pub n_embd: usize,
+    pub n_positions: usize,
+    pub vocab_size: usize,
+    #[serde(default)]
+    pub n_inner: Option<usize>,
+    #[serde(default = "default_layer_norm_epsilon")]
+    pub layer_norm_epsilon: f32,
+    #[serde(rename = "activation_function", default)]
+    pub activation: Activation,
+    #[serde(
+        default = "default_gpt2_eos_token_id",
+        deserialize_with = "deserialize_token_ids"
+    )]
+    pub eos_token_id: Vec<usize>,
+}
+
+// <|endoftext|> of the GPT-2 tokenizer
+pub const GPT2_EOS_TOKEN: usize = 50256;
+
+fn default_gpt2_eos_token_id() -> Vec<usize> {
+    vec![GPT2_EOS_TOKEN]
+}
+
+// the default of the Hugging Face configs that don't have a tokenizer of their own
+fn default_eos_token_id() -> Vec<usize> {
+    vec![2]
+}
+
+// eos_token_id is a single id in most configs, a list in some (Llama 3) and null when the model
+// has none
+fn deserialize_token_ids<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<usize>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum TokenIds {
+        One(usize),
+        Many(Vec<usize>),
+    }
+    Ok(match Option::<TokenIds>::deserialize(deserializer)? {
+        None => Vec::new(),
+        Some(TokenIds::One(id)) => vec![id],
+        Some(TokenIds::Many(ids)) => ids,
+    })
+}
+
+fn default_layer_norm_epsilon() -> f32 {
+    1e-5
+}
+
+impl GPTConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<GPTConfig> {
+        let file = File::open(path)?;
+        let config = serde_json::from_reader(BufReader::new(file))?;
+        Ok(config)
+    }
+
+    pub fn from_json(json: &str) -> io::Result<GPTConfig> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn validate(&self) -> Result<(), LoadError> {
+        if self.n_embd.checked_rem(self.n_head) != Some(0) {
+            return Err(LoadError::InvalidConfig(format!(
+                "n_embd ({}) must be a multiple of n_head ({})",
+                self.n_embd, self.n_head
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn inner_dim(&self) -> usize {
+        self.n_inner.unwrap_or(4 * self.n_embd)
+    }
+}
+
+impl Default for GPTConfig {
+    fn default() -> Self {
+        // GPT-2 small
+        GPTConfig {
+            n_layer: 12,
+            n_head: 12,
+            n_embd: 768,
+            n_positions: 1024,
+            vocab_size: 50257,
+            n_inner: None,
+            layer_norm_epsilon: default_layer_norm_epsilon(),
+            activation: Activation::default(),
+            eos_token_id: default_gpt2_eos_token_id(),
+        }
+    }
+}
+
+// subset of the Hugging Face LlamaConfig
+#[derive(Clone, Debug, Deserialize)]
+pub struct LlamaConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    // fewer key and value heads than query heads is grouped-query attention, the same number when missing
+    #[serde(default)]
+    pub num_key_value_heads: Option<usize>,
+    pub max_position_embeddings: usize,
+    pub vocab_size: usize,
+    #[serde(default = "default_rms_norm_eps")]
+    pub rms_norm_eps: f32,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: f32,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
+    // only the plain rotary embeddings are supported, the config is rejected when this is set
+    #[serde(default)]
+    pub rope_scaling: Option<serde_json::Value>,
+    // when present it must be hidden_size / num_attention_heads
+    #[serde(default, rename = "head_dim")]
+    pub explicit_head_dim: Option<usize>,
+    // Mixtral replaces the MLP of every block with a mixture of experts, the MLP is dense when missing
+    #[serde(default)]
+    pub num_local_experts: Option<usize>,
+    #[serde(default = "default_experts_per_tok")]
+    pub num_experts_per_tok: usize,
+    #[serde(
+        default = "default_eos_token_id",
+        deserialize_with = "deserialize_token_ids"
+    )]
+    pub eos_token_id: Vec<usize>,
+}
+
+fn default_experts_per_tok() -> usize {
+    2
+}
+
+fn default_rms_norm_eps() -> f32 {
+    1e-6
+}
+
+fn default_rope_theta() -> f32 {
+    10000.0
+}
+
+impl LlamaConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<LlamaConfig> {
+        let file = File::open(path)?;
+        let config = serde_json::from_reader(BufReader::new(file))?;
+        Ok(config)
+    }
+
+    pub fn from_json(json: &str) -> io::Result<LlamaConfig> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn num_kv_heads(&self) -> usize {
+        self.num_key_value_heads.unwrap_or(self.num_attention_heads)
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+
+    pub fn validate(&self) -> Result<(), LoadError> {
+        if self.hidden_size.checked_rem(self.num_attention_heads) != Some(0) {
+            return Err(LoadError::InvalidConfig(format!(
+                "hidden_size ({}) must be a multiple of num_attention_heads ({})",
+                self.hidden_size, self.num_attention_heads
+            )));
+        }
+        if self.num_attention_heads.checked_rem(self.num_kv_heads()) != Some(0) {
+            return Err(LoadError::InvalidConfig(format!(
+                "num_attention_heads ({}) must be a multiple of num_key_value_heads ({})",
+                self.num_attention_heads,
+                self.num_kv_heads()
+            )));
+        }
+        if let Some(head_dim) = self.explicit_head_dim {
+            if head_dim != self.head_dim() {
+                return Err(LoadError::InvalidConfig(format!(
+                    "head_dim ({}) must be hidden_size / num_attention_heads ({})",
+                    head_dim,
+                    self.head_dim()
+                )));
+            }
+        }
+        if let Some(rope_scaling) = &self.rope_scaling {
+            return Err(LoadError::InvalidConfig(format!(
+                "rope_scaling {} is not supported",
+                rope_scaling
+            )));
+        }
+        if !self.head_dim().is_multiple_of(2) {
+            return Err(LoadError::InvalidConfig(format!(
+                "the head dimension ({}) must be even for the rotary embeddings",
+                self.head_dim()
+            )));
+        }
+        if let Some(num_experts) = self.num_local_experts {
+            if self.num_experts_per_tok == 0 || self.num_experts_per_tok > num_experts {
+                return Err(LoadError::InvalidConfig(format!(
+                    "num_experts_per_tok ({}) must be between 1 and num_local_experts ({})",
+                    self.num_experts_per_tok, num_experts
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+// subset of the Hugging Face GPTNeoXConfig, used by the Pythia models
+#[derive(Clone, Debug, Deserialize)]
+pub struct NeoXConfig {
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_hidden_layers: usize,
+    pub num_attention_heads: usize,
+    pub max_position_embeddings: usize,
+    pub vocab_size: usize,
+    // share of every head that is rotated
+    #[serde(default = "default_rotary_pct")]
+    pub rotary_pct: f32,
+    #[serde(default = "default_rope_theta")]
+    pub rotary_emb_base: f32,
+    #[serde(default = "default_layer_norm_epsilon")]
+    pub layer_norm_eps: f32,
+    #[serde(default = "default_true")]
+    pub use_parallel_residual: bool,
+    #[serde(default = "default_neox_activation")]
+    pub hidden_act: Activation,
+    #[serde(default)]
+    pub tie_word_embeddings: bool,
+    #[serde(
+        default = "default_eos_token_id",
+        deserialize_with = "deserialize_token_ids"
+    )]
+    pub eos_token_id: Vec<usize>,
+}
+
+fn default_rotary_pct() -> f32 {
+    0.25
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_neox_activation() -> Activation {
+    Activation::Gelu
+}
+
+impl NeoXConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<NeoXConfig> {
+        let file = File::open(path)?;
+        let config = serde_json::from_reader(BufReader::new(file))?;
+        Ok(config)
+    }
+
+    pub fn from_json(json: &str) -> io::Result<NeoXConfig> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    pub fn head_dim(&self) -> usize {
+        self.hidden_size / self.num_attention_heads
+    }
+
+    pub fn rotary_dim(&self) -> usize {
+        (self.head_dim() as f32 * self.rotary_pct) as usize
+    }
+
+    pub fn validate(&self) -> Result<(), LoadError> {
+        if self.hidden_size.checked_rem(self.num_attention_heads) != Some(0) {
+            return Err(LoadError::InvalidConfig(format!(
+                "hidden_size ({}) must be a multiple of num_attention_heads ({})",
+                self.hidden_size, self.num_attention_heads
+            )));
+        }
+        if !self.rotary_dim().is_multiple_of(2) || self.rotary_dim() > self.head_dim() {
+            return Err(LoadError::InvalidConfig(format!(
+                "rotary_pct {} gives {} rotary dimensions for heads of {}, it must be even and fit in the head",
+                self.rotary_pct,
+                self.rotary_dim(),
+                self.head_dim()
+            )));
+        }
+        Ok(())
+    }
+}
+
+// the architecture is chosen from the model_type of config.json, GPT-2 when it is missing
+#[derive(Clone, Debug)]
+pub enum ModelConfig {
+    Gpt2(GPTConfig),
+    Llama(LlamaConfig),
+    NeoX(NeoXConfig),
+}
+
+impl ModelConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<ModelConfig> {
+        let file = File::open(path)?;
+        let value = serde_json::from_reader(BufReader::new(file))?;
+        ModelConfig::from_value(value)
+    }
+
+    pub fn from_json(json: &str) -> io::Result<ModelConfig> {
+        ModelConfig::from_value(serde_json::from_str(json)?)
+    }
+
+    fn from_value(value: serde_json::Value) -> io::Result<ModelConfig> {
+        let model_type = value.get("model_type").and_then(|t| t.as_str());
+        match model_type {
+            Some("gpt2") | None => Ok(ModelConfig::Gpt2(serde_json::from_value(value)?)),
+            Some("llama") => Ok(ModelConfig::Llama(serde_json::from_value(value)?)),
+            Some("mixtral") => {
+                let config: LlamaConfig = serde_json::from_value(value)?;
+                if config.num_local_experts.is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "a mixtral config needs num_local_experts",
+                    ));
+                }
+                Ok(ModelConfig::Llama(config))
+            }
+            Some("gpt_neox") => Ok(ModelConfig::NeoX(serde_json::from_value(value)?)),
+            Some(other) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported model_type {}", other),
+            )),
+        }
+    }
+
+    // the generation stops after one of these tokens
+    pub fn eos_tokens(&self) -> &[usize] {
+        match self {
+            ModelConfig::Gpt2(config) => &config.eos_token_id,
+            ModelConfig::Llama(config) => &config.eos_token_id,
+            ModelConfig::NeoX(config) => &config.eos_token_id,
+        }
+    }
+
+    pub fn model_type(&self) -> &'static str {
+        match self {
+            ModelConfig::Gpt2(_) => "gpt2",
+            ModelConfig::Llama(config) if config.num_local_experts.is_some() => "mixtral",
+            ModelConfig::Llama(_) => "llama",
+            ModelConfig::NeoX(_) => "gpt_neox",
+        }
+    }
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        ModelConfig::Gpt2(GPTConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hf_config() {
+        // trimmed down config.json of distilgpt2
+        let json = r#"{
+            "_num_labels": 1,
+            "activation_function": "gelu_new",
+            "architectures": ["GPT2LMHeadModel"],
+            "attn_pdrop": 0.1,
+            "bos_token_id": 50256,
+            "embd_pdrop": 0.1,
+            "eos_token_id": 50256,
+            "initializer_range": 0.02,
+            "layer_norm_epsilon": 1e-05,
+            "model_type": "gpt2",
+            "n_ctx": 1024,
+            "n_embd": 768,
+            "n_head": 12,
+            "n_layer": 6,
+            "n_positions": 1024,
+            "resid_pdrop": 0.1,
+            "vocab_size": 50257
+        }"#;
+
+        let config = GPTConfig::from_json(json).unwrap();
+
+        assert_eq!(config.n_layer, 6);
+        assert_eq!(config.n_head, 12);
+        assert_eq!(config.inner_dim(), 3072);
+        assert_eq!(config.activation, Activation::GeluNew);
+        assert_eq!(config.eos_token_id, vec![50256]);
+    }
+
+    #[test]
+    fn test_unknown_activation() {
+        let json = r#"{
+            "activation_function": "swish",
+            "n_embd": 768,
+            "n_head": 12,
+            "n_layer": 6,
+            "n_positions": 1024,
+            "vocab_size": 50257
+        }"#;
+
+        assert!(GPTConfig::from_json(json).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let config = GPTConfig {
+            n_head: 5,
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(LoadError::InvalidConfig(_))
+        ));
+        assert!(GPTConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_llama_config() {
+        // trimmed down config.json of TinyLlama-1.1B
+        let json = r#"{
+            "architectures": ["LlamaForCausalLM"],
+            "bos_token_id": 1,
+            "eos_token_id": 2,
+            "hidden_act": "silu",
+            "hidden_size": 2048,
+            "intermediate_size": 5632,
+            "max_position_embeddings": 2048,
+            "model_type": "llama",
+            "num_attention_heads": 32,
+            "num_hidden_layers": 22,
+            "num_key_value_heads": 4,
+            "rms_norm_eps": 1e-05,
+            "rope_scaling": null,
+            "tie_word_embeddings": false,
+            "torch_dtype": "bfloat16",
+            "vocab_size": 32000
+        }"#;
+
+        let config = LlamaConfig::from_json(json).unwrap();
+        assert_eq!(config.num_kv_heads(), 4);
+        assert_eq!(config.head_dim(), 64);
+        assert_eq!(config.rope_theta, 10000.0);
+        assert_eq!(config.eos_token_id, vec![2]);
+        assert!(config.validate().is_ok());
+
+        // Llama 3 ends a turn with one of several tokens, some models have none
+        let eos = |value: &str| {
+            let json = json.replace(
+                r#""eos_token_id": 2"#,
+                &format!(r#""eos_token_id": {}"#, value),
+            );
+            LlamaConfig::from_json(&json).unwrap().eos_token_id
+        };
+        assert_eq!(eos("[128001, 128009]"), vec![128001, 128009]);
+        assert_eq!(eos("null"), Vec::<usize>::new());
+        assert!(LlamaConfig::from_json(
+            &json.replace(r#""eos_token_id": 2"#, r#""eos_token_id": "2""#)
+        )
+        .is_err());
+
+        let invalid = LlamaConfig {
+            num_key_value_heads: Some(5),
+            ..config.clone()
+        };
+        assert!(matches!(
+            invalid.validate(),
+            Err(LoadError::InvalidConfig(_))
+        ));
+
+        // the head dimension can be given, but it can't differ from the one that is computed
+        let head_dim = json.replace(r#""hidden_act""#, r#""head_dim": 64, "hidden_act""#);
+        assert!(LlamaConfig::from_json(&head_dim)
+            .unwrap()
+            .validate()
+            .is_ok());
+        let head_dim = json.replace(r#""hidden_act""#, r#""head_dim": 128, "hidden_act""#);
+        assert!(matches!(
+            LlamaConfig::from_json(&head_dim).unwrap().validate(),
+            Err(LoadError::InvalidConfig(_))
+        ));
+
+        // the scaled rotary embeddings of Llama 3.1 and others are not implemented
+        let scaled = json.replace(
+            r#""rope_scaling": null"#,
+            r#""rope_scaling": {"type": "linear", "factor": 2.0}"#,
+        );
+        let error = LlamaConfig::from_json(&scaled)
+            .unwrap()
+            .validate()
+            .unwrap_err();
+        assert!(error.to_string().contains("rope_scaling"), "{}", error);
+    }
+
+    #[test]
+    fn test_model_type() {
+        let gpt2 = r#"{"model_type": "gpt2", "n_embd": 768, "n_head": 12, "n_layer": 12,
+            "n_positions": 1024, "vocab_size": 50257}"#;
+        let config = ModelConfig::from_json(gpt2).unwrap();
+        assert!(matches!(config, ModelConfig::Gpt2(ref c) if c.n_layer == 12));
+        assert_eq!(config.model_type(), "gpt2");
+
+        assert_eq!(config.eos_tokens(), &[50256]);
+
+        // configs written by hand often leave it out
+        let untyped = gpt2.replace(r#""model_type": "gpt2", "#, "");
+        assert!(matches!(
+            ModelConfig::from_json(&untyped).unwrap(),
+            ModelConfig::Gpt2(_)
+        ));
+
+        let llama = r#"{"model_type": "llama", "hidden_size": 64, "intermediate_size": 128,
+            "num_hidden_layers": 2, "num_attention_heads": 4, "max_position_embeddings": 128,
+            "vocab_size": 100}"#;
+        let config = ModelConfig::from_json(llama).unwrap();
+        assert!(matches!(config, ModelConfig::Llama(ref c) if c.num_kv_heads() == 4));
+        assert_eq!(config.model_type(), "llama");
+
+        // trimmed down config.json of pythia-70m
+        let pythia = r#"{
+            "architectures": ["GPTNeoXForCausalLM"],
+            "bos_token_id": 0,
+            "eos_token_id": 0,
+            "hidden_act": "gelu",
+            "hidden_size": 512,
+            "initializer_range": 0.02,
+            "intermediate_size": 2048,
+            "layer_norm_eps": 1e-05,
+            "max_position_embeddings": 2048,
+            "model_type": "gpt_neox",
+            "num_attention_heads": 8,
+            "num_hidden_layers": 6,
+            "rotary_emb_base": 10000,
+            "rotary_pct": 0.25,
+            "tie_word_embeddings": false,
+            "torch_dtype": "float16",
+            "use_cache": true,
+            "use_parallel_residual": true,
+            "vocab_size": 50304
+        }"#;
+        let ModelConfig::NeoX(config) = ModelConfig::from_json(pythia).unwrap() else {
+            panic!("not a GPT-NeoX config");
+        };
+        assert_eq!(config.rotary_dim(), 16);
+        assert_eq!(config.hidden_act, Activation::Gelu);
+        assert!(config.use_parallel_residual);
+        assert_eq!(config.eos_token_id, vec![0]);
+        assert!(config.validate().is_ok());
+        let config = NeoXConfig {
+            rotary_pct: 0.3,
+            ..config
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(LoadError::InvalidConfig(_))
+        ));
+
+        // trimmed down config.json of Mixtral-8x7B, a LLaMA with a mixture of experts as MLP
+        let mixtral = r#"{
+            "architectures": ["MixtralForCausalLM"],
+            "hidden_act": "silu",
+            "hidden_size": 4096,
+            "intermediate_size": 14336,
+            "max_position_embeddings": 32768,
+            "model_type": "mixtral",
+            "num_attention_heads": 32,
+            "num_experts_per_tok": 2,
+            "num_hidden_layers": 32,
+            "num_key_value_heads": 8,
+            "num_local_experts": 8,
+            "rms_norm_eps": 1e-05,
+            "rope_theta": 1e6,
+            "router_aux_loss_coef": 0.02,
+            "tie_word_embeddings": false,
+            "vocab_size": 32000
+        }"#;
+        let config = ModelConfig::from_json(mixtral).unwrap();
+        assert_eq!(config.model_type(), "mixtral");
+        let ModelConfig::Llama(config) = config else {
+            panic!("not a LLaMA config");
+        };
+        assert_eq!(config.num_local_experts, Some(8));
+        assert!(config.validate().is_ok());
+        let config = LlamaConfig {
+            num_experts_per_tok: 9,
+            ..config
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(LoadError::InvalidConfig(_))
+        ));
+        let dense = mixtral.replace(r#""num_local_experts": 8,"#, "");
+        assert!(ModelConfig::from_json(&dense).is_err());
+
+        let error = ModelConfig::from_json(r#"{"model_type": "t5"}"#).unwrap_err();
+        assert_eq!(error.to_string(), "unsupported model_type t5");
+    }
+}
@@ -1,33 +1,306 @@
+use crate::error::LoadError;
 use crate::float::MyFloat;
-use ndarray::{Array, Ix1, Ix2};
-use safetensors::tensor::TensorView;
+use crate::nn::weight::Weight;
+use half::{bf16, f16};
+use memmap2::Mmap;
+use ndarray::{Array, Dimension, Ix1, Ix2, IxDyn};
+use safetensors::tensor::{Dtype, TensorView};
+use safetensors::SafeTensors;
+use std::any::TypeId;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
 
-pub fn to_float<'data, T: MyFloat>(view: &TensorView<'data>) -> Vec<T> {
-    // inspire from smelte-rs.
-    // https://github.com/Narsil/smelte-rs/blob/d81f714abce2e64539d3c87dfc6c5488e6a65c03/examples/bert.rs#L168
-    // TODO understand what the fuck is going on here. I want to move on towards the end of
-    // the project and then deal with this part
+fn decode<T, const N: usize>(data: &[u8], convert: impl Fn([u8; N]) -> Option<T>) -> Vec<T> {
+    data.chunks_exact(N)
+        .map(|bytes| convert(bytes.try_into().unwrap()).unwrap())
+        .collect()
+}
 
+pub fn to_float<T: MyFloat>(view: &TensorView) -> Result<Vec<T>, LoadError> {
+    // safetensors stores little endian values, each dtype is decoded to its own type first
+    // and then cast to T. Integer dtypes are kept as is, they are used for quantized weights.
     let v = view.data();
 
-    let mut c = Vec::with_capacity(v.len() / 4);
-    let mut i = 0;
-    while i < v.len() {
-        c.push(T::from_f32_bytes([v[i], v[i + 1], v[i + 2], v[i + 3]]));
-        i += 4;
+    let values = match view.dtype() {
+        Dtype::F32 => decode(v, |b| T::from_f32(f32::from_le_bytes(b))),
+        Dtype::F16 => decode(v, |b| T::from_f32(f16::from_le_bytes(b).to_f32())),
+        Dtype::BF16 => decode(v, |b| T::from_f32(bf16::from_le_bytes(b).to_f32())),
+        Dtype::F64 => decode(v, |b| T::from_f64(f64::from_le_bytes(b))),
+        Dtype::U8 => decode(v, |b| T::from_u8(u8::from_le_bytes(b))),
+        Dtype::I8 => decode(v, |b| T::from_i8(i8::from_le_bytes(b))),
+        Dtype::U16 => decode(v, |b| T::from_u16(u16::from_le_bytes(b))),
+        Dtype::I16 => decode(v, |b| T::from_i16(i16::from_le_bytes(b))),
+        Dtype::U32 => decode(v, |b| T::from_u32(u32::from_le_bytes(b))),
+        Dtype::I32 => decode(v, |b| T::from_i32(i32::from_le_bytes(b))),
+        Dtype::U64 => decode(v, |b| T::from_u64(u64::from_le_bytes(b))),
+        Dtype::I64 => decode(v, |b| T::from_i64(i64::from_le_bytes(b))),
+        dtype => return Err(LoadError::UnsupportedDtype(dtype)),
+    };
+    Ok(values)
+}
+
+pub fn from_safe_tensorview_nd<T: MyFloat, D: Dimension>(
+    tensor: TensorView,
+) -> Result<Array<T, D>, LoadError> {
+    let shape =
+        D::from_dimension(&IxDyn(tensor.shape())).ok_or_else(|| LoadError::RankMismatch {
+            expected: D::NDIM.unwrap_or(0),
+            found: tensor.shape().to_vec(),
+        })?;
+    let values = to_float::<T>(&tensor)?;
+    // safetensors already checked that the data length matches the shape
+    Ok(Array::<T, Ix1>::from(values).into_shape(shape).unwrap())
+}
+
+pub fn from_safe_tensorview_1d<T: MyFloat>(tensor: TensorView) -> Result<Array<T, Ix1>, LoadError> {
+    from_safe_tensorview_nd::<T, Ix1>(tensor)
+}
+
+pub fn from_safe_tensorview<T: MyFloat>(tensor: TensorView) -> Result<Array<T, Ix2>, LoadError> {
+    from_safe_tensorview_nd::<T, Ix2>(tensor)
+}
+
+fn native_dtype<T: MyFloat>() -> Option<Dtype> {
+    // the dtype whose bytes can be used as T without any conversion
+    let id = TypeId::of::<T>();
+    if id == TypeId::of::<f32>() {
+        Some(Dtype::F32)
+    } else if id == TypeId::of::<f16>() {
+        Some(Dtype::F16)
+    } else if id == TypeId::of::<bf16>() {
+        Some(Dtype::BF16)
+    } else {
+        None
+    }
+}
+
+fn check_shape(name: &str, tensor: &TensorView, expected: &[usize]) -> Result<(), LoadError> {
+    if tensor.shape() != expected {
+        return Err(LoadError::ShapeMismatch {
+            name: name.to_string(),
+            expected: expected.to_vec(),
+            found: tensor.shape().to_vec(),
+        });
+    }
+    Ok(())
+}
+
+pub trait TensorLoader {
+    // load the tensor `name`, checking that it has the shape the model expects
+    fn load<T: MyFloat, D: Dimension>(
+        &self,
+        name: &str,
+        shape: &[usize],
+    ) -> Result<Weight<T, D>, LoadError>;
+}
+
+impl TensorLoader for SafeTensors<'_> {
+    fn load<T: MyFloat, D: Dimension>(
+        &self,
+        name: &str,
+        shape: &[usize],
+    ) -> Result<Weight<T, D>, LoadError> {
+        let tensor = self.tensor(name)?;
+        check_shape(name, &tensor, shape)?;
+        Ok(from_safe_tensorview_nd::<T, D>(tensor)?.into())
     }
-    c
 }
 
-pub fn from_safe_tensorview_1d<T: MyFloat>(tensor: TensorView) -> Array<T, Ix1> {
-    Array::<T, Ix1>::from(to_float::<T>(&tensor))
+pub struct SafeTensorsFile {
+    mmap: Arc<Mmap>,
 }
 
-pub fn from_safe_tensorview<T: MyFloat>(tensor: TensorView) -> Array<T, Ix2> {
-    let shape = tensor.shape();
-    Array::<T, Ix1>::from(to_float::<T>(&tensor))
-        .into_shape(shape)
-        .unwrap()
-        .into_dimensionality::<Ix2>()
-        .unwrap()
+impl SafeTensorsFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SafeTensorsFile, LoadError> {
+        let file = File::open(path)?;
+        // the file must not be modified while it is mapped, like any model file that is being read
+        let mmap = unsafe { Mmap::map(&file)? };
+        Ok(SafeTensorsFile {
+            mmap: Arc::new(mmap),
+        })
+    }
+
+    pub fn tensors(&self) -> Result<MappedSafeTensors<'_>, LoadError> {
+        let tensors = SafeTensors::deserialize(&self.mmap)?;
+        Ok(MappedSafeTensors {
+            tensors,
+            mmap: &self.mmap,
+        })
+    }
+}
+
+pub struct MappedSafeTensors<'a> {
+    tensors: SafeTensors<'a>,
+    mmap: &'a Arc<Mmap>,
+}
+
+impl<'a> MappedSafeTensors<'a> {
+    pub fn tensors(&self) -> &SafeTensors<'a> {
+        &self.tensors
+    }
+}
+
+impl TensorLoader for MappedSafeTensors<'_> {
+    // tensors stored with the dtype of T are used in place, the others are converted to an
+    // owned array. The weights keep the mapping alive so the file can be dropped after loading.
+    fn load<T: MyFloat, D: Dimension>(
+        &self,
+        name: &str,
+        shape: &[usize],
+    ) -> Result<Weight<T, D>, LoadError> {
+        let tensor = self.tensors.tensor(name)?;
+        check_shape(name, &tensor, shape)?;
+
+        let ptr = tensor.data().as_ptr();
+        let zero_copy = cfg!(target_endian = "little")
+            && native_dtype::<T>() == Some(tensor.dtype())
+            && ptr.align_offset(std::mem::align_of::<T>()) == 0;
+
+        if zero_copy {
+            let offset = ptr as usize - self.mmap.as_ptr() as usize;
+            let shape =
+                D::from_dimension(&IxDyn(shape)).ok_or_else(|| LoadError::RankMismatch {
+                    expected: D::NDIM.unwrap_or(0),
+                    found: shape.to_vec(),
+                })?;
+            Ok(Weight::mapped(self.mmap.clone(), offset, shape))
+        } else {
+            Ok(from_safe_tensorview_nd::<T, D>(tensor)?.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    fn to_bytes<const N: usize>(values: &[[u8; N]]) -> Vec<u8> {
+        values.iter().flatten().cloned().collect()
+    }
+
+    #[test]
+    fn test_f32() {
+        let data = to_bytes(&[1.5f32, -2.0, 0.25, 4.0].map(f32::to_le_bytes));
+        let view = TensorView::new(Dtype::F32, vec![2, 2], &data).unwrap();
+
+        let array = from_safe_tensorview::<f32>(view).unwrap();
+        assert_eq!(array, array![[1.5, -2.0], [0.25, 4.0]]);
+    }
+
+    #[test]
+    fn test_f16() {
+        let data =
+            to_bytes(&[1.5f32, -2.0, 0.25, 4.0, 8.0, 0.5].map(|x| f16::from_f32(x).to_le_bytes()));
+        let view = TensorView::new(Dtype::F16, vec![2, 3], &data).unwrap();
+
+        let array = from_safe_tensorview::<f32>(view).unwrap();
+        assert_eq!(array, array![[1.5, -2.0, 0.25], [4.0, 8.0, 0.5]]);
+    }
+
+    #[test]
+    fn test_bf16_to_f16() {
+        let data = to_bytes(&[1.5f32, -2.0, 0.25].map(|x| bf16::from_f32(x).to_le_bytes()));
+        let view = TensorView::new(Dtype::BF16, vec![3], &data).unwrap();
+
+        let array = from_safe_tensorview_1d::<f16>(view).unwrap();
+        assert_eq!(array, array![1.5, -2.0, 0.25].mapv(f16::from_f32));
+    }
+
+    #[test]
+    fn test_f64() {
+        let data = to_bytes(&[1.5f64, -2.0].map(f64::to_le_bytes));
+        let view = TensorView::new(Dtype::F64, vec![2], &data).unwrap();
+
+        let array = from_safe_tensorview_1d::<f32>(view).unwrap();
+        assert_eq!(array, array![1.5, -2.0]);
+    }
+
+    #[test]
+    fn test_integers() {
+        let data = to_bytes(&[-3i8, 7].map(i8::to_le_bytes));
+        let view = TensorView::new(Dtype::I8, vec![2], &data).unwrap();
+
+        assert_eq!(to_float::<f32>(&view).unwrap(), vec![-3.0, 7.0]);
+    }
+
+    fn write_file(name: &str, data: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(name);
+        let view = TensorView::new(Dtype::F32, vec![2, 2], data).unwrap();
+        safetensors::serialize_to_file(vec![("weight", view)], &None, &path).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_mapped_zero_copy() {
+        let data = to_bytes(&[1.5f32, -2.0, 0.25, 4.0].map(f32::to_le_bytes));
+        let path = write_file("rusty_llm_test_mapped_zero_copy.safetensors", &data);
+
+        let file = SafeTensorsFile::open(&path).unwrap();
+        let tensors = file.tensors().unwrap();
+
+        let weight = tensors.load::<f32, Ix2>("weight", &[2, 2]).unwrap();
+        let converted = tensors.load::<f16, Ix2>("weight", &[2, 2]).unwrap();
+        drop(tensors);
+        drop(file);
+
+        assert!(weight.is_mapped());
+        assert!(!converted.is_mapped());
+        assert_eq!(weight.view(), array![[1.5, -2.0], [0.25, 4.0]]);
+        assert_eq!(
+            converted.view(),
+            array![[1.5, -2.0], [0.25, 4.0]].mapv(f16::from_f32)
+        );
+    }
+
+    #[test]
+    fn test_unsupported_dtype() {
+        let data = vec![0u8, 1];
+        let view = TensorView::new(Dtype::BOOL, vec![2], &data).unwrap();
+
+        assert!(matches!(
+            to_float::<f32>(&view),
+            Err(LoadError::UnsupportedDtype(Dtype::BOOL))
+        ));
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let data = to_bytes(&[1.5f32, -2.0, 0.25, 4.0].map(f32::to_le_bytes));
+        let path = write_file("rusty_llm_test_load_errors.safetensors", &data);
+
+        let file = SafeTensorsFile::open(&path).unwrap();
+        let tensors = file.tensors().unwrap();
+
+        assert!(matches!(
+            tensors.load::<f32, Ix2>("bias", &[2]),
+            Err(LoadError::MissingTensor(name)) if name == "bias"
+        ));
+        assert!(matches!(
+            tensors.load::<f32, Ix2>("weight", &[4, 1]),
+            Err(LoadError::ShapeMismatch { .. })
+        ));
+        assert!(matches!(
+            from_safe_tensorview_1d::<f32>(tensors.tensors().tensor("weight").unwrap()),
+            Err(LoadError::RankMismatch { expected: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let data = to_bytes(&[1.5f32, -2.0, 0.25, 4.0].map(f32::to_le_bytes));
+        let path = write_file("rusty_llm_test_truncated_file.safetensors", &data);
+
+        let bytes = std::fs::read(&path).unwrap();
+        let path = std::env::temp_dir().join("rusty_llm_test_truncated_file_cut.safetensors");
+        std::fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+
+        let file = SafeTensorsFile::open(&path).unwrap();
+        assert!(matches!(file.tensors(), Err(LoadError::Format(_))));
+        assert!(matches!(
+            SafeTensorsFile::open("does/not/exist.safetensors"),
+            Err(LoadError::Io(_))
+        ));
+    }
 }
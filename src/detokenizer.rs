@@ -0,0 +1,166 @@
+use tokenizers::Tokenizer;
+
+// Turns a stream of token ids into text without breaking characters.
+//
+// Byte-level BPE can split a character over several tokens, decoding them one by one gives
+// U+FFFD replacement characters. Some decoders also drop the leading space of the first token
+// they see. So instead of decoding the new token alone, the text of a few previous tokens is
+// decoded with and without it and only the difference is emitted, once it is complete.
+pub struct IncrementalDecoder<'a> {
+    tokenizer: &'a Tokenizer,
+    ids: Vec<u32>,
+    prefix_offset: usize, // start of the tokens decoded as context
+    read_offset: usize,   // end of the tokens whose text was already emitted
+}
+
+impl<'a> IncrementalDecoder<'a> {
+    pub fn new(tokenizer: &'a Tokenizer) -> IncrementalDecoder<'a> {
+        IncrementalDecoder::new_with_context(tokenizer, &[])
+    }
+
+    // the context tokens, usually the end of the prompt, are never emitted but the first
+    // generated tokens are decoded after them
+    pub fn new_with_context(tokenizer: &'a Tokenizer, context: &[usize]) -> IncrementalDecoder<'a> {
+        IncrementalDecoder {
+            tokenizer,
+            ids: context.iter().map(|&id| id as u32).collect(),
+            prefix_offset: 0,
+            read_offset: context.len(),
+        }
+    }
+
+    fn decode(&self, ids: &[u32]) -> String {
+        self.tokenizer
+            .decode(ids.to_vec(), false)
+            .unwrap_or_default()
+    }
+
+    // returns the text that became complete with this token, it can be empty
+    pub fn push(&mut self, id: usize) -> String {
+        self.ids.push(id as u32);
+
+        let prefix_text = self.decode(&self.ids[self.prefix_offset..self.read_offset]);
+        let new_text = self.decode(&self.ids[self.prefix_offset..]);
+
+        if new_text.ends_with('\u{FFFD}') {
+            return String::new(); // wait for the rest of the character
+        }
+        match new_text.get(prefix_text.len()..) {
+            Some(delta) if !delta.is_empty() => {
+                let delta = delta.to_string();
+                self.prefix_offset = self.read_offset;
+                self.read_offset = self.ids.len();
+                delta
+            }
+            _ => String::new(),
+        }
+    }
+
+    // text of the tokens still buffered, incomplete characters are replaced by U+FFFD
+    pub fn flush(&mut self) -> String {
+        let prefix_text = self.decode(&self.ids[self.prefix_offset..self.read_offset]);
+        let new_text = self.decode(&self.ids[self.prefix_offset..]);
+        self.prefix_offset = self.read_offset;
+        self.read_offset = self.ids.len();
+        new_text
+            .get(prefix_text.len()..)
+            .unwrap_or_default()
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    // byte-level tokenizer like GPT-2's, every token is a single byte written with the GPT-2
+    // byte to unicode mapping: "é" is "Ã©" and the space is "Ġ"
+    fn byte_level_tokenizer(tokens: &[&str]) -> Tokenizer {
+        let vocab: Vec<String> = tokens
+            .iter()
+            .enumerate()
+            .map(|(i, token)| format!("\"{}\": {}", token, i))
+            .collect();
+        let json = format!(
+            r#"{{
+                "version": "1.0",
+                "truncation": null,
+                "padding": null,
+                "added_tokens": [],
+                "normalizer": null,
+                "pre_tokenizer": {{"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true}},
+                "post_processor": null,
+                "decoder": {{"type": "ByteLevel", "add_prefix_space": false, "trim_offsets": true, "use_regex": true}},
+                "model": {{"type": "BPE", "dropout": null, "unk_token": null, "continuing_subword_prefix": null,
+                           "end_of_word_suffix": null, "fuse_unk": false, "vocab": {{{}}}, "merges": []}}
+            }}"#,
+            vocab.join(", ")
+        );
+        Tokenizer::from_str(&json).unwrap()
+    }
+
+    fn ids(tokenizer: &Tokenizer, text: &str) -> Vec<usize> {
+        let encoding = tokenizer.encode(text, false).unwrap();
+        encoding.get_ids().iter().map(|&id| id as usize).collect()
+    }
+
+    #[test]
+    fn test_split_characters() {
+        // 😀 is f0 9f 98 80
+        let tokenizer = byte_level_tokenizer(&["c", "a", "f", "Ã", "©", "Ġ", "ð", "Ł", "ĺ", "Ģ"]);
+        let ids = ids(&tokenizer, "café 😀");
+        assert_eq!(ids.len(), 10);
+
+        // decoding the tokens one by one breaks the characters
+        let naive: String = ids
+            .iter()
+            .map(|&id| tokenizer.decode(vec![id as u32], false).unwrap())
+            .collect();
+        assert!(naive.contains('\u{FFFD}'));
+
+        let mut decoder = IncrementalDecoder::new(&tokenizer);
+        let deltas: Vec<String> = ids.iter().map(|&id| decoder.push(id)).collect();
+
+        assert_eq!(deltas.concat(), "café 😀");
+        assert_eq!(deltas[3], "");
+        assert_eq!(deltas[4], "é");
+        assert_eq!(deltas[5], " ");
+        assert_eq!(&deltas[6..], &["", "", "", "😀"]);
+        assert_eq!(decoder.flush(), "");
+    }
+
+    #[test]
+    fn test_flush_incomplete_character() {
+        let tokenizer = byte_level_tokenizer(&["a", "Ã", "©"]);
+        let mut decoder = IncrementalDecoder::new(&tokenizer);
+
+        assert_eq!(decoder.push(0), "a");
+        assert_eq!(decoder.push(1), "");
+        assert_eq!(decoder.flush(), "\u{FFFD}");
+        assert_eq!(decoder.flush(), "");
+    }
+
+    #[test]
+    fn test_context() {
+        // with a decoder that strips the leading space of the text, like the sentencepiece ones,
+        // the space of the first generated token is only kept thanks to the context
+        let json = r#"{
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {"type": "Metaspace", "replacement": "▁", "add_prefix_space": true},
+            "post_processor": null,
+            "decoder": {"type": "Metaspace", "replacement": "▁", "add_prefix_space": true},
+            "model": {"type": "WordLevel", "vocab": {"▁hello": 0, "▁world": 1}, "unk_token": "▁hello"}
+        }"#;
+        let tokenizer = Tokenizer::from_str(json).unwrap();
+        assert_eq!(tokenizer.decode(vec![1], false).unwrap(), "world");
+
+        let mut decoder = IncrementalDecoder::new_with_context(&tokenizer, &[0]);
+        assert_eq!(decoder.push(1), " world");
+        assert_eq!(decoder.push(1), " world");
+    }
+}
@@ -0,0 +1,108 @@
+use safetensors::tensor::{Dtype, SafeTensorError};
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum LoadError {
+    MissingTensor(String),
+    ShapeMismatch {
+        name: String,
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+    // the tensor does not have the number of dimensions of the array it is converted to
+    RankMismatch {
+        expected: usize,
+        found: Vec<usize>,
+    },
+    UnsupportedDtype(Dtype),
+    InvalidConfig(String),
+    // the file is not a valid safetensors file, for instance because it is truncated
+    Format(SafeTensorError),
+    Io(io::Error),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::MissingTensor(name) => write!(f, "tensor {} is missing", name),
+            LoadError::ShapeMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "tensor {} has shape {:?}, expected {:?}",
+                name, found, expected
+            ),
+            LoadError::RankMismatch { expected, found } => write!(
+                f,
+                "tensor of shape {:?} can't be converted to a {}d array",
+                found, expected
+            ),
+            LoadError::UnsupportedDtype(dtype) => {
+                write!(f, "unsupported safetensors dtype {:?}", dtype)
+            }
+            LoadError::InvalidConfig(reason) => write!(f, "invalid model config: {}", reason),
+            LoadError::Format(e) => write!(f, "invalid safetensors file: {}", e),
+            LoadError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            LoadError::Format(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl From<SafeTensorError> for LoadError {
+    fn from(e: SafeTensorError) -> Self {
+        match e {
+            SafeTensorError::TensorNotFound(name) => LoadError::MissingTensor(name),
+            SafeTensorError::IoError(e) => LoadError::Io(e),
+            e => LoadError::Format(e),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum GenerateError {
+    EmptyPrompt,
+    Tokenizer(String),
+    // the tokens don't fit in the position embedding table and the overflow policy is to fail
+    ContextOverflow { len: usize, max: usize },
+    // the windows of the perplexity must start at most window tokens apart to cover every token
+    InvalidStride { stride: usize, window: usize },
+}
+
+impl fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GenerateError::EmptyPrompt => write!(f, "the prompt is empty"),
+            GenerateError::Tokenizer(e) => write!(f, "tokenizer error: {}", e),
+            GenerateError::ContextOverflow { len, max } => write!(
+                f,
+                "{} tokens don't fit in the context of {} positions",
+                len, max
+            ),
+            GenerateError::InvalidStride { stride, window } => write!(
+                f,
+                "the stride ({}) must be between 1 and the window size ({})",
+                stride, window
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GenerateError {}
@@ -0,0 +1,267 @@
+use crate::error::GenerateError;
+use crate::float::MyFloat;
+use crate::generation::log_softmax;
+use crate::model::CausalLM;
+use ndarray::{s, Array};
+use serde::{Deserialize, Serialize};
+use std::f64::consts::LN_2;
+use tokenizers::Tokenizer;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Perplexity {
+    // number of tokens predicted. The first token of a window has no context and is not
+    // predicted unless it is already in the previous window
+    pub tokens: usize,
+    // sum of the negative log likelihoods of the predicted tokens, in nats
+    pub nll: f64,
+}
+
+impl Perplexity {
+    // average negative log likelihood per token, in nats
+    pub fn cross_entropy(&self) -> f64 {
+        self.nll / self.tokens as f64
+    }
+
+    pub fn perplexity(&self) -> f64 {
+        self.cross_entropy().exp()
+    }
+
+    // unlike the perplexity it does not depend on the tokenizer, so models with different
+    // vocabularies can be compared. bytes is the size of the text in utf-8
+    pub fn bits_per_byte(&self, bytes: usize) -> f64 {
+        self.nll / LN_2 / bytes as f64
+    }
+}
+
+// Runs the model over windows of `window` tokens that start every `stride` tokens, like the
+// Hugging Face perplexity recipe. Every token is predicted once, in the first window that
+// reaches it, and with a stride smaller than the window it still gets the end of the previous
+// window as context.
+pub fn perplexity<T: MyFloat>(
+    model: &dyn CausalLM<T>,
+    ids: &[usize],
+    window: usize,
+    stride: usize,
+) -> Result<Perplexity, GenerateError> {
+    if window > model.n_positions() {
+        return Err(GenerateError::ContextOverflow {
+            len: window,
+            max: model.n_positions(),
+        });
+    }
+    if stride == 0 || stride > window {
+        return Err(GenerateError::InvalidStride { stride, window });
+    }
+
+    let mut result = Perplexity {
+        tokens: 0,
+        nll: 0.0,
+    };
+    let mut scored = 0; // end of the tokens already predicted
+    for begin in (0..ids.len()).step_by(stride) {
+        let end = (begin + window).min(ids.len());
+        let logits = model.forward(&ids[begin..end]);
+        for position in scored.max(begin + 1)..end {
+            // the logits of a position predict the next token
+            let logprobs = log_softmax(&logits.row(position - begin - 1));
+            result.nll -= logprobs[ids[position]] as f64;
+            result.tokens += 1;
+        }
+        scored = end;
+        if end == ids.len() {
+            break;
+        }
+    }
+    Ok(result)
+}
+
+// a multiple-choice question in the style of HellaSwag, PIQA or ARC
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct Example {
+    pub context: String,
+    pub choices: Vec<String>,
+    // index of the right choice
+    pub label: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Prediction {
+    pub label: usize,
+    // choice with the highest log likelihood
+    pub prediction: usize,
+    // choice with the highest log likelihood per byte, which doesn't favor the short choices
+    pub normalized_prediction: usize,
+    // log likelihood of every choice
+    pub logprobs: Vec<f32>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Score {
+    // log likelihood of the whole continuation given the prompt
+    pub logprob: f32,
+    // log probability of every token of the continuation
+    pub token_logprobs: Vec<f32>,
+    // every token of the continuation is the most likely one, greedy decoding would generate it
+    pub is_greedy: bool,
+}
+
+// Scores continuations of a prompt without generating them, like the loglikelihood requests of
+// lm-evaluation-harness. The prompt goes through the model once, then all the continuations go
+// through it together as a batch.
+pub struct Scorer<'a, T>
+where
+    T: MyFloat,
+{
+    model: &'a dyn CausalLM<T>,
+    tokenizer: &'a Tokenizer,
+}
+
+impl<'a, T> Scorer<'a, T>
+where
+    T: MyFloat,
+{
+    pub fn new(model: &'a dyn CausalLM<T>, tokenizer: &'a Tokenizer) -> Scorer<'a, T> {
+        Scorer { model, tokenizer }
+    }
+
+    fn encode(&self, text: &str) -> Result<Vec<usize>, GenerateError> {
+        let encoding = self
+            .tokenizer
+            .encode(text, false)
+            .map_err(|e| GenerateError::Tokenizer(e.to_string()))?;
+        Ok(encoding.get_ids().iter().map(|&x| x as usize).collect())
+    }
+
+    pub fn score(&self, prompt: &str, continuation: &str) -> Result<Score, GenerateError> {
+        let mut scores = self.score_batch(prompt, &[continuation])?;
+        Ok(scores.remove(0))
+    }
+
+    // the continuations are tokenized on their own, so they should start with the space that
+    // separates them from the prompt
+    pub fn score_batch(
+        &self,
+        prompt: &str,
+        continuations: &[&str],
+    ) -> Result<Vec<Score>, GenerateError> {
+        let prompt_ids = self.encode(prompt)?;
+        let continuation_ids = continuations
+            .iter()
+            .map(|continuation| self.encode(continuation))
+            .collect::<Result<Vec<Vec<usize>>, GenerateError>>()?;
+        self.score_batch_ids(&prompt_ids, &continuation_ids)
+    }
+
+    // the choices follow the context after a space, like in lm-evaluation-harness
+    pub fn predict(&self, example: &Example) -> Result<Prediction, GenerateError> {
+        let choices: Vec<String> = example
+            .choices
+            .iter()
+            .map(|choice| match choice.starts_with(char::is_whitespace) {
+                true => choice.clone(),
+                false => format!(" {}", choice),
+            })
+            .collect();
+        let choices: Vec<&str> = choices.iter().map(|choice| choice.as_str()).collect();
+        let logprobs: Vec<f32> = self
+            .score_batch(&example.context, &choices)?
+            .into_iter()
+            .map(|score| score.logprob)
+            .collect();
+
+        // acc_norm divides by the length in bytes of the continuation that was scored
+        let normalized: Vec<f32> = logprobs
+            .iter()
+            .zip(&choices)
+            .map(|(logprob, choice)| logprob / choice.len().max(1) as f32)
+            .collect();
+        Ok(Prediction {
+            label: example.label,
+            prediction: argmax(&logprobs),
+            normalized_prediction: argmax(&normalized),
+            logprobs,
+        })
+    }
+
+    pub fn score_batch_ids(
+        &self,
+        prompt_ids: &[usize],
+        continuations: &[Vec<usize>],
+    ) -> Result<Vec<Score>, GenerateError> {
+        if prompt_ids.is_empty() {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        // the last token of a continuation is predicted but never goes through the model
+        let longest = continuations.iter().map(|ids| ids.len()).max().unwrap_or(0);
+        let seq_len = longest.saturating_sub(1);
+        let max = self.model.n_positions();
+        if prompt_ids.len() + seq_len > max {
+            return Err(GenerateError::ContextOverflow {
+                len: prompt_ids.len() + seq_len,
+                max,
+            });
+        }
+
+        let mut cache = self.model.new_cache();
+        let output = self.model.forward_with_cache(prompt_ids, &mut cache);
+        let first = log_softmax(&output.row(output.nrows() - 1));
+
+        // the continuations share the cache of the prompt and are padded on the right, the
+        // padding comes after the tokens that matter so it doesn't change their logits
+        let batch = continuations.len();
+        let logits = if seq_len > 0 {
+            cache.select(&vec![0; batch], 1);
+            let ids = Array::from_shape_fn((batch, seq_len), |(i, j)| {
+                continuations[i].get(j).copied().unwrap_or(0)
+            });
+            let mask = Array::from_shape_fn((batch, prompt_ids.len() + seq_len), |(i, j)| {
+                j < prompt_ids.len() + continuations[i].len()
+            });
+            Some(
+                self.model
+                    .forward_batch_with_cache(&ids.view(), &mask.view(), &mut cache),
+            )
+        } else {
+            None
+        };
+
+        let scores = continuations
+            .iter()
+            .enumerate()
+            .map(|(i, ids)| {
+                let mut score = Score {
+                    logprob: 0.0,
+                    token_logprobs: Vec::with_capacity(ids.len()),
+                    is_greedy: true,
+                };
+                for (j, &id) in ids.iter().enumerate() {
+                    // the first token is predicted by the end of the prompt, the others by the
+                    // previous token of the continuation
+                    let row;
+                    let logprobs = if j == 0 {
+                        &first
+                    } else {
+                        row = log_softmax(&logits.as_ref().unwrap().slice(s![i, j - 1, ..]));
+                        &row
+                    };
+                    let best = (0..logprobs.len())
+                        .max_by(|&a, &b| logprobs[a].total_cmp(&logprobs[b]))
+                        .unwrap();
+                    score.is_greedy &= logprobs[best] <= logprobs[id];
+                    score.logprob += logprobs[id];
+                    score.token_logprobs.push(logprobs[id]);
+                }
+                score
+            })
+            .collect();
+        Ok(scores)
+    }
+}
+
+// first index of the largest value
+fn argmax(values: &[f32]) -> usize {
+    values.iter().enumerate().fold(
+        0,
+        |best, (i, value)| if *value > values[best] { i } else { best },
+    )
+}
@@ -0,0 +1,451 @@
+use crate::batch::Batch;
+use crate::detokenizer::IncrementalDecoder;
+use crate::error::GenerateError;
+use crate::float::MyFloat;
+use crate::gpt2::ContextOverflow;
+use crate::model::CausalLM;
+use crate::nn::cache::KVCache;
+use crate::sampling::{Sampler, SamplerConfig};
+use ndarray::{concatenate, s, Array, ArrayView, Axis, Ix1};
+use serde::Serialize;
+use tokenizers::Tokenizer;
+
+#[derive(Clone, Debug)]
+pub struct GenerationConfig {
+    pub max_tokens: usize,
+    // the generation stops after one of these tokens, it is returned without its text. The eos
+    // tokens of the model when None
+    pub eos_tokens: Option<Vec<usize>>,
+    // the generation stops when the text contains one of these strings, they can span several
+    // tokens. The text from the stop string on is not returned
+    pub stop: Vec<String>,
+    pub sampler: SamplerConfig,
+    pub context_overflow: ContextOverflow,
+    // number of the most likely tokens returned with their log probabilities at every step
+    pub top_logprobs: usize,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        GenerationConfig {
+            max_tokens: 16,
+            eos_tokens: None,
+            stop: Vec::new(),
+            sampler: SamplerConfig::default(),
+            context_overflow: ContextOverflow::default(),
+            top_logprobs: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinishReason {
+    // max_tokens was reached or the context is full
+    Length,
+    // an end of sequence token was generated
+    Eos,
+    // a stop string was found in the text
+    Stop,
+}
+
+impl FinishReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FinishReason::Length => "length",
+            FinishReason::Eos => "eos",
+            FinishReason::Stop => "stop",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TokenLogprob {
+    pub id: usize,
+    // text of the token decoded alone
+    pub text: String,
+    pub logprob: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GeneratedToken {
+    pub id: usize,
+    // text completed by this token. It is empty when the token only holds part of a character,
+    // or when its text could be the beginning of a stop string and is held back
+    pub text: String,
+    // log probability of the token under the model, before the sampler changes the distribution
+    pub logprob: f32,
+    // the config.top_logprobs most likely tokens at this step, the most likely first. The
+    // generated token is not among them when the sampler picked a less likely one
+    pub top_logprobs: Vec<TokenLogprob>,
+    // only set on the last token
+    pub finish_reason: Option<FinishReason>,
+}
+
+pub struct Generator<'a, T>
+where
+    T: MyFloat,
+{
+    model: &'a dyn CausalLM<T>,
+    tokenizer: &'a Tokenizer,
+}
+
+impl<'a, T> Generator<'a, T>
+where
+    T: MyFloat,
+{
+    pub fn new(model: &'a dyn CausalLM<T>, tokenizer: &'a Tokenizer) -> Generator<'a, T> {
+        Generator { model, tokenizer }
+    }
+
+    pub fn stream(
+        &self,
+        prompt: &str,
+        config: &GenerationConfig,
+    ) -> Result<TokenStream<'a, T>, GenerateError> {
+        let encoding = self
+            .tokenizer
+            .encode(prompt, false)
+            .map_err(|e| GenerateError::Tokenizer(e.to_string()))?;
+        let prompt_ids = encoding.get_ids().iter().map(|&x| x as usize).collect();
+        self.stream_ids(prompt_ids, config)
+    }
+
+    pub fn stream_ids(
+        &self,
+        prompt_ids: Vec<usize>,
+        config: &GenerationConfig,
+    ) -> Result<TokenStream<'a, T>, GenerateError> {
+        let cache = self.model.new_cache();
+        // fail now rather than in the middle of the iteration if the prompt can't be used
+        self.model
+            .context_start(prompt_ids.len(), &cache, config.context_overflow)?;
+
+        Ok(TokenStream {
+            model: self.model,
+            sequence: Sequence::new(self.tokenizer, prompt_ids, config, self.eos_tokens(config)),
+            sampler: config.sampler.build::<T>(),
+            config: config.clone(),
+            cache,
+        })
+    }
+
+    fn eos_tokens(&self, config: &GenerationConfig) -> Vec<usize> {
+        config
+            .eos_tokens
+            .clone()
+            .unwrap_or_else(|| self.model.eos_tokens())
+    }
+
+    pub fn generate_batch(
+        &self,
+        prompts: &[&str],
+        config: &GenerationConfig,
+    ) -> Result<Vec<Vec<GeneratedToken>>, GenerateError> {
+        let prompt_ids = prompts
+            .iter()
+            .map(|prompt| {
+                let encoding = self
+                    .tokenizer
+                    .encode(*prompt, false)
+                    .map_err(|e| GenerateError::Tokenizer(e.to_string()))?;
+                Ok(encoding.get_ids().iter().map(|&x| x as usize).collect())
+            })
+            .collect::<Result<Vec<Vec<usize>>, GenerateError>>()?;
+        self.generate_batch_ids(prompt_ids, config)
+    }
+
+    pub fn generate_batch_ids(
+        &self,
+        prompt_ids: Vec<Vec<usize>>,
+        config: &GenerationConfig,
+    ) -> Result<Vec<Vec<GeneratedToken>>, GenerateError> {
+        // the prompts are left padded and go through the model together. The padded rows share
+        // one context, so the context overflow policy is always to fail: a prompt that does not
+        // fit is an error and the generation stops once the longest prompt fills the context.
+        if prompt_ids.iter().any(|ids| ids.is_empty()) {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        let batch = Batch::new(&prompt_ids);
+        let max = self.model.n_positions();
+        if batch.seq_len() > max {
+            return Err(GenerateError::ContextOverflow {
+                len: batch.seq_len(),
+                max,
+            });
+        }
+
+        let eos_tokens = self.eos_tokens(config);
+        let mut sequences: Vec<Sequence> = prompt_ids
+            .into_iter()
+            .map(|ids| Sequence::new(self.tokenizer, ids, config, eos_tokens.clone()))
+            .collect();
+        // with a seed every row gets its own, so identical prompts don't draw the same tokens. The
+        // first row keeps the seed and samples like a single generation
+        let mut samplers: Vec<Box<dyn Sampler<T>>> = (0..sequences.len())
+            .map(|i| {
+                let sampler = SamplerConfig {
+                    seed: config.sampler.seed.map(|seed| seed.wrapping_add(i as u64)),
+                    ..config.sampler.clone()
+                };
+                sampler.build::<T>()
+            })
+            .collect();
+        let mut outputs = vec![Vec::new(); sequences.len()];
+
+        let mut cache = self.model.new_cache();
+        let mut ids = batch.ids().to_owned();
+        let mut mask = batch.mask().to_owned();
+
+        while sequences.iter().any(|sequence| !sequence.finished) {
+            let logits = self
+                .model
+                .forward_batch_with_cache(&ids.view(), &mask.view(), &mut cache);
+            let context_full = cache.len() + 1 > max;
+
+            // finished sequences keep going through the model as padding
+            let mut next = vec![0; sequences.len()];
+            let mut next_mask = vec![false; sequences.len()];
+            for (i, sequence) in sequences.iter_mut().enumerate() {
+                if sequence.finished {
+                    continue;
+                }
+                let logits = logits.slice(s![i, -1, ..]);
+                let id = samplers[i].sample(&logits);
+                let logprobs = log_softmax(&logits);
+                outputs[i].push(sequence.push(id, &logprobs, config, context_full));
+                next[i] = id;
+                next_mask[i] = true;
+            }
+
+            ids = Array::from_shape_vec((next.len(), 1), next).unwrap();
+            let next_mask = Array::from_shape_vec((next_mask.len(), 1), next_mask).unwrap();
+            mask = concatenate(Axis(1), &[mask.view(), next_mask.view()]).unwrap();
+        }
+
+        Ok(outputs)
+    }
+}
+
+// what is known about a sequence being generated, apart from the model state
+struct Sequence<'a> {
+    tokenizer: &'a Tokenizer,
+    decoder: IncrementalDecoder<'a>,
+    ids: Vec<usize>,
+    prompt_len: usize,
+    eos_tokens: Vec<usize>,
+    pending: String, // decoded text not returned yet because it may start a stop string
+    finished: bool,
+}
+
+impl<'a> Sequence<'a> {
+    fn new(
+        tokenizer: &'a Tokenizer,
+        prompt_ids: Vec<usize>,
+        config: &GenerationConfig,
+        eos_tokens: Vec<usize>,
+    ) -> Self {
+        Sequence {
+            tokenizer,
+            // the prompt is given as context so that the first token gets its leading space
+            decoder: IncrementalDecoder::new_with_context(tokenizer, &prompt_ids),
+            prompt_len: prompt_ids.len(),
+            ids: prompt_ids,
+            eos_tokens,
+            pending: String::new(),
+            finished: config.max_tokens == 0,
+        }
+    }
+
+    // logprobs is the distribution the token was sampled from, context_full tells that the model
+    // can't look at one more token
+    fn push(
+        &mut self,
+        id: usize,
+        logprobs: &[f32],
+        config: &GenerationConfig,
+        context_full: bool,
+    ) -> GeneratedToken {
+        self.ids.push(id);
+
+        let eos = self.eos_tokens.contains(&id);
+        let length =
+            !eos && (self.ids.len() - self.prompt_len >= config.max_tokens || context_full);
+        if !eos {
+            let text = self.decoder.push(id);
+            self.pending.push_str(&text);
+        }
+        if eos || length {
+            // the last token also carries the end of an incomplete character
+            let text = self.decoder.flush();
+            self.pending.push_str(&text);
+        }
+
+        let (text, finish_reason) = match find_stop(&self.pending, &config.stop) {
+            Some(position) => {
+                self.pending.truncate(position);
+                (std::mem::take(&mut self.pending), Some(FinishReason::Stop))
+            }
+            None if eos => (std::mem::take(&mut self.pending), Some(FinishReason::Eos)),
+            None if length => (
+                std::mem::take(&mut self.pending),
+                Some(FinishReason::Length),
+            ),
+            None => {
+                let held = stop_prefix_len(&self.pending, &config.stop);
+                let text = self.pending.drain(..self.pending.len() - held).collect();
+                (text, None)
+            }
+        };
+        self.finished = finish_reason.is_some();
+
+        GeneratedToken {
+            id,
+            text,
+            logprob: logprobs[id],
+            top_logprobs: self.top_logprobs(logprobs, config.top_logprobs),
+            finish_reason,
+        }
+    }
+
+    fn top_logprobs(&self, logprobs: &[f32], n: usize) -> Vec<TokenLogprob> {
+        let n = n.min(logprobs.len());
+        if n == 0 {
+            return Vec::new();
+        }
+        let order = |a: &usize, b: &usize| logprobs[*b].total_cmp(&logprobs[*a]);
+        let mut ids: Vec<usize> = (0..logprobs.len()).collect();
+        ids.select_nth_unstable_by(n - 1, order);
+        ids.truncate(n);
+        ids.sort_by(order);
+        ids.into_iter()
+            .map(|id| TokenLogprob {
+                id,
+                text: self
+                    .tokenizer
+                    .decode(vec![id as u32], false)
+                    .unwrap_or_default(),
+                logprob: logprobs[id],
+            })
+            .collect()
+    }
+}
+
+pub struct TokenStream<'a, T>
+where
+    T: MyFloat,
+{
+    model: &'a dyn CausalLM<T>,
+    sequence: Sequence<'a>,
+    config: GenerationConfig,
+    sampler: Box<dyn Sampler<T>>,
+    cache: KVCache<T>,
+}
+
+impl<'a, T> TokenStream<'a, T>
+where
+    T: MyFloat,
+{
+    pub fn prompt_len(&self) -> usize {
+        self.sequence.prompt_len
+    }
+
+    // prompt followed by the tokens generated so far
+    pub fn ids(&self) -> &[usize] {
+        &self.sequence.ids
+    }
+}
+
+impl<'a, T> Iterator for TokenStream<'a, T>
+where
+    T: MyFloat,
+{
+    type Item = GeneratedToken;
+
+    fn next(&mut self) -> Option<GeneratedToken> {
+        if self.sequence.finished {
+            return None;
+        }
+
+        // stream_ids checked the prompt and every token checks that the next one fits, the
+        // sequence is finished otherwise
+        let overflow = self.config.context_overflow;
+        let logits = self
+            .model
+            .next_logits(&self.sequence.ids, &mut self.cache, overflow)
+            .expect("the context was checked on the previous token");
+        let id = self.sampler.sample(&logits.view());
+
+        let context_full = self
+            .model
+            .context_start(self.sequence.ids.len() + 1, &self.cache, overflow)
+            .is_err();
+        let logprobs = log_softmax(&logits.view());
+        Some(
+            self.sequence
+                .push(id, &logprobs, &self.config, context_full),
+        )
+    }
+}
+
+// position of the first stop string in the text
+fn find_stop(text: &str, stop: &[String]) -> Option<usize> {
+    stop.iter()
+        .filter(|stop| !stop.is_empty())
+        .filter_map(|stop| text.find(stop.as_str()))
+        .min()
+}
+
+// length of the longest end of the text that is the beginning of a stop string
+fn stop_prefix_len(text: &str, stop: &[String]) -> usize {
+    text.char_indices()
+        .map(|(i, _)| &text[i..])
+        .find(|end| stop.iter().any(|stop| stop.starts_with(end)))
+        .map_or(0, |end| end.len())
+}
+
+pub(crate) fn log_softmax<T: MyFloat>(logits: &ArrayView<T, Ix1>) -> Vec<f32> {
+    let max = logits
+        .iter()
+        .map(|x| x.to_f32().unwrap())
+        .fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits
+        .iter()
+        .map(|x| (x.to_f32().unwrap() - max).exp())
+        .sum();
+    let log_sum = sum.ln();
+    logits
+        .iter()
+        .map(|x| x.to_f32().unwrap() - max - log_sum)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_log_softmax() {
+        let logits = array![1.0f32, 2.0, 3.0];
+        let total: f32 = log_softmax(&logits.view()).iter().map(|x| x.exp()).sum();
+        assert!((total - 1.0).abs() < 1e-6);
+
+        // large logits don't overflow
+        let logits = array![1000.0f32, 1000.0];
+        assert!((log_softmax(&logits.view())[0] - 0.5f32.ln()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_stop_strings() {
+        let stop = vec!["\n\n".to_string(), "Human:".to_string(), String::new()];
+
+        assert_eq!(find_stop("Hi\nHuman: yo\n\n", &stop), Some(3));
+        assert_eq!(find_stop("Hi\n", &stop), None);
+
+        assert_eq!(stop_prefix_len("Hi\n", &stop), 1);
+        assert_eq!(stop_prefix_len("Hi Hum", &stop), 3);
+        assert_eq!(stop_prefix_len("Hi", &stop), 0);
+        assert_eq!(stop_prefix_len("é", &["éa".to_string()]), 2);
+    }
+}
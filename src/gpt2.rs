@@ -1,22 +1,90 @@
-use crate::convert::{from_safe_tensorview, from_safe_tensorview_1d};
+use crate::batch::{position_ids, Batch};
+use crate::config::{GPTConfig, ModelConfig};
+use crate::convert::TensorLoader;
+use crate::error::{GenerateError, LoadError};
 use crate::float::MyFloat;
-use crate::nn::block::Block;
+use crate::model::CausalLM;
+use crate::nn::block::{Activation, Block};
+use crate::nn::cache::KVCache;
 use crate::nn::head::CausalHead;
+use crate::nn::hooks::HookPoint;
 use crate::nn::layer_norm::LayerNorm;
 use crate::nn::linear::{Linear, LinearNoBias};
-use crate::nn::utils::{argmax, softmax};
-use ndarray::{s, Array, Axis, Ix2};
-use safetensors::SafeTensors;
+use crate::nn::weight::Weight;
+use ndarray::{s, Array, ArrayView, ArrayViewMut, Axis, Dimension, Ix1, Ix2, Ix3, IxDyn};
+use std::str::FromStr;
+
+// what to do when the tokens don't fit in the position embedding table anymore
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ContextOverflow {
+    #[default]
+    Error,
+    // drop the beginning of the prompt so that it fits, the generation fails once the context is full
+    TruncateLeft,
+    // only look at the last n_positions tokens. The positions are absolute, so once the window is full
+    // it moves at every step and the cache is recomputed
+    SlidingWindow,
+}
+
+impl FromStr for ContextOverflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(ContextOverflow::Error),
+            "truncate-left" => Ok(ContextOverflow::TruncateLeft),
+            "sliding-window" => Ok(ContextOverflow::SlidingWindow),
+            _ => Err(format!(
+                "unknown context overflow policy {}, expected error, truncate-left or sliding-window",
+                s
+            )),
+        }
+    }
+}
+
+// how the hidden states of the tokens of a sequence are turned into a single embedding
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Pooling {
+    #[default]
+    Mean,
+    // the only token that attended to the whole sequence
+    LastToken,
+    Max,
+}
+
+impl FromStr for Pooling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(Pooling::Mean),
+            "last" => Ok(Pooling::LastToken),
+            "max" => Ok(Pooling::Max),
+            _ => Err(format!("unknown pooling {}, expected mean, last or max", s)),
+        }
+    }
+}
+
+pub struct HiddenStates<T>
+where
+    T: MyFloat,
+{
+    // output of every block, the residual stream, (seq, embed)
+    pub layers: Vec<Array<T, Ix2>>,
+    // output of ln_f, what the logits are computed from, (seq, embed)
+    pub last: Array<T, Ix2>,
+}
 
 pub struct GPT<T>
 where
     T: MyFloat,
 {
-    w_token_embed: Array<T, Ix2>,
-    w_pos_embed: Array<T, Ix2>,
+    w_token_embed: Weight<T, Ix2>,
+    w_pos_embed: Weight<T, Ix2>,
     blocks: Vec<Block<T>>,
     ln_f: LayerNorm<T>,
     next_word_layer: LinearNoBias<T>,
+    config: GPTConfig,
 }
 
 impl<T> GPT<T>
@@ -24,133 +92,375 @@ where
     T: MyFloat,
 {
     pub fn new(
-        w_token_embed: Array<T, Ix2>,
-        w_pos_embed: Array<T, Ix2>,
+        w_token_embed: impl Into<Weight<T, Ix2>>,
+        w_pos_embed: impl Into<Weight<T, Ix2>>,
         blocks: Vec<Block<T>>,
         ln_f: LayerNorm<T>,
         next_word_layer: LinearNoBias<T>,
     ) -> GPT<T> {
+        let (w_token_embed, w_pos_embed) = (w_token_embed.into(), w_pos_embed.into());
+        // the config is read back from the layers, a checkpoint loaded from a file keeps its own
+        let first = blocks.first();
+        let config = GPTConfig {
+            n_layer: blocks.len(),
+            n_head: first.map_or(1, |block| block.num_head()),
+            n_embd: w_token_embed.shape()[1],
+            n_positions: w_pos_embed.shape()[0],
+            vocab_size: w_token_embed.shape()[0],
+            n_inner: first.map(|block| block.inner_dim()),
+            layer_norm_epsilon: ln_f.eps().to_f32().unwrap(),
+            activation: first.map_or(Activation::default(), |block| block.activation()),
+            ..Default::default()
+        };
         GPT::<T> {
             w_token_embed,
             w_pos_embed,
             blocks,
             ln_f,
             next_word_layer,
+            config,
         }
     }
 
-    pub fn forward(&self, indices: &Vec<usize>) -> Array<T, Ix2> {
-        let token_embedding = self.w_token_embed.select(Axis(0), indices);
-        let range: Vec<usize> = (0..indices.len()).map(|i| i).collect();
-        let pos_embedding = self.w_pos_embed.select(Axis(0), &range);
+    // runs the blocks and ln_f, but not the projection on the vocabulary which is the most expensive
+    // layer of the small models. on_layer gets the output of every block. Returns (batch * seq, embed).
+    fn hidden_batch_with_cache(
+        &self,
+        ids: &ArrayView<usize, Ix2>,
+        mask: &ArrayView<bool, Ix2>,
+        cache: &mut KVCache<T>,
+        mut on_layer: impl FnMut(&Array<T, Ix2>),
+    ) -> Array<T, Ix2> {
+        let (batch, seq_len) = ids.dim();
+        let offset = cache.len();
+        assert_eq!(mask.shape(), &[batch, offset + seq_len]);
+
+        let positions = position_ids(mask);
+        let positions: Vec<usize> = positions.slice(s![.., offset..]).iter().copied().collect();
+        let max_position = positions.iter().copied().max().unwrap_or(0);
+        assert!(
+            max_position < self.n_positions(),
+            "{} tokens don't fit in the context of {} positions",
+            max_position + 1,
+            self.n_positions()
+        );
+
+        let ids: Vec<usize> = ids.iter().copied().collect();
+        let token_embedding = self.w_token_embed.view().select(Axis(0), &ids);
+        let pos_embedding = self.w_pos_embed.view().select(Axis(0), &positions);
         let embed = pos_embedding + token_embedding; // TODO : optimization do addition in place
 
+        // the sequences are flattened to (batch * seq, embed), only the attention splits them
         let mut output = embed;
         // let mut i = 0;
-        for block in self.blocks.iter() {
+        for (block, layer_cache) in self.blocks.iter().zip(cache.layers_mut()) {
             // i += 1;
             //println!("=========== block{} ================", i);
-            output = block.forward(&output);
+            output = block.forward_batch_with_cache(&output, mask, layer_cache);
+            on_layer(&output);
         }
 
-        let output = self.ln_f.forward(&output);
-        let output = self.next_word_layer.forward(&output);
-        output
+        self.ln_f.forward(&output)
     }
 
-    pub fn generate(&self, indices: &Vec<usize>) -> usize {
-        let embed = self.forward(indices);
-        let logits = embed.slice(s![-1, ..]); // only keep the last work embedding
-        let probs = softmax(&logits);
-        argmax(&probs.view().into_dyn())
+    pub fn hidden_states(&self, indices: &[usize]) -> Result<HiddenStates<T>, GenerateError> {
+        if indices.is_empty() {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        self.check_context(indices.len())?;
+        let ids = ArrayView::from_shape((1, indices.len()), indices).unwrap();
+        let mask = Array::from_elem((1, indices.len()), true);
+        let mut layers = Vec::with_capacity(self.blocks.len());
+        let last = self.hidden_batch_with_cache(&ids, &mask.view(), &mut self.new_cache(), |x| {
+            layers.push(x.clone())
+        });
+        Ok(HiddenStates { layers, last })
+    }
+
+    pub fn embed(
+        &self,
+        indices: &[usize],
+        pooling: Pooling,
+    ) -> Result<Array<T, Ix1>, GenerateError> {
+        let batch = Batch::new(&[indices.to_vec()]);
+        Ok(self
+            .embed_batch(&batch, pooling)?
+            .index_axis_move(Axis(0), 0))
+    }
+
+    // one embedding per sequence of the batch, pooled from the output of ln_f. Returns (batch, embed).
+    pub fn embed_batch(
+        &self,
+        batch: &Batch,
+        pooling: Pooling,
+    ) -> Result<Array<T, Ix2>, GenerateError> {
+        let (ids, mask) = (batch.ids(), batch.mask());
+        // an empty sequence has nothing to pool
+        if mask.outer_iter().any(|mask| !mask.iter().any(|&m| m)) {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        self.check_context(batch.seq_len())?;
+        let hidden = self.hidden_batch_with_cache(&ids, &mask, &mut self.new_cache(), |_| ());
+        let embed_dim = hidden.shape()[1];
+        let hidden = hidden
+            .into_shape((batch.len(), batch.seq_len(), embed_dim))
+            .unwrap();
+
+        let mut embeddings = Array::zeros((batch.len(), embed_dim));
+        for ((hidden, mask), mut embedding) in hidden
+            .outer_iter()
+            .zip(mask.outer_iter())
+            .zip(embeddings.outer_iter_mut())
+        {
+            // the padding tokens are left out
+            let rows: Vec<usize> = (0..mask.len()).filter(|&i| mask[i]).collect();
+            let tokens = hidden.select(Axis(0), &rows);
+            match pooling {
+                Pooling::Mean => embedding.assign(&tokens.mean_axis(Axis(0)).unwrap()),
+                Pooling::LastToken => embedding.assign(&tokens.row(tokens.nrows() - 1)),
+                Pooling::Max => {
+                    embedding.assign(&tokens.fold_axis(Axis(0), T::neg_infinity(), |&a, &b| {
+                        if b > a {
+                            b
+                        } else {
+                            a
+                        }
+                    }))
+                }
+            }
+        }
+        Ok(embeddings)
+    }
+
+    fn check_context(&self, len: usize) -> Result<(), GenerateError> {
+        if len > self.n_positions() {
+            return Err(GenerateError::ContextOverflow {
+                len,
+                max: self.n_positions(),
+            });
+        }
+        Ok(())
     }
 
-    pub fn load_linear(tensors: &SafeTensors, weight_name: &str, bias_name: &str) -> Linear<T> {
-        let weight = from_safe_tensorview::<T>(tensors.tensor(weight_name).unwrap());
+    // the hook reads or changes an activation of the block at index layer during every forward
+    // pass, until the hooks are cleared
+    pub fn register_hook(
+        &mut self,
+        layer: usize,
+        point: HookPoint,
+        hook: impl Fn(&mut ArrayViewMut<T, IxDyn>) + Send + Sync + 'static,
+    ) {
+        self.blocks[layer].register_hook(point, Box::new(hook));
+    }
 
-        let bias = from_safe_tensorview_1d::<T>(tensors.tensor(bias_name).unwrap());
+    pub fn clear_hooks(&mut self) {
+        self.blocks.iter_mut().for_each(|block| block.clear_hooks());
+    }
 
-        Linear::<T>::new(weight, bias)
+    pub fn num_layers(&self) -> usize {
+        self.blocks.len()
     }
 
-    pub fn load_layer_norm(
-        tensors: &SafeTensors,
+    pub fn load_linear<L: TensorLoader>(
+        tensors: &L,
         weight_name: &str,
         bias_name: &str,
-    ) -> LayerNorm<T> {
-        let weight = from_safe_tensorview_1d::<T>(tensors.tensor(weight_name).unwrap());
+        dim_in: usize,
+        dim_out: usize,
+    ) -> Result<Linear<T>, LoadError> {
+        let weight = tensors.load::<T, Ix2>(weight_name, &[dim_in, dim_out])?;
+
+        let bias = tensors.load::<T, Ix1>(bias_name, &[dim_out])?;
 
-        let bias = from_safe_tensorview_1d::<T>(tensors.tensor(bias_name).unwrap());
+        Ok(Linear::<T>::new(weight, bias))
+    }
 
-        LayerNorm::<T>::new(weight, bias)
+    pub fn load_layer_norm<L: TensorLoader>(
+        tensors: &L,
+        weight_name: &str,
+        bias_name: &str,
+        dim: usize,
+        eps: f32,
+    ) -> Result<LayerNorm<T>, LoadError> {
+        let weight = tensors.load::<T, Ix1>(weight_name, &[dim])?;
+
+        let bias = tensors.load::<T, Ix1>(bias_name, &[dim])?;
+
+        Ok(LayerNorm::<T>::new_with_eps(
+            weight,
+            bias,
+            T::from(eps).unwrap(),
+        ))
     }
 
-    pub fn load_block(tensors: &SafeTensors, index: usize) -> Block<T> {
+    pub fn load_block<L: TensorLoader>(
+        tensors: &L,
+        index: usize,
+        config: &GPTConfig,
+    ) -> Result<Block<T>, LoadError> {
+        let embed_dim = config.n_embd;
+
         let qkv = GPT::<T>::load_linear(
             tensors,
             &format!("h.{}.attn.c_attn.weight", index),
             &format!("h.{}.attn.c_attn.bias", index),
-        );
+            embed_dim,
+            3 * embed_dim,
+        )?;
 
         let proj_head = GPT::<T>::load_linear(
             tensors,
             &format!("h.{}.attn.c_proj.weight", index),
             &format!("h.{}.attn.c_proj.bias", index),
-        );
+            embed_dim,
+            embed_dim,
+        )?;
 
-        let head = CausalHead::<T>::new(qkv, proj_head, 12);
+        let head = CausalHead::<T>::new(qkv, proj_head, config.n_head);
 
         let fc = GPT::<T>::load_linear(
             tensors,
             &format!("h.{}.mlp.c_fc.weight", index),
             &format!("h.{}.mlp.c_fc.bias", index),
-        );
+            embed_dim,
+            config.inner_dim(),
+        )?;
 
         let proj = GPT::<T>::load_linear(
             tensors,
             &format!("h.{}.mlp.c_proj.weight", index),
             &format!("h.{}.mlp.c_proj.bias", index),
-        );
+            config.inner_dim(),
+            embed_dim,
+        )?;
 
         let ln_1 = GPT::<T>::load_layer_norm(
             tensors,
             &format!("h.{}.ln_1.weight", index),
             &format!("h.{}.ln_1.bias", index),
-        );
+            embed_dim,
+            config.layer_norm_epsilon,
+        )?;
 
         let ln_2 = GPT::<T>::load_layer_norm(
             tensors,
             &format!("h.{}.ln_2.weight", index),
             &format!("h.{}.ln_2.bias", index),
-        );
-
-        Block::<T>::new(ln_1, head, ln_2, fc, proj)
+            embed_dim,
+            config.layer_norm_epsilon,
+        )?;
+
+        Ok(Block::<T>::new_with_activation(
+            ln_1,
+            head,
+            ln_2,
+            fc,
+            proj,
+            config.activation,
+        ))
     }
 
-    pub fn load_from_safe_tensors(tensors: &SafeTensors, num_block: usize) -> GPT<T> {
-        let w_token_embed = from_safe_tensorview::<T>(tensors.tensor("wte.weight").unwrap());
-
-        let w_pos_embed = from_safe_tensorview::<T>(tensors.tensor("wpe.weight").unwrap());
+    pub fn load_from_safe_tensors<L: TensorLoader>(
+        tensors: &L,
+        config: &GPTConfig,
+    ) -> Result<GPT<T>, LoadError> {
+        config.validate()?;
+        let tensors = &TransformerPrefix(tensors);
 
-        let blocks = (0..num_block)
-            .map(|i| GPT::<T>::load_block(tensors, i))
-            .collect::<Vec<Block<T>>>();
+        let w_token_embed =
+            tensors.load::<T, Ix2>("wte.weight", &[config.vocab_size, config.n_embd])?;
 
-        let ln_f_weigth = from_safe_tensorview_1d::<T>(tensors.tensor("ln_f.weight").unwrap());
-        let ln_f_bias = from_safe_tensorview_1d::<T>(tensors.tensor("ln_f.bias").unwrap());
+        let w_pos_embed =
+            tensors.load::<T, Ix2>("wpe.weight", &[config.n_positions, config.n_embd])?;
 
-        let ln_f = LayerNorm::<T>::new(ln_f_weigth, ln_f_bias);
+        let blocks = (0..config.n_layer)
+            .map(|i| GPT::<T>::load_block(tensors, i, config))
+            .collect::<Result<Vec<Block<T>>, LoadError>>()?;
 
-        let next_word_weight = from_safe_tensorview::<T>(tensors.tensor("wte.weight").unwrap());
+        let ln_f = GPT::<T>::load_layer_norm(
+            tensors,
+            "ln_f.weight",
+            "ln_f.bias",
+            config.n_embd,
+            config.layer_norm_epsilon,
+        )?;
+
+        // the output layer is tied to the token embedding, when the file is mapped both share the same memory
+        let next_word_weight =
+            tensors.load::<T, Ix2>("wte.weight", &[config.vocab_size, config.n_embd])?;
         let next_word_layer = LinearNoBias::<T>::new(next_word_weight);
 
-        GPT::<T>::new(w_token_embed, w_pos_embed, blocks, ln_f, next_word_layer)
+        Ok(GPT {
+            config: config.clone(),
+            ..GPT::<T>::new(w_token_embed, w_pos_embed, blocks, ln_f, next_word_layer)
+        })
+    }
+}
+
+// the checkpoints saved from GPT2LMHeadModel, like distilgpt2, name their tensors
+// transformer.wte.weight, transformer.h.0.ln_1.weight, ... instead of wte.weight, h.0.ln_1.weight
+struct TransformerPrefix<'a, L: TensorLoader>(&'a L);
+
+impl<L: TensorLoader> TensorLoader for TransformerPrefix<'_, L> {
+    fn load<T: MyFloat, D: Dimension>(
+        &self,
+        name: &str,
+        shape: &[usize],
+    ) -> Result<Weight<T, D>, LoadError> {
+        match self.0.load(name, shape) {
+            // the bare name is reported when neither is found
+            Err(LoadError::MissingTensor(missing)) => self
+                .0
+                .load(&format!("transformer.{}", name), shape)
+                .map_err(|e| match e {
+                    LoadError::MissingTensor(_) => LoadError::MissingTensor(missing),
+                    e => e,
+                }),
+            result => result,
+        }
+    }
+}
+
+impl<T> CausalLM<T> for GPT<T>
+where
+    T: MyFloat,
+{
+    fn forward_batch_with_cache(
+        &self,
+        ids: &ArrayView<usize, Ix2>,
+        mask: &ArrayView<bool, Ix2>,
+        cache: &mut KVCache<T>,
+    ) -> Array<T, Ix3> {
+        let (batch, seq_len) = ids.dim();
+        let output = self.hidden_batch_with_cache(ids, mask, cache, |_| ());
+        let output = self.next_word_layer.forward(&output);
+        let vocab_size = output.shape()[1];
+        output.into_shape((batch, seq_len, vocab_size)).unwrap()
+    }
+
+    fn new_cache(&self) -> KVCache<T> {
+        KVCache::new(self.blocks.len())
+    }
+
+    fn config(&self) -> ModelConfig {
+        ModelConfig::Gpt2(self.config.clone())
+    }
+
+    fn n_positions(&self) -> usize {
+        self.w_pos_embed.shape()[0]
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.w_token_embed.shape()[0]
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::GenerateError;
+    use crate::sampling::Greedy;
+    use crate::testing::{self, pseudo_random, pseudo_random_1d};
     use ndarray::prelude::*;
 
     use tokenizers::Tokenizer;
@@ -186,19 +496,453 @@ mod tests {
         gpt.generate(&ids);
     }
 
-    use std::fs::File;
-    use std::io::prelude::*;
+    fn random_gpt(embed_dim: usize, vocab_size: usize, n_blocks: usize) -> GPT<f32> {
+        let block_size = 64;
+
+        let blocks = (0..n_blocks)
+            .map(|i| {
+                let ln_1 = LayerNorm::<f32>::new(
+                    pseudo_random_1d(embed_dim, 10 * i + 1) + 1.0,
+                    pseudo_random_1d(embed_dim, 10 * i + 2),
+                );
+                let qkv = Linear::<f32>::new(
+                    pseudo_random((embed_dim, 3 * embed_dim), 10 * i + 3),
+                    pseudo_random_1d(3 * embed_dim, 10 * i + 4),
+                );
+                let proj_head = Linear::<f32>::new(
+                    pseudo_random((embed_dim, embed_dim), 10 * i + 5),
+                    pseudo_random_1d(embed_dim, 10 * i + 6),
+                );
+                let ln_2 = LayerNorm::<f32>::new(
+                    pseudo_random_1d(embed_dim, 10 * i + 7) + 1.0,
+                    pseudo_random_1d(embed_dim, 10 * i + 8),
+                );
+                let fc = Linear::<f32>::new(
+                    pseudo_random((embed_dim, 4 * embed_dim), 10 * i + 9),
+                    Array::zeros(4 * embed_dim),
+                );
+                let proj = Linear::<f32>::new(
+                    pseudo_random((4 * embed_dim, embed_dim), 10 * i + 10),
+                    Array::zeros(embed_dim),
+                );
+                Block::<f32>::new(ln_1, CausalHead::new(qkv, proj_head, 2), ln_2, fc, proj)
+            })
+            .collect::<Vec<Block<f32>>>();
+
+        let w_token_embed = pseudo_random((vocab_size, embed_dim), 1000);
+        let w_pos_embed = pseudo_random((block_size, embed_dim), 2000);
+
+        let ln_f = LayerNorm::<f32>::new(Array::ones(embed_dim), pseudo_random_1d(embed_dim, 3000));
+        let next_word_layer = LinearNoBias::<f32>::new(w_token_embed.clone());
+
+        GPT::<f32>::new(w_token_embed, w_pos_embed, blocks, ln_f, next_word_layer)
+    }
+
+    fn assert_close(a: &ArrayView<f32, Ix1>, b: &ArrayView<f32, Ix1>) {
+        let max_diff = (a - b).mapv(f32::abs).fold(0.0, |acc: f32, &x| acc.max(x));
+        assert!(max_diff < 1e-4, "max diff {}", max_diff);
+    }
 
     #[test]
-    fn test_weight_loading() {
-        let mut f = File::open("models/model.safetensors").unwrap();
-        let mut buffer = Vec::new();
+    fn test_config_from_layers() {
+        let gpt = random_gpt(8, 50, 2);
+        let ModelConfig::Gpt2(config) = gpt.config() else {
+            panic!("not a GPT-2 config");
+        };
+        assert_eq!(config.n_layer, 2);
+        assert_eq!(config.n_head, 2);
+        assert_eq!(config.n_embd, 8);
+        assert_eq!(config.n_positions, 64);
+        assert_eq!(config.inner_dim(), 32);
+        assert_eq!(gpt.vocab_size(), 50);
+    }
+
+    #[test]
+    fn test_cache_matches_full_forward() {
+        let gpt = random_gpt(8, 50, 2);
+        let ids: Vec<usize> = vec![3, 14, 15, 9, 26, 5, 35];
 
-        // read the whole file
-        f.read_to_end(&mut buffer).unwrap();
-        let tensors: SafeTensors = SafeTensors::deserialize(&buffer).unwrap();
+        let full = gpt.forward(&ids);
 
-        let gpt = GPT::<f32>::load_from_safe_tensors(&tensors, 2);
+        let mut cache = gpt.new_cache();
+        let prompt_len = 3;
+        let prefix = gpt.forward_with_cache(&ids[..prompt_len], &mut cache);
+        assert_eq!(cache.len(), prompt_len);
+
+        for i in 0..prompt_len {
+            assert_close(&prefix.row(i), &full.row(i));
+        }
+
+        for i in prompt_len..ids.len() {
+            let step = gpt.forward_with_cache(&ids[i..i + 1], &mut cache);
+            assert_eq!(step.shape(), &[1, 50]);
+            assert_close(&step.row(0), &full.row(i));
+        }
+        assert_eq!(cache.len(), ids.len());
+    }
+
+    #[test]
+    fn test_batch_matches_single_sequences() {
+        let gpt = random_gpt(8, 50, 2);
+        let sequences = vec![vec![3, 14, 15, 9, 26], vec![5, 35], vec![8, 9, 7, 9]];
+
+        let batch = Batch::new(&sequences);
+        let logits = gpt.forward_batch(&batch);
+        assert_eq!(logits.shape(), &[3, 5, 50]);
+
+        for (i, ids) in sequences.iter().enumerate() {
+            let single = gpt.forward(ids);
+            let padding = batch.seq_len() - ids.len();
+            for j in 0..ids.len() {
+                assert_close(&logits.slice(s![i, padding + j, ..]), &single.row(j));
+            }
+        }
+
+        // one more token for every sequence, through the cache
+        let mut cache = gpt.new_cache();
+        gpt.forward_batch_with_cache(&batch.ids(), &batch.mask(), &mut cache);
+        let next = array![[1], [2], [3]];
+        let new_mask = Array::from_elem((3, 1), true);
+        let mask = ndarray::concatenate(Axis(1), &[batch.mask(), new_mask.view()]).unwrap();
+        let logits = gpt.forward_batch_with_cache(&next.view(), &mask.view(), &mut cache);
+
+        for (i, ids) in sequences.iter().enumerate() {
+            let mut ids = ids.clone();
+            ids.push(i + 1);
+            let single = gpt.forward(&ids);
+            assert_close(&logits.slice(s![i, 0, ..]), &single.row(ids.len() - 1));
+        }
+    }
+
+    #[test]
+    fn test_hidden_states() {
+        let gpt = random_gpt(8, 50, 2);
+        let ids = vec![3, 14, 15, 9, 26];
+
+        let hidden = gpt.hidden_states(&ids).unwrap();
+        assert_eq!(hidden.layers.len(), 2);
+        assert_eq!(hidden.layers[1].shape(), &[5, 8]);
+        assert_eq!(hidden.last, gpt.ln_f.forward(&hidden.layers[1]));
+
+        let logits = gpt.next_word_layer.forward(&hidden.last);
+        let full = gpt.forward(&ids);
+        for i in 0..ids.len() {
+            assert_close(&logits.row(i), &full.row(i));
+        }
+
+        let mean = gpt.embed(&ids, Pooling::Mean).unwrap();
+        assert_close(
+            &mean.view(),
+            &hidden.last.mean_axis(Axis(0)).unwrap().view(),
+        );
+        let last = gpt.embed(&ids, Pooling::LastToken).unwrap();
+        assert_close(&last.view(), &hidden.last.row(4));
+        let max = gpt.embed(&ids, Pooling::Max).unwrap();
+        for (j, value) in max.iter().enumerate() {
+            assert!(hidden.last.column(j).iter().all(|x| x <= value));
+        }
+    }
+
+    #[test]
+    fn test_hidden_states_context() {
+        let gpt = random_gpt(8, 50, 2);
+
+        assert!(matches!(
+            gpt.hidden_states(&[]),
+            Err(GenerateError::EmptyPrompt)
+        ));
+        // the context of random_gpt is 64 positions
+        assert_eq!(gpt.hidden_states(&[1; 64]).unwrap().last.shape(), &[64, 8]);
+        assert!(matches!(
+            gpt.hidden_states(&[1; 65]),
+            Err(GenerateError::ContextOverflow { len: 65, max: 64 })
+        ));
+    }
+
+    #[test]
+    fn test_embed_batch_ignores_padding() {
+        let gpt = random_gpt(8, 50, 2);
+        let sequences = vec![vec![3, 14, 15, 9, 26], vec![5, 35]];
+        let batch = Batch::new(&sequences);
+
+        for pooling in [Pooling::Mean, Pooling::LastToken, Pooling::Max] {
+            let embeddings = gpt.embed_batch(&batch, pooling).unwrap();
+            assert_eq!(embeddings.shape(), &[2, 8]);
+            for (ids, embedding) in sequences.iter().zip(embeddings.outer_iter()) {
+                assert_close(&embedding, &gpt.embed(ids, pooling).unwrap().view());
+            }
+        }
+
+        let empty = Batch::new(&[vec![3, 14], vec![]]);
+        assert_eq!(
+            gpt.embed_batch(&empty, Pooling::Mean),
+            Err(GenerateError::EmptyPrompt)
+        );
+        assert_eq!(
+            gpt.embed(&[], Pooling::LastToken),
+            Err(GenerateError::EmptyPrompt)
+        );
+        assert_eq!(
+            gpt.embed(&[1; 65], Pooling::Max),
+            Err(GenerateError::ContextOverflow { len: 65, max: 64 })
+        );
+
+        assert_eq!("last".parse(), Ok(Pooling::LastToken));
+        assert!("sum".parse::<Pooling>().is_err());
+    }
+
+    #[test]
+    fn test_hooks() {
+        use std::sync::{Arc, Mutex};
+
+        let mut gpt = random_gpt(8, 50, 2);
+        let ids = vec![3, 14, 15, 9];
+        let logits = gpt.forward(&ids);
+
+        // reading the attention probabilities does not change the output
+        let probs = Arc::new(Mutex::new(Vec::new()));
+        let captured = probs.clone();
+        gpt.register_hook(1, HookPoint::AttentionProbs, move |x| {
+            captured.lock().unwrap().push(x.to_owned())
+        });
+        assert_eq!(gpt.forward(&ids), logits);
+
+        let probs = probs.lock().unwrap().clone();
+        assert_eq!(probs.len(), 1);
+        assert_eq!(probs[0].shape(), &[2, 4, 4]);
+        for row in probs[0].rows() {
+            assert!((row.sum() - 1.0).abs() < 1e-5);
+        }
+        // the first token can only attend to itself
+        assert_eq!(probs[0][[0, 0, 1]], 0.0);
+
+        // the output of the last block is replaced by zeros
+        gpt.register_hook(1, HookPoint::BlockOutput, |x| x.fill(0.0));
+        let edited = gpt.forward(&ids);
+        let expected = gpt
+            .next_word_layer
+            .forward(&gpt.ln_f.forward(&Array::zeros((4, 8))));
+        for i in 0..ids.len() {
+            assert_close(&edited.row(i), &expected.row(i));
+        }
+
+        gpt.clear_hooks();
+        assert_eq!(gpt.forward(&ids), logits);
+
+        // the hooks also run on the tokens going through the cache
+        gpt.register_hook(0, HookPoint::Value, |x| x.fill(0.0));
+        let full = gpt.forward(&ids);
+        let mut cache = gpt.new_cache();
+        gpt.forward_with_cache(&ids[..2], &mut cache);
+        let step = gpt.forward_with_cache(&ids[2..], &mut cache);
+        assert_close(&step.row(1), &full.row(3));
+    }
+
+    #[test]
+    fn test_generate_with_cache() {
+        let gpt = random_gpt(8, 50, 2);
+        let mut ids: Vec<usize> = vec![1, 2, 3];
+
+        let mut cache = gpt.new_cache();
+        let mut next = gpt.generate_with_cache(&ids, &mut cache, &mut Greedy);
+
+        for _ in 0..5 {
+            assert_eq!(next, gpt.generate(&ids));
+            ids.push(next);
+            next = gpt.generate_with_cache(&ids[ids.len() - 1..], &mut cache, &mut Greedy);
+        }
+    }
+
+    fn ids(len: usize) -> Vec<usize> {
+        (0..len).map(|i| (i * 7) % 50).collect()
+    }
+
+    #[test]
+    fn test_context_overflow_error() {
+        let gpt = random_gpt(8, 50, 2);
+        assert_eq!(gpt.n_positions(), 64);
+
+        // the context is full but there is still room to predict the next token
+        let mut ids = ids(62);
+        let mut cache = gpt.new_cache();
+        for _ in 0..3 {
+            let next = gpt
+                .generate_next(&ids, &mut cache, &mut Greedy, ContextOverflow::Error)
+                .unwrap();
+            ids.push(next);
+        }
+        assert_eq!(ids.len(), 65);
+
+        let error = gpt.generate_next(&ids, &mut cache, &mut Greedy, ContextOverflow::Error);
+        assert_eq!(
+            error,
+            Err(GenerateError::ContextOverflow { len: 65, max: 64 })
+        );
+
+        let error = gpt.generate_next(&[], &mut cache, &mut Greedy, ContextOverflow::Error);
+        assert_eq!(error, Err(GenerateError::EmptyPrompt));
+    }
+
+    #[test]
+    fn test_context_overflow_truncate_left() {
+        let gpt = random_gpt(8, 50, 2);
+        let policy = ContextOverflow::TruncateLeft;
+
+        let mut ids = ids(70);
+        let mut cache = gpt.new_cache();
+        let next = gpt
+            .generate_next(&ids, &mut cache, &mut Greedy, policy)
+            .unwrap();
+        assert_eq!(next, gpt.generate(&ids[6..]));
+        assert_eq!(cache.start(), 6);
+        assert_eq!(cache.len(), 64);
+
+        // the prompt is only truncated once, the generation can't go further
+        ids.push(next);
+        let error = gpt.generate_next(&ids, &mut cache, &mut Greedy, policy);
+        assert_eq!(
+            error,
+            Err(GenerateError::ContextOverflow { len: 65, max: 64 })
+        );
+    }
+
+    #[test]
+    fn test_context_overflow_sliding_window() {
+        let gpt = random_gpt(8, 50, 2);
+
+        let mut ids = ids(60);
+        let mut cache = gpt.new_cache();
+        for _ in 0..10 {
+            let next = gpt
+                .generate_next(
+                    &ids,
+                    &mut cache,
+                    &mut Greedy,
+                    ContextOverflow::SlidingWindow,
+                )
+                .unwrap();
+            let start = ids.len().saturating_sub(64);
+            assert_eq!(next, gpt.generate(&ids[start..]));
+            assert_eq!(cache.start(), start);
+            ids.push(next);
+        }
+        assert_eq!(cache.len(), 64);
+    }
+
+    #[test]
+    fn test_context_overflow_from_str() {
+        assert_eq!(
+            "sliding-window".parse::<ContextOverflow>(),
+            Ok(ContextOverflow::SlidingWindow)
+        );
+        assert!("truncate".parse::<ContextOverflow>().is_err());
+    }
+
+    use crate::convert::SafeTensorsFile;
+    use safetensors::tensor::{Dtype, TensorView};
+    use safetensors::SafeTensors;
+
+    #[test]
+    fn test_load_reports_missing_tensor() {
+        let config = GPTConfig {
+            n_layer: 1,
+            n_head: 2,
+            n_embd: 4,
+            n_positions: 8,
+            vocab_size: 10,
+            ..Default::default()
+        };
+
+        let wte = vec![0u8; 10 * 4 * 4];
+        let wpe = vec![0u8; 8 * 4 * 4];
+        let tensors = vec![
+            (
+                "wte.weight",
+                TensorView::new(Dtype::F32, vec![10, 4], &wte).unwrap(),
+            ),
+            (
+                "wpe.weight",
+                TensorView::new(Dtype::F32, vec![8, 4], &wpe).unwrap(),
+            ),
+        ];
+        let buffer = safetensors::serialize(tensors, &None).unwrap();
+        let tensors = SafeTensors::deserialize(&buffer).unwrap();
+
+        let error = GPT::<f32>::load_from_safe_tensors(&tensors, &config)
+            .err()
+            .unwrap();
+        assert!(
+            matches!(error, LoadError::MissingTensor(name) if name == "h.0.attn.c_attn.weight")
+        );
+
+        let config = GPTConfig {
+            n_positions: 16,
+            ..config
+        };
+        let error = GPT::<f32>::load_from_safe_tensors(&tensors, &config)
+            .err()
+            .unwrap();
+        assert!(matches!(error, LoadError::ShapeMismatch { name, .. } if name == "wpe.weight"));
+    }
+
+    #[test]
+    fn test_load_transformer_prefix() {
+        let config = GPTConfig {
+            n_layer: 1,
+            n_head: 2,
+            n_embd: 4,
+            n_positions: 8,
+            vocab_size: 10,
+            ..Default::default()
+        };
+        let checkpoint = |prefix: &str| {
+            let mut shapes = vec![("wte.weight", vec![10, 4]), ("wpe.weight", vec![8, 4])];
+            shapes.extend([
+                ("h.0.ln_1.weight", vec![4]),
+                ("h.0.ln_1.bias", vec![4]),
+                ("h.0.attn.c_attn.weight", vec![4, 12]),
+                ("h.0.attn.c_attn.bias", vec![12]),
+                ("h.0.attn.c_proj.weight", vec![4, 4]),
+                ("h.0.attn.c_proj.bias", vec![4]),
+                ("h.0.ln_2.weight", vec![4]),
+                ("h.0.ln_2.bias", vec![4]),
+                ("h.0.mlp.c_fc.weight", vec![4, 16]),
+                ("h.0.mlp.c_fc.bias", vec![16]),
+                ("h.0.mlp.c_proj.weight", vec![16, 4]),
+                ("h.0.mlp.c_proj.bias", vec![4]),
+                ("ln_f.weight", vec![4]),
+                ("ln_f.bias", vec![4]),
+            ]);
+            let shapes: Vec<(String, Vec<usize>)> = shapes
+                .into_iter()
+                .map(|(name, shape)| (format!("{}{}", prefix, name), shape))
+                .collect();
+            testing::tiny_checkpoint(&shapes, |_, x| x)
+        };
+
+        let bare = checkpoint("");
+        let bare =
+            GPT::<f32>::load_from_safe_tensors(&SafeTensors::deserialize(&bare).unwrap(), &config)
+                .unwrap();
+        let prefixed = checkpoint("transformer.");
+        let prefixed = GPT::<f32>::load_from_safe_tensors(
+            &SafeTensors::deserialize(&prefixed).unwrap(),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(prefixed.forward(&[1, 5, 9]), bare.forward(&[1, 5, 9]));
+    }
+
+    #[test]
+    fn test_weight_loading() {
+        let file = SafeTensorsFile::open("models/model.safetensors").unwrap();
+        let tensors = file.tensors().unwrap();
+
+        let config = GPTConfig {
+            n_layer: 2,
+            ..Default::default()
+        };
+        let gpt = GPT::<f32>::load_from_safe_tensors(&tensors, &config).unwrap();
 
         let tokenizer = Tokenizer::from_file("tokenizer/tokenizer.json").unwrap();
 
@@ -1,6 +1,20 @@
+pub mod batch;
+pub mod beam;
+pub mod config;
 pub mod convert;
+pub mod detokenizer;
+pub mod error;
+pub mod eval;
 pub mod float;
+pub mod generation;
 pub mod gpt2;
+pub mod llama;
+pub mod model;
+pub mod neox;
 pub mod nn;
+pub mod sampling;
+pub mod server;
+#[cfg(test)]
+mod testing;
 
 extern crate blas_src;
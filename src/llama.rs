@@ -0,0 +1,733 @@
+use crate::batch::position_ids;
+use crate::config::{LlamaConfig, ModelConfig};
+use crate::convert::TensorLoader;
+use crate::error::LoadError;
+use crate::float::MyFloat;
+use crate::model::CausalLM;
+use crate::nn::block::Activation;
+use crate::nn::cache::{KVCache, LayerCache};
+use crate::nn::head::CausalHead;
+use crate::nn::hooks::{Hook, HookPoint, Hooks};
+use crate::nn::linear::{Linear, LinearNoBias};
+use crate::nn::moe::{Expert, SparseMoe};
+use crate::nn::rms_norm::RmsNorm;
+use crate::nn::rope::Rope;
+use crate::nn::weight::Weight;
+use ndarray::{Array, ArrayView, ArrayViewMut, Axis, Ix1, Ix2, Ix3, IxDyn};
+use std::sync::Arc;
+
+// the projections of the Hugging Face checkpoints are stored (out, in), which is what
+// LinearNoBias expects, so the weights of the MLP are used as they are in the file
+
+// down(silu(gate(x)) * up(x))
+pub struct SwiGlu<T>
+where
+    T: MyFloat,
+{
+    gate_proj: LinearNoBias<T>,
+    up_proj: LinearNoBias<T>,
+    down_proj: LinearNoBias<T>,
+}
+
+impl<T> SwiGlu<T>
+where
+    T: MyFloat,
+{
+    pub fn new(
+        gate_proj: LinearNoBias<T>,
+        up_proj: LinearNoBias<T>,
+        down_proj: LinearNoBias<T>,
+    ) -> SwiGlu<T> {
+        SwiGlu {
+            gate_proj,
+            up_proj,
+            down_proj,
+        }
+    }
+
+    pub fn forward(&self, x: &Array<T, Ix2>) -> Array<T, Ix2> {
+        self.forward_with_hooks(x, &Hooks::new())
+    }
+
+    // the MlpActivation hooks get silu(gate(x)) * up(x)
+    fn forward_with_hooks(&self, x: &Array<T, Ix2>, hooks: &Hooks<T>) -> Array<T, Ix2> {
+        let mut gate = self.gate_proj.forward(x);
+        Activation::Silu.apply_inplace(&mut gate);
+        let mut hidden = gate * self.up_proj.forward(x);
+        hooks.run(HookPoint::MlpActivation, &mut hidden);
+        self.down_proj.forward(&hidden)
+    }
+}
+
+impl<T> Expert<T> for SwiGlu<T>
+where
+    T: MyFloat,
+{
+    fn forward(&self, x: &Array<T, Ix2>) -> Array<T, Ix2> {
+        SwiGlu::forward(self, x)
+    }
+}
+
+// the MLP of a block, a single SwiGlu for LLaMA or a mixture of them for Mixtral
+pub enum FeedForward<T>
+where
+    T: MyFloat,
+{
+    Dense(SwiGlu<T>),
+    Sparse(SparseMoe<T, SwiGlu<T>>),
+}
+
+impl<T> FeedForward<T>
+where
+    T: MyFloat,
+{
+    pub fn forward(&self, x: &Array<T, Ix2>) -> Array<T, Ix2> {
+        self.forward_with_hooks(x, &Hooks::new())
+    }
+
+    // every expert only runs on some of the tokens, so a mixture has no activation for the hooks
+    fn forward_with_hooks(&self, x: &Array<T, Ix2>, hooks: &Hooks<T>) -> Array<T, Ix2> {
+        match self {
+            FeedForward::Dense(mlp) => mlp.forward_with_hooks(x, hooks),
+            FeedForward::Sparse(moe) => moe.forward(x),
+        }
+    }
+}
+
+impl<T: MyFloat> From<SwiGlu<T>> for FeedForward<T> {
+    fn from(mlp: SwiGlu<T>) -> Self {
+        FeedForward::Dense(mlp)
+    }
+}
+
+impl<T: MyFloat> From<SparseMoe<T, SwiGlu<T>>> for FeedForward<T> {
+    fn from(moe: SparseMoe<T, SwiGlu<T>>) -> Self {
+        FeedForward::Sparse(moe)
+    }
+}
+
+pub struct LlamaBlock<T>
+where
+    T: MyFloat,
+{
+    input_layernorm: RmsNorm<T>,
+    attention: CausalHead<T>,
+    post_attention_layernorm: RmsNorm<T>,
+    mlp: FeedForward<T>,
+    hooks: Hooks<T>,
+}
+
+impl<T> LlamaBlock<T>
+where
+    T: MyFloat,
+{
+    pub fn new(
+        input_layernorm: RmsNorm<T>,
+        attention: CausalHead<T>,
+        post_attention_layernorm: RmsNorm<T>,
+        mlp: impl Into<FeedForward<T>>,
+    ) -> LlamaBlock<T> {
+        LlamaBlock {
+            input_layernorm,
+            attention,
+            post_attention_layernorm,
+            mlp: mlp.into(),
+            hooks: Hooks::new(),
+        }
+    }
+
+    pub fn forward_batch_with_cache(
+        &self,
+        x: &Array<T, Ix2>,
+        mask: &ArrayView<bool, Ix2>,
+        cache: &mut LayerCache<T>,
+    ) -> Array<T, Ix2> {
+        // x is (batch * seq, embed), only the attention needs to know where the sequences start
+        let batch = mask.shape()[0];
+        let (rows, embed_dim) = x.dim();
+
+        let y = self.input_layernorm.forward(x);
+        let y = y.into_shape((batch, rows / batch, embed_dim)).unwrap();
+        let y = self
+            .attention
+            .attention_batch_with_cache(&y.view(), mask, cache);
+        let x = x + y.into_shape((rows, embed_dim)).unwrap();
+
+        let y = self.post_attention_layernorm.forward(&x);
+        let mut x = x + self.mlp.forward_with_hooks(&y, &self.hooks);
+        self.hooks.run(HookPoint::BlockOutput, &mut x);
+        x
+    }
+
+    pub fn register_hook(&mut self, point: HookPoint, hook: Hook<T>) {
+        match point {
+            HookPoint::Query | HookPoint::Key | HookPoint::Value | HookPoint::AttentionProbs => {
+                self.attention.register_hook(point, hook)
+            }
+            HookPoint::MlpActivation | HookPoint::BlockOutput => self.hooks.register(point, hook),
+        }
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.attention.clear_hooks();
+        self.hooks.clear();
+    }
+}
+
+pub struct Llama<T>
+where
+    T: MyFloat,
+{
+    embed_tokens: Weight<T, Ix2>,
+    layers: Vec<LlamaBlock<T>>,
+    norm: RmsNorm<T>,
+    lm_head: LinearNoBias<T>,
+    config: LlamaConfig,
+}
+
+impl<T> Llama<T>
+where
+    T: MyFloat,
+{
+    pub fn new(
+        config: &LlamaConfig,
+        embed_tokens: impl Into<Weight<T, Ix2>>,
+        layers: Vec<LlamaBlock<T>>,
+        norm: RmsNorm<T>,
+        lm_head: LinearNoBias<T>,
+    ) -> Llama<T> {
+        Llama {
+            embed_tokens: embed_tokens.into(),
+            layers,
+            norm,
+            lm_head,
+            config: config.clone(),
+        }
+    }
+
+    // the hook reads or changes an activation of the block at index layer during every forward
+    // pass, until the hooks are cleared
+    pub fn register_hook(
+        &mut self,
+        layer: usize,
+        point: HookPoint,
+        hook: impl Fn(&mut ArrayViewMut<T, IxDyn>) + Send + Sync + 'static,
+    ) {
+        self.layers[layer].register_hook(point, Box::new(hook));
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.layers.iter_mut().for_each(|layer| layer.clear_hooks());
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.layers.len()
+    }
+
+    fn load_linear<L: TensorLoader>(
+        tensors: &L,
+        name: &str,
+        dim_in: usize,
+        dim_out: usize,
+    ) -> Result<LinearNoBias<T>, LoadError> {
+        let weight = tensors.load::<T, Ix2>(name, &[dim_out, dim_in])?;
+        Ok(LinearNoBias::new(weight))
+    }
+
+    fn load_rms_norm<L: TensorLoader>(
+        tensors: &L,
+        name: &str,
+        config: &LlamaConfig,
+    ) -> Result<RmsNorm<T>, LoadError> {
+        let weight = tensors.load::<T, Ix1>(name, &[config.hidden_size])?;
+        Ok(RmsNorm::new_with_eps(
+            weight,
+            T::from(config.rms_norm_eps).unwrap(),
+        ))
+    }
+
+    // CausalHead computes x @ (in, out), so the attention weights are transposed and can't stay in
+    // the mapped file
+    fn load_attention_linear<L: TensorLoader>(
+        tensors: &L,
+        name: &str,
+        dim_in: usize,
+        dim_out: usize,
+    ) -> Result<Linear<T>, LoadError> {
+        let weight = tensors.load::<T, Ix2>(name, &[dim_out, dim_in])?;
+        Ok(Linear::new_no_bias(
+            weight.view().t().as_standard_layout().into_owned(),
+        ))
+    }
+
+    pub fn load_block<L: TensorLoader>(
+        tensors: &L,
+        index: usize,
+        config: &LlamaConfig,
+        rope: &Arc<Rope<T>>,
+    ) -> Result<LlamaBlock<T>, LoadError> {
+        let prefix = format!("model.layers.{}", index);
+        let hidden = config.hidden_size;
+        let kv_dim = config.num_kv_heads() * config.head_dim();
+        let linear = |name: &str, dim_in, dim_out| {
+            Llama::<T>::load_linear(tensors, &format!("{}.{}", prefix, name), dim_in, dim_out)
+        };
+
+        let attention_linear = |name: &str, dim_in, dim_out| {
+            let name = format!("{}.self_attn.{}.weight", prefix, name);
+            Llama::<T>::load_attention_linear(tensors, &name, dim_in, dim_out)
+        };
+        let qkv = (
+            attention_linear("q_proj", hidden, hidden)?,
+            attention_linear("k_proj", hidden, kv_dim)?,
+            attention_linear("v_proj", hidden, kv_dim)?,
+        );
+        let attention = CausalHead::new_grouped(
+            qkv,
+            attention_linear("o_proj", hidden, hidden)?,
+            config.num_attention_heads,
+            config.num_kv_heads(),
+            Some(rope.clone()),
+        );
+
+        let inner = config.intermediate_size;
+        let mlp = match config.num_local_experts {
+            None => FeedForward::Dense(SwiGlu::new(
+                linear("mlp.gate_proj.weight", hidden, inner)?,
+                linear("mlp.up_proj.weight", hidden, inner)?,
+                linear("mlp.down_proj.weight", inner, hidden)?,
+            )),
+            // the experts of Mixtral name their projections w1 (gate), w3 (up) and w2 (down)
+            Some(num_experts) => {
+                let experts = (0..num_experts)
+                    .map(|e| {
+                        let expert = format!("block_sparse_moe.experts.{}", e);
+                        Ok(SwiGlu::new(
+                            linear(&format!("{}.w1.weight", expert), hidden, inner)?,
+                            linear(&format!("{}.w3.weight", expert), hidden, inner)?,
+                            linear(&format!("{}.w2.weight", expert), inner, hidden)?,
+                        ))
+                    })
+                    .collect::<Result<Vec<SwiGlu<T>>, LoadError>>()?;
+                let gate = linear("block_sparse_moe.gate.weight", hidden, num_experts)?;
+                FeedForward::Sparse(SparseMoe::new(gate, experts, config.num_experts_per_tok))
+            }
+        };
+
+        Ok(LlamaBlock::new(
+            Llama::<T>::load_rms_norm(
+                tensors,
+                &format!("{}.input_layernorm.weight", prefix),
+                config,
+            )?,
+            attention,
+            Llama::<T>::load_rms_norm(
+                tensors,
+                &format!("{}.post_attention_layernorm.weight", prefix),
+                config,
+            )?,
+            mlp,
+        ))
+    }
+
+    pub fn load_from_safe_tensors<L: TensorLoader>(
+        tensors: &L,
+        config: &LlamaConfig,
+    ) -> Result<Llama<T>, LoadError> {
+        config.validate()?;
+
+        let embed_shape = [config.vocab_size, config.hidden_size];
+        let embed_tokens = tensors.load::<T, Ix2>("model.embed_tokens.weight", &embed_shape)?;
+
+        let rope = Arc::new(Rope::new(
+            config.head_dim(),
+            config.max_position_embeddings,
+            config.rope_theta,
+        ));
+        let layers = (0..config.num_hidden_layers)
+            .map(|i| Llama::<T>::load_block(tensors, i, config, &rope))
+            .collect::<Result<Vec<LlamaBlock<T>>, LoadError>>()?;
+
+        let norm = Llama::<T>::load_rms_norm(tensors, "model.norm.weight", config)?;
+
+        // most checkpoints have their own output layer, the small ones can share the embedding
+        let lm_head_name = if config.tie_word_embeddings {
+            "model.embed_tokens.weight"
+        } else {
+            "lm_head.weight"
+        };
+        let lm_head = LinearNoBias::new(tensors.load::<T, Ix2>(lm_head_name, &embed_shape)?);
+
+        Ok(Llama::new(config, embed_tokens, layers, norm, lm_head))
+    }
+}
+
+impl<T> CausalLM<T> for Llama<T>
+where
+    T: MyFloat,
+{
+    fn forward_batch_with_cache(
+        &self,
+        ids: &ArrayView<usize, Ix2>,
+        mask: &ArrayView<bool, Ix2>,
+        cache: &mut KVCache<T>,
+    ) -> Array<T, Ix3> {
+        let (batch, seq_len) = ids.dim();
+        assert_eq!(mask.shape(), &[batch, cache.len() + seq_len]);
+
+        // the positions only enter through the rotary embeddings of the attention
+        let max_position = position_ids(mask).iter().copied().max().unwrap_or(0);
+        assert!(
+            max_position < self.n_positions(),
+            "{} tokens don't fit in the context of {} positions",
+            max_position + 1,
+            self.n_positions()
+        );
+
+        let flat_ids: Vec<usize> = ids.iter().copied().collect();
+        let mut output = self.embed_tokens.view().select(Axis(0), &flat_ids);
+        for (layer, layer_cache) in self.layers.iter().zip(cache.layers_mut()) {
+            output = layer.forward_batch_with_cache(&output, mask, layer_cache);
+        }
+
+        let output = self.lm_head.forward(&self.norm.forward(&output));
+        let vocab_size = output.shape()[1];
+        output.into_shape((batch, seq_len, vocab_size)).unwrap()
+    }
+
+    fn new_cache(&self) -> KVCache<T> {
+        KVCache::new(self.layers.len())
+    }
+
+    fn config(&self) -> ModelConfig {
+        ModelConfig::Llama(self.config.clone())
+    }
+
+    fn n_positions(&self) -> usize {
+        self.config.max_position_embeddings
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.config.vocab_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::Batch;
+    use crate::testing::{self, pseudo_random};
+    use ndarray::s;
+    use safetensors::tensor::{Dtype, TensorView};
+    use safetensors::SafeTensors;
+
+    fn tiny_config() -> LlamaConfig {
+        LlamaConfig::from_json(
+            r#"{
+                "hidden_size": 16,
+                "intermediate_size": 24,
+                "num_hidden_layers": 2,
+                "num_attention_heads": 4,
+                "num_key_value_heads": 2,
+                "max_position_embeddings": 32,
+                "vocab_size": 12
+            }"#,
+        )
+        .unwrap()
+    }
+
+    // a tiny random checkpoint with the Hugging Face names, in the order of
+    // scripts/reference_logits.py that computed the reference logits. With num_experts it is a
+    // Mixtral, every MLP is replaced by a mixture of experts
+    fn tiny_checkpoint(num_experts: Option<usize>) -> Vec<u8> {
+        let (h, kv, i, v) = (16, 8, 24, 12);
+        let mut shapes = vec![("model.embed_tokens.weight".to_string(), vec![v, h])];
+        for l in 0..2 {
+            let layer =
+                |name: &str, shape: Vec<usize>| (format!("model.layers.{}.{}", l, name), shape);
+            shapes.extend([
+                layer("input_layernorm.weight", vec![h]),
+                layer("self_attn.q_proj.weight", vec![h, h]),
+                layer("self_attn.k_proj.weight", vec![kv, h]),
+                layer("self_attn.v_proj.weight", vec![kv, h]),
+                layer("self_attn.o_proj.weight", vec![h, h]),
+                layer("post_attention_layernorm.weight", vec![h]),
+            ]);
+            let Some(num_experts) = num_experts else {
+                shapes.extend([
+                    layer("mlp.gate_proj.weight", vec![i, h]),
+                    layer("mlp.up_proj.weight", vec![i, h]),
+                    layer("mlp.down_proj.weight", vec![h, i]),
+                ]);
+                continue;
+            };
+            shapes.push(layer("block_sparse_moe.gate.weight", vec![num_experts, h]));
+            for e in 0..num_experts {
+                let expert = |w: &str| format!("block_sparse_moe.experts.{}.{}.weight", e, w);
+                shapes.extend([
+                    layer(&expert("w1"), vec![i, h]),
+                    layer(&expert("w3"), vec![i, h]),
+                    layer(&expert("w2"), vec![h, i]),
+                ]);
+            }
+        }
+        shapes.push(("model.norm.weight".to_string(), vec![h]));
+        shapes.push(("lm_head.weight".to_string(), vec![v, h]));
+
+        // the norm weights are around 1, the router has larger scores so that it picks clear winners
+        testing::tiny_checkpoint(&shapes, |name, x| match name {
+            _ if name.ends_with("norm.weight") => x + 1.0,
+            _ if name.ends_with("gate.weight") => x * 10.0,
+            _ => x,
+        })
+    }
+
+    fn assert_close(a: &ArrayView<f32, Ix1>, b: &ArrayView<f32, Ix1>) {
+        let max_diff = (a - b).mapv(f32::abs).fold(0.0, |acc: f32, &x| acc.max(x));
+        assert!(max_diff < 1e-4, "max diff {}", max_diff);
+    }
+
+    #[test]
+    fn test_reference_logits() {
+        let buffer = tiny_checkpoint(None);
+        let tensors = SafeTensors::deserialize(&buffer).unwrap();
+        let llama = Llama::<f32>::load_from_safe_tensors(&tensors, &tiny_config()).unwrap();
+
+        // python3 scripts/reference_logits.py llama
+        let expected = ndarray::array![
+            [
+                -0.41842, -0.35552, 0.0935973, 0.441035, 0.309357, -0.15839, -0.454071, -0.256474,
+                0.219741, 0.457242, 0.19802, -0.27632
+            ],
+            [
+                0.680028, 0.570909, -0.158415, -0.715645, -0.495438, 0.262985, 0.735716, 0.409206,
+                -0.361843, -0.739806, -0.314084, 0.452841
+            ],
+            [
+                0.199963, 0.178416, -0.0369517, -0.212177, -0.156905, 0.06882, 0.219783, 0.131986,
+                -0.0991934, -0.222615, -0.1042, 0.127412
+            ],
+            [
+                -0.537836, -0.450385, 0.12634, 0.565816, 0.390621, -0.208923, -0.581504, -0.322372,
+                0.286968, 0.584561, 0.24712, -0.358779
+            ],
+            [
+                -0.496147, -0.416361, 0.115736, 0.522104, 0.361287, -0.192012, -0.53672, -0.298365,
+                0.264117, 0.539677, 0.228962, -0.330485
+            ],
+        ];
+        let logits = llama.forward(&[1, 5, 9, 2, 7]);
+        assert_eq!(logits.shape(), &[5, 12]);
+        for i in 0..5 {
+            assert_close(&logits.row(i), &expected.row(i));
+        }
+    }
+
+    #[test]
+    fn test_cache_and_batch_match_full_forward() {
+        let buffer = tiny_checkpoint(None);
+        let tensors = SafeTensors::deserialize(&buffer).unwrap();
+        let llama = Llama::<f32>::load_from_safe_tensors(&tensors, &tiny_config()).unwrap();
+
+        let ids = vec![3, 1, 4, 1, 5, 9, 2];
+        let full = llama.forward(&ids);
+
+        let mut cache = llama.new_cache();
+        llama.forward_with_cache(&ids[..4], &mut cache);
+        for i in 4..ids.len() {
+            let step = llama.forward_with_cache(&ids[i..i + 1], &mut cache);
+            assert_close(&step.row(0), &full.row(i));
+        }
+
+        // the positions of the rotary embeddings start after the padding
+        let batch = Batch::new(&[ids.clone(), vec![8, 6]]);
+        let logits = llama.forward_batch(&batch);
+        for j in 0..ids.len() {
+            assert_close(&logits.slice(s![0, j, ..]), &full.row(j));
+        }
+        let short = llama.forward(&[8, 6]);
+        assert_close(&logits.slice(s![1, 6, ..]), &short.row(1));
+    }
+
+    #[test]
+    fn test_hooks() {
+        use std::sync::{Arc, Mutex};
+
+        let buffer = tiny_checkpoint(None);
+        let tensors = SafeTensors::deserialize(&buffer).unwrap();
+        let mut llama = Llama::<f32>::load_from_safe_tensors(&tensors, &tiny_config()).unwrap();
+        let ids = [1, 5, 9, 2, 7];
+        let logits = llama.forward(&ids);
+
+        // reading the activations does not change the output
+        let shapes = Arc::new(Mutex::new(Vec::new()));
+        for point in [
+            HookPoint::Query,
+            HookPoint::Key,
+            HookPoint::AttentionProbs,
+            HookPoint::MlpActivation,
+        ] {
+            let captured = shapes.clone();
+            llama.register_hook(0, point, move |x| {
+                captured.lock().unwrap().push(x.shape().to_vec())
+            });
+        }
+        assert_eq!(llama.forward(&ids), logits);
+        // 4 query heads share 2 key and value heads
+        assert_eq!(
+            *shapes.lock().unwrap(),
+            vec![vec![4, 5, 4], vec![2, 5, 4], vec![4, 5, 5], vec![5, 24]]
+        );
+
+        // the output of the last block is replaced by zeros
+        llama.register_hook(1, HookPoint::BlockOutput, |x| x.fill(0.0));
+        let edited = llama.forward(&ids);
+        let expected = llama
+            .lm_head
+            .forward(&llama.norm.forward(&Array::zeros((5, 16))));
+        for i in 0..ids.len() {
+            assert_close(&edited.row(i), &expected.row(i));
+        }
+
+        llama.clear_hooks();
+        assert_eq!(llama.forward(&ids), logits);
+    }
+
+    #[test]
+    fn test_tied_embeddings() {
+        let buffer = tiny_checkpoint(None);
+        let tensors = SafeTensors::deserialize(&buffer).unwrap();
+        let config = LlamaConfig {
+            tie_word_embeddings: true,
+            ..tiny_config()
+        };
+        let tied = Llama::<f32>::load_from_safe_tensors(&tensors, &config).unwrap();
+        let untied = Llama::<f32>::load_from_safe_tensors(&tensors, &tiny_config()).unwrap();
+        assert_ne!(tied.forward(&[1, 2]), untied.forward(&[1, 2]));
+
+        let config = LlamaConfig {
+            num_hidden_layers: 3,
+            ..tiny_config()
+        };
+        let error = Llama::<f32>::load_from_safe_tensors(&tensors, &config)
+            .err()
+            .unwrap();
+        assert!(
+            matches!(error, LoadError::MissingTensor(name) if name == "model.layers.2.self_attn.q_proj.weight")
+        );
+    }
+
+    #[test]
+    fn test_mixture_of_identical_experts() {
+        let buffer = tiny_checkpoint(None);
+        let tensors = SafeTensors::deserialize(&buffer).unwrap();
+
+        // the MLP of every layer becomes three experts that are copies of it, behind a random gate
+        let mut moe_tensors: Vec<(String, TensorView)> = Vec::new();
+        for (name, view) in tensors.tensors() {
+            match name.split_once(".mlp.") {
+                None => moe_tensors.push((name, view)),
+                Some((layer, projection)) => {
+                    let w = match projection {
+                        "gate_proj.weight" => "w1",
+                        "up_proj.weight" => "w3",
+                        _ => "w2",
+                    };
+                    for e in 0..3 {
+                        let expert =
+                            format!("{}.block_sparse_moe.experts.{}.{}.weight", layer, e, w);
+                        let copy =
+                            TensorView::new(view.dtype(), view.shape().to_vec(), view.data());
+                        moe_tensors.push((expert, copy.unwrap()));
+                    }
+                }
+            }
+        }
+        let gate: Vec<u8> = pseudo_random((3, 16), 5)
+            .iter()
+            .flat_map(|x| (x * 10.0).to_le_bytes())
+            .collect();
+        for l in 0..2 {
+            let name = format!("model.layers.{}.block_sparse_moe.gate.weight", l);
+            let view = TensorView::new(Dtype::F32, vec![3, 16], &gate).unwrap();
+            moe_tensors.push((name, view));
+        }
+        let buffer = safetensors::serialize(moe_tensors, &None).unwrap();
+        let moe_checkpoint = SafeTensors::deserialize(&buffer).unwrap();
+
+        let config = LlamaConfig {
+            num_local_experts: Some(3),
+            ..tiny_config()
+        };
+        let mixtral = Llama::<f32>::load_from_safe_tensors(&moe_checkpoint, &config).unwrap();
+        assert_eq!(mixtral.config().model_type(), "mixtral");
+
+        // the weights of the chosen experts sum to one, so the output is the one of the dense model
+        let llama = Llama::<f32>::load_from_safe_tensors(&tensors, &tiny_config()).unwrap();
+        let ids = [1, 5, 9, 2, 7];
+        let (expected, logits) = (llama.forward(&ids), mixtral.forward(&ids));
+        for i in 0..ids.len() {
+            assert_close(&logits.row(i), &expected.row(i));
+        }
+
+        // the dense MLP is not there anymore
+        assert!(matches!(
+            Llama::<f32>::load_from_safe_tensors(&moe_checkpoint, &tiny_config()),
+            Err(LoadError::MissingTensor(_))
+        ));
+    }
+
+    #[test]
+    fn test_mixtral_reference_logits() {
+        let buffer = tiny_checkpoint(Some(3));
+        let tensors = SafeTensors::deserialize(&buffer).unwrap();
+        let config = LlamaConfig {
+            num_local_experts: Some(3),
+            ..tiny_config()
+        };
+        let mixtral = Llama::<f32>::load_from_safe_tensors(&tensors, &config).unwrap();
+
+        // python3 scripts/reference_logits.py mixtral
+        let expected = ndarray::array![
+            [
+                -0.220916, 0.224479, 0.426013, 0.16475, -0.275489, -0.416452, -0.105004, 0.320514,
+                0.397843, 0.0429782, -0.358576, -0.370593
+            ],
+            [
+                0.237196, -0.240964, -0.457354, -0.1769, 0.295728, 0.447093, 0.112761, -0.344069,
+                -0.427121, -0.0461728, 0.384935, 0.397871
+            ],
+            [
+                0.131629, -0.126283, -0.247008, -0.099397, 0.156193, 0.242104, 0.0650062, -0.18271,
+                -0.23194, -0.0292033, 0.205259, 0.216739
+            ],
+            [
+                -0.278105, 0.289572, 0.542674, 0.206245, -0.354237, -0.529896, -0.129905, 0.411207,
+                0.505607, 0.0507432, -0.459245, -0.470335
+            ],
+            [
+                -0.254055, 0.262624, 0.494002, 0.188724, -0.321573, -0.482531, -0.119294, 0.373538,
+                0.460579, 0.0472724, -0.417388, -0.428621
+            ],
+        ];
+        let logits = mixtral.forward(&[1, 5, 9, 2, 7]);
+        for i in 0..5 {
+            assert_close(&logits.row(i), &expected.row(i));
+        }
+    }
+
+    #[test]
+    fn test_load_from_model_type() {
+        let buffer = tiny_checkpoint(None);
+        let tensors = SafeTensors::deserialize(&buffer).unwrap();
+        let config = ModelConfig::Llama(tiny_config());
+        let model = crate::model::load_from_safe_tensors::<f32, _>(&tensors, &config).unwrap();
+
+        assert_eq!(model.config().model_type(), "llama");
+        assert_eq!(model.vocab_size(), 12);
+        assert_eq!(model.n_positions(), 32);
+        assert_eq!(model.eos_tokens(), vec![2]);
+        // the largest of the reference logits of the last position
+        assert_eq!(model.generate(&[1, 5, 9, 2, 7]), 9);
+    }
+}
@@ -1,74 +1,314 @@
-use std::fs::File;
+#[path = "bin/common/mod.rs"]
+mod common;
+
+use std::fs;
 use std::io;
 use std::io::prelude::*;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+
+use clap::Parser;
+use half::{bf16, f16};
+use serde::Serialize;
 
-use rusty_llm::gpt2::GPT;
+use common::{fail, Dtype, ModelArgs};
+use rusty_llm::config::ModelConfig;
+use rusty_llm::float::MyFloat;
+use rusty_llm::generation::{FinishReason, GeneratedToken, GenerationConfig, Generator};
+use rusty_llm::gpt2::ContextOverflow;
+use rusty_llm::sampling::SamplerConfig;
 
-use safetensors::SafeTensors;
 use tokenizers::Tokenizer;
 
-use std::env;
-use std::process;
+/// Run a GPT-2, LLaMA, Mixtral or GPT-NeoX model locally on cpu
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Cli {
+    /// Prompt to complete, read from --prompt-file or stdin when missing
+    prompt: Option<String>,
 
-fn main() {
-    // Get command line arguments
-    let args: Vec<String> = env::args().collect();
+    /// Read the prompt from a file
+    #[arg(short = 'f', long, conflicts_with = "prompt")]
+    prompt_file: Option<PathBuf>,
 
-    // Ensure an argument is provided
-    if args.len() != 2 {
-        eprintln!("You must provide exactly one argument.");
-        process::exit(1);
-    }
+    #[command(flatten)]
+    model: ModelArgs,
+
+    /// Number of tokens to generate
+    #[arg(short = 'n', long, default_value_t = 50)]
+    max_tokens: usize,
+
+    /// What to do when the prompt and the generated tokens don't fit in the context:
+    /// error (reject a long prompt and stop when the context is full), truncate-left or sliding-window
+    #[arg(long, default_value = "error")]
+    context_overflow: ContextOverflow,
+
+    /// Stop the generation when this text is generated, can be repeated
+    #[arg(long)]
+    stop: Vec<String>,
+
+    /// Keep generating after the end of text token
+    #[arg(long)]
+    ignore_eos: bool,
+
+    /// Sampling temperature, 0 always picks the most likely token
+    #[arg(long, default_value_t = 0.0)]
+    temperature: f32,
+
+    #[arg(long)]
+    top_k: Option<usize>,
+
+    #[arg(long)]
+    top_p: Option<f32>,
+
+    #[arg(long)]
+    min_p: Option<f32>,
+
+    #[arg(long)]
+    typical_p: Option<f32>,
 
-    // Try to parse the argument as an integer
-    let number: i32 = match args[1].parse() {
-        Ok(v) => v,
-        Err(e) => {
-            eprintln!("Failed to parse argument as integer: {}", e);
-            process::exit(1);
+    /// Seed of the sampler, makes the run reproducible
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Number of threads, defaults to the number of cpus
+    #[arg(long)]
+    threads: Option<usize>,
+
+    /// Only print the completion, for use in pipelines
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Print the generated tokens with their log probabilities as a JSON document
+    #[arg(long)]
+    json: bool,
+
+    /// Number of alternative tokens returned with their log probabilities at every step, in the JSON output
+    #[arg(long, default_value_t = 0, requires = "json")]
+    top_logprobs: usize,
+}
+
+#[derive(Serialize)]
+struct JsonOutput<'a> {
+    prompt: &'a str,
+    text: String,
+    finish_reason: Option<FinishReason>,
+    tokens: Vec<GeneratedToken>,
+}
+
+impl Cli {
+    fn sampler_config(&self) -> SamplerConfig {
+        SamplerConfig {
+            temperature: self.temperature,
+            top_k: self.top_k,
+            top_p: self.top_p,
+            min_p: self.min_p,
+            typical_p: self.typical_p,
+            seed: self.seed,
         }
-    };
+    }
 
-    let mut f = File::open("models/model.safetensors").unwrap();
-    let mut buffer = Vec::new();
+    fn read_prompt(&self) -> io::Result<String> {
+        if let Some(prompt) = &self.prompt {
+            return Ok(prompt.clone());
+        }
+        if let Some(path) = &self.prompt_file {
+            return fs::read_to_string(path);
+        }
 
-    // read the whole file
-    f.read_to_end(&mut buffer).unwrap();
-    let tensors: SafeTensors = SafeTensors::deserialize(&buffer).unwrap();
+        let stdin = io::stdin();
+        let mut prompt = String::new();
+        if stdin.is_terminal() {
+            if !self.quiet {
+                println!("Please enter your prompt: \n");
+                // Make sure the prompt immediately appears on the screen.
+                io::stdout().flush()?;
+            }
+            stdin.read_line(&mut prompt)?;
+        } else {
+            stdin.lock().read_to_string(&mut prompt)?;
+        }
+        Ok(prompt)
+    }
+}
 
-    let gpt = GPT::<f32>::load_from_safe_tensors(&tensors, 12);
+fn main() {
+    let mut cli = Cli::parse();
+    // the JSON document is the only thing written to stdout
+    cli.quiet |= cli.json;
 
-    let tokenizer = Tokenizer::from_file("tokenizer/tokenizer.json").unwrap();
+    if let Some(threads) = cli.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap_or_else(|e| fail(format!("Failed to start {} threads: {}", threads, e)));
+    }
 
-    println!("========== GPT 2 ================");
+    let config = cli.model.read_config();
+    let tokenizer = cli.model.load_tokenizer();
 
-    println!("Please enter your prompt: \n");
+    match cli.model.dtype {
+        Dtype::F32 => run::<f32>(&cli, &config, &tokenizer),
+        Dtype::F16 => run::<f16>(&cli, &config, &tokenizer),
+        Dtype::Bf16 => run::<bf16>(&cli, &config, &tokenizer),
+    }
+}
 
-    // Make sure the prompt immediately appears on the screen.
-    io::stdout().flush().unwrap();
+fn run<T: MyFloat>(cli: &Cli, config: &ModelConfig, tokenizer: &Tokenizer) {
+    let model = cli.model.load_model::<T>(config);
 
-    let mut init_text = String::new();
+    if !cli.quiet {
+        println!("========== {} ================", config.model_type());
+    }
 
-    io::stdin()
-        .read_line(&mut init_text)
-        .expect("Failed to read line");
+    let prompt = cli
+        .read_prompt()
+        .unwrap_or_else(|e| fail(format!("Failed to read the prompt: {}", e)));
 
     // Remove the trailing newline.
-    let init_text = init_text.trim();
+    let prompt = prompt.trim_end_matches(['\r', '\n']);
 
-    let encode = tokenizer.encode(init_text, false).unwrap();
+    let mut generation_config = GenerationConfig {
+        max_tokens: cli.max_tokens,
+        stop: cli.stop.clone(),
+        sampler: cli.sampler_config(),
+        context_overflow: cli.context_overflow,
+        top_logprobs: cli.top_logprobs,
+        ..Default::default()
+    };
+    if cli.ignore_eos {
+        generation_config.eos_tokens = Some(Vec::new());
+    }
+    let stream = Generator::new(&*model, tokenizer)
+        .stream(prompt, &generation_config)
+        .unwrap_or_else(|e| fail(format!("Failed to start the generation: {}", e)));
 
-    let mut ids: Vec<usize> = encode.get_ids().iter().map(|&x| x as usize).collect();
+    if cli.json {
+        let tokens: Vec<GeneratedToken> = stream.collect();
+        let output = JsonOutput {
+            prompt,
+            text: tokens.iter().map(|token| token.text.as_str()).collect(),
+            finish_reason: tokens.last().and_then(|token| token.finish_reason),
+            tokens,
+        };
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+        return;
+    }
 
-    for _ in 0..number {
-        let new_word_id = gpt.generate(&ids);
+    let mut stdout = io::stdout().lock();
+    for token in stream {
+        // a closed pipe is not an error, the reader just does not want more tokens
+        if write!(stdout, "{}", token.text)
+            .and_then(|_| stdout.flush())
+            .is_err()
+        {
+            return;
+        }
+    }
+    let _ = writeln!(stdout);
+}
 
-        ids.push(new_word_id);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let cli = Cli::try_parse_from([
+            "rusty-llm",
+            "--dtype",
+            "bf16",
+            "-n",
+            "12",
+            "--temperature",
+            "0.8",
+            "--top-p",
+            "0.95",
+            "--seed",
+            "42",
+            "-q",
+            "--stop",
+            "Human:",
+            "--stop",
+            "\n\n",
+            "hello world",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.model.dtype, Dtype::Bf16);
+        assert_eq!(cli.max_tokens, 12);
+        assert_eq!(cli.context_overflow, ContextOverflow::Error);
+        assert_eq!(cli.stop, ["Human:", "\n\n"]);
+        assert!(!cli.ignore_eos);
+        assert_eq!(cli.prompt.as_deref(), Some("hello world"));
+        assert!(cli.quiet);
+
+        let sampler = cli.sampler_config();
+        assert_eq!(sampler.temperature, 0.8);
+        assert_eq!(sampler.top_p, Some(0.95));
+        assert_eq!(sampler.seed, Some(42));
+        assert_eq!(sampler.top_k, None);
+    }
+
+    #[test]
+    fn test_config_next_to_weights() {
+        let cli = Cli::try_parse_from(["rusty-llm", "hi"]).unwrap();
+        assert_eq!(cli.model.config_path(), PathBuf::from("models/config.json"));
+
+        let cli =
+            Cli::try_parse_from(["rusty-llm", "-m", "pythia/model.safetensors", "hi"]).unwrap();
+        assert_eq!(cli.model.config_path(), PathBuf::from("pythia/config.json"));
+
+        let cli = Cli::try_parse_from([
+            "rusty-llm",
+            "-m",
+            "a/model.safetensors",
+            "-c",
+            "b.json",
+            "hi",
+        ])
+        .unwrap();
+        assert_eq!(cli.model.config_path(), PathBuf::from("b.json"));
+    }
+
+    #[test]
+    fn test_prompt_sources_conflict() {
+        let cli = Cli::try_parse_from(["rusty-llm", "-f", "prompt.txt", "hello"]);
+        assert!(cli.is_err());
+        assert!(Cli::try_parse_from(["rusty-llm", "--dtype", "f64"]).is_err());
+        assert!(Cli::try_parse_from(["rusty-llm", "--context-overflow", "wrap"]).is_err());
+    }
 
-        let id_to_decode: Vec<u32> = vec![new_word_id as u32];
+    #[test]
+    fn test_json_output() {
+        let cli =
+            Cli::try_parse_from(["rusty-llm", "--json", "--top-logprobs", "5", "hi"]).unwrap();
+        assert!(cli.json);
+        assert_eq!(cli.top_logprobs, 5);
+        // the alternatives are only printed in the JSON output
+        assert!(Cli::try_parse_from(["rusty-llm", "--top-logprobs", "5", "hi"]).is_err());
 
-        let txt = tokenizer.decode(id_to_decode, false).unwrap();
-        print!("{}", txt);
-        io::stdout().flush().unwrap(); // flush to see output in real time
+        let output = JsonOutput {
+            prompt: "hi",
+            text: " there".to_string(),
+            finish_reason: Some(FinishReason::Length),
+            tokens: vec![GeneratedToken {
+                id: 612,
+                text: " there".to_string(),
+                logprob: -0.5,
+                top_logprobs: Vec::new(),
+                finish_reason: Some(FinishReason::Length),
+            }],
+        };
+        let json = serde_json::to_value(&output).unwrap();
+        assert_eq!(json["finish_reason"], "length");
+        assert_eq!(json["tokens"][0]["id"], 612);
+        assert_eq!(json["tokens"][0]["logprob"], -0.5);
     }
 }
@@ -0,0 +1,179 @@
+use crate::batch::Batch;
+use crate::config::ModelConfig;
+use crate::convert::TensorLoader;
+use crate::error::{GenerateError, LoadError};
+use crate::float::MyFloat;
+use crate::gpt2::{ContextOverflow, GPT};
+use crate::llama::Llama;
+use crate::neox::GPTNeoX;
+use crate::nn::cache::KVCache;
+use crate::sampling::{Greedy, Sampler};
+use ndarray::{Array, ArrayView, Axis, Ix1, Ix2, Ix3};
+
+// what the generation, the evaluation and the binaries need from a decoder-only model. An
+// architecture only implements the batched forward pass, the rest is built on top of it.
+pub trait CausalLM<T>: Send + Sync
+where
+    T: MyFloat,
+{
+    // ids (batch, seq) are the tokens that are not in the cache yet, mask (batch, cached + seq)
+    // tells which of the cached and new tokens are padding. Returns (batch, seq, vocab).
+    fn forward_batch_with_cache(
+        &self,
+        ids: &ArrayView<usize, Ix2>,
+        mask: &ArrayView<bool, Ix2>,
+        cache: &mut KVCache<T>,
+    ) -> Array<T, Ix3>;
+
+    fn new_cache(&self) -> KVCache<T>;
+
+    fn config(&self) -> ModelConfig;
+
+    // number of positions the model can attend to
+    fn n_positions(&self) -> usize;
+
+    fn vocab_size(&self) -> usize;
+
+    // the tokens that end a generation, from eos_token_id of the config
+    fn eos_tokens(&self) -> Vec<usize> {
+        self.config().eos_tokens().to_vec()
+    }
+
+    fn forward(&self, indices: &[usize]) -> Array<T, Ix2> {
+        self.forward_with_cache(indices, &mut self.new_cache())
+    }
+
+    fn forward_with_cache(&self, indices: &[usize], cache: &mut KVCache<T>) -> Array<T, Ix2> {
+        // indices are the tokens that are not in the cache yet, their positions start after the cached ones
+        let ids = ArrayView::from_shape((1, indices.len()), indices).unwrap();
+        let mask = Array::from_elem((1, cache.len() + indices.len()), true);
+        self.forward_batch_with_cache(&ids, &mask.view(), cache)
+            .index_axis_move(Axis(0), 0)
+    }
+
+    fn forward_batch(&self, batch: &Batch) -> Array<T, Ix3> {
+        self.forward_batch_with_cache(&batch.ids(), &batch.mask(), &mut self.new_cache())
+    }
+
+    // logits of the token following indices, the cache is updated with all of them
+    fn last_logits(&self, indices: &[usize], cache: &mut KVCache<T>) -> Array<T, Ix1> {
+        let output = self.forward_with_cache(indices, cache);
+        let last = output.nrows() - 1;
+        output.index_axis_move(Axis(0), last)
+    }
+
+    fn generate(&self, indices: &[usize]) -> usize {
+        self.generate_with_cache(indices, &mut self.new_cache(), &mut Greedy)
+    }
+
+    fn generate_with_cache(
+        &self,
+        indices: &[usize],
+        cache: &mut KVCache<T>,
+        sampler: &mut dyn Sampler<T>,
+    ) -> usize {
+        let logits = self.last_logits(indices, cache);
+        sampler.sample(&logits.view())
+    }
+
+    fn generate_next(
+        &self,
+        ids: &[usize],
+        cache: &mut KVCache<T>,
+        sampler: &mut dyn Sampler<T>,
+        overflow: ContextOverflow,
+    ) -> Result<usize, GenerateError> {
+        let logits = self.next_logits(ids, cache, overflow)?;
+        Ok(sampler.sample(&logits.view()))
+    }
+
+    fn next_logits(
+        &self,
+        ids: &[usize],
+        cache: &mut KVCache<T>,
+        overflow: ContextOverflow,
+    ) -> Result<Array<T, Ix1>, GenerateError> {
+        // ids are all the tokens so far, prompt included, the cache holds the beginning of them
+        let start = self.context_start(ids.len(), cache, overflow)?;
+        if start != cache.start() {
+            cache.reset(start);
+        }
+
+        Ok(self.last_logits(&ids[start + cache.len()..], cache))
+    }
+
+    // position of the first token the model looks at to predict the token following `len` tokens
+    fn context_start(
+        &self,
+        len: usize,
+        cache: &KVCache<T>,
+        overflow: ContextOverflow,
+    ) -> Result<usize, GenerateError> {
+        if len == 0 {
+            return Err(GenerateError::EmptyPrompt);
+        }
+        let max = self.n_positions();
+
+        let start = match overflow {
+            ContextOverflow::Error => cache.start(),
+            ContextOverflow::TruncateLeft if cache.is_empty() => len.saturating_sub(max),
+            ContextOverflow::TruncateLeft => cache.start(),
+            ContextOverflow::SlidingWindow => len.saturating_sub(max),
+        };
+        if len - start > max {
+            return Err(GenerateError::ContextOverflow {
+                len: len - start,
+                max,
+            });
+        }
+        Ok(start)
+    }
+}
+
+// a model chosen at runtime can be used wherever a model is expected
+impl<T, M> CausalLM<T> for Box<M>
+where
+    T: MyFloat,
+    M: CausalLM<T> + ?Sized,
+{
+    fn forward_batch_with_cache(
+        &self,
+        ids: &ArrayView<usize, Ix2>,
+        mask: &ArrayView<bool, Ix2>,
+        cache: &mut KVCache<T>,
+    ) -> Array<T, Ix3> {
+        (**self).forward_batch_with_cache(ids, mask, cache)
+    }
+
+    fn new_cache(&self) -> KVCache<T> {
+        (**self).new_cache()
+    }
+
+    fn config(&self) -> ModelConfig {
+        (**self).config()
+    }
+
+    fn n_positions(&self) -> usize {
+        (**self).n_positions()
+    }
+
+    fn vocab_size(&self) -> usize {
+        (**self).vocab_size()
+    }
+}
+
+// builds the architecture named by the model_type of the config
+pub fn load_from_safe_tensors<T: MyFloat, L: TensorLoader>(
+    tensors: &L,
+    config: &ModelConfig,
+) -> Result<Box<dyn CausalLM<T>>, LoadError> {
+    Ok(match config {
+        ModelConfig::Gpt2(config) => Box::new(GPT::<T>::load_from_safe_tensors(tensors, config)?),
+        ModelConfig::Llama(config) => {
+            Box::new(Llama::<T>::load_from_safe_tensors(tensors, config)?)
+        }
+        ModelConfig::NeoX(config) => {
+            Box::new(GPTNeoX::<T>::load_from_safe_tensors(tensors, config)?)
+        }
+    })
+}
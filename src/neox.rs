@@ -0,0 +1,385 @@
+use crate::batch::position_ids;
+use crate::config::{ModelConfig, NeoXConfig};
+use crate::convert::TensorLoader;
+use crate::error::LoadError;
+use crate::float::MyFloat;
+use crate::model::CausalLM;
+use crate::nn::block::Block;
+use crate::nn::cache::KVCache;
+use crate::nn::head::CausalHead;
+use crate::nn::layer_norm::LayerNorm;
+use crate::nn::linear::{Linear, LinearNoBias};
+use crate::nn::rope::Rope;
+use crate::nn::weight::Weight;
+use ndarray::{Array, ArrayView, Axis, Ix1, Ix2, Ix3};
+use std::sync::Arc;
+
+// GPT-NeoX, the architecture of the Pythia models. The blocks are the GPT-2 ones with rotary
+// embeddings on part of every head instead of learned positions, and by default a parallel residual.
+pub struct GPTNeoX<T>
+where
+    T: MyFloat,
+{
+    embed_in: Weight<T, Ix2>,
+    blocks: Vec<Block<T>>,
+    final_layer_norm: LayerNorm<T>,
+    embed_out: LinearNoBias<T>,
+    config: NeoXConfig,
+}
+
+impl<T> GPTNeoX<T>
+where
+    T: MyFloat,
+{
+    pub fn new(
+        config: &NeoXConfig,
+        embed_in: impl Into<Weight<T, Ix2>>,
+        blocks: Vec<Block<T>>,
+        final_layer_norm: LayerNorm<T>,
+        embed_out: LinearNoBias<T>,
+    ) -> GPTNeoX<T> {
+        GPTNeoX {
+            embed_in: embed_in.into(),
+            blocks,
+            final_layer_norm,
+            embed_out,
+            config: config.clone(),
+        }
+    }
+
+    pub fn num_layers(&self) -> usize {
+        self.blocks.len()
+    }
+
+    // the Hugging Face linear layers are stored (out, in) while Linear computes x @ (in, out), so
+    // the weights are transposed and can't stay in the mapped file
+    fn load_linear<L: TensorLoader>(
+        tensors: &L,
+        name: &str,
+        dim_in: usize,
+        dim_out: usize,
+    ) -> Result<Linear<T>, LoadError> {
+        let weight = tensors.load::<T, Ix2>(&format!("{}.weight", name), &[dim_out, dim_in])?;
+        let bias = tensors.load::<T, Ix1>(&format!("{}.bias", name), &[dim_out])?;
+        let weight = weight.view().t().as_standard_layout().into_owned();
+        Ok(Linear::new(weight, bias))
+    }
+
+    // the fused projection interleaves the heads, [q_0, k_0, v_0, q_1, k_1, v_1, ...], while
+    // CausalHead expects all the queries, then all the keys, then all the values
+    fn load_qkv<L: TensorLoader>(
+        tensors: &L,
+        name: &str,
+        config: &NeoXConfig,
+    ) -> Result<Linear<T>, LoadError> {
+        let (embed_dim, head_dim) = (config.hidden_size, config.head_dim());
+        let weight =
+            tensors.load::<T, Ix2>(&format!("{}.weight", name), &[3 * embed_dim, embed_dim])?;
+        let bias = tensors.load::<T, Ix1>(&format!("{}.bias", name), &[3 * embed_dim])?;
+
+        // column c of the output is q, k or v (c / embed_dim) of the head and dimension in c % embed_dim
+        let source = |c: usize| {
+            let (kind, rest) = (c / embed_dim, c % embed_dim);
+            let (head, dim) = (rest / head_dim, rest % head_dim);
+            head * 3 * head_dim + kind * head_dim + dim
+        };
+        let (weight, bias) = (weight.view(), bias.view());
+        let qkv_weight =
+            Array::from_shape_fn((embed_dim, 3 * embed_dim), |(i, c)| weight[[source(c), i]]);
+        let qkv_bias = Array::from_shape_fn(3 * embed_dim, |c| bias[source(c)]);
+        Ok(Linear::new(qkv_weight, qkv_bias))
+    }
+
+    fn load_layer_norm<L: TensorLoader>(
+        tensors: &L,
+        name: &str,
+        config: &NeoXConfig,
+    ) -> Result<LayerNorm<T>, LoadError> {
+        let dim = config.hidden_size;
+        let weight = tensors.load::<T, Ix1>(&format!("{}.weight", name), &[dim])?;
+        let bias = tensors.load::<T, Ix1>(&format!("{}.bias", name), &[dim])?;
+        Ok(LayerNorm::new_with_eps(
+            weight,
+            bias,
+            T::from(config.layer_norm_eps).unwrap(),
+        ))
+    }
+
+    pub fn load_block<L: TensorLoader>(
+        tensors: &L,
+        index: usize,
+        config: &NeoXConfig,
+        rope: &Arc<Rope<T>>,
+    ) -> Result<Block<T>, LoadError> {
+        let prefix = format!("gpt_neox.layers.{}", index);
+        let name = |name: &str| format!("{}.{}", prefix, name);
+        let (embed_dim, inner_dim) = (config.hidden_size, config.intermediate_size);
+
+        let qkv = GPTNeoX::<T>::load_qkv(tensors, &name("attention.query_key_value"), config)?;
+        let dense =
+            GPTNeoX::<T>::load_linear(tensors, &name("attention.dense"), embed_dim, embed_dim)?;
+        let head = CausalHead::new_with_rope(qkv, dense, config.num_attention_heads, rope.clone());
+
+        let ln_1 = GPTNeoX::<T>::load_layer_norm(tensors, &name("input_layernorm"), config)?;
+        let ln_2 =
+            GPTNeoX::<T>::load_layer_norm(tensors, &name("post_attention_layernorm"), config)?;
+        let fc =
+            GPTNeoX::<T>::load_linear(tensors, &name("mlp.dense_h_to_4h"), embed_dim, inner_dim)?;
+        let proj =
+            GPTNeoX::<T>::load_linear(tensors, &name("mlp.dense_4h_to_h"), inner_dim, embed_dim)?;
+
+        Ok(if config.use_parallel_residual {
+            Block::new_parallel(ln_1, head, ln_2, fc, proj, config.hidden_act)
+        } else {
+            Block::new_with_activation(ln_1, head, ln_2, fc, proj, config.hidden_act)
+        })
+    }
+
+    pub fn load_from_safe_tensors<L: TensorLoader>(
+        tensors: &L,
+        config: &NeoXConfig,
+    ) -> Result<GPTNeoX<T>, LoadError> {
+        config.validate()?;
+
+        let embed_shape = [config.vocab_size, config.hidden_size];
+        let embed_in = tensors.load::<T, Ix2>("gpt_neox.embed_in.weight", &embed_shape)?;
+
+        let rope = Arc::new(Rope::new(
+            config.rotary_dim(),
+            config.max_position_embeddings,
+            config.rotary_emb_base,
+        ));
+        let blocks = (0..config.num_hidden_layers)
+            .map(|i| GPTNeoX::<T>::load_block(tensors, i, config, &rope))
+            .collect::<Result<Vec<Block<T>>, LoadError>>()?;
+
+        let final_layer_norm =
+            GPTNeoX::<T>::load_layer_norm(tensors, "gpt_neox.final_layer_norm", config)?;
+
+        let embed_out_name = if config.tie_word_embeddings {
+            "gpt_neox.embed_in.weight"
+        } else {
+            "embed_out.weight"
+        };
+        let embed_out = LinearNoBias::new(tensors.load::<T, Ix2>(embed_out_name, &embed_shape)?);
+
+        Ok(GPTNeoX::new(
+            config,
+            embed_in,
+            blocks,
+            final_layer_norm,
+            embed_out,
+        ))
+    }
+}
+
+impl<T> CausalLM<T> for GPTNeoX<T>
+where
+    T: MyFloat,
+{
+    fn forward_batch_with_cache(
+        &self,
+        ids: &ArrayView<usize, Ix2>,
+        mask: &ArrayView<bool, Ix2>,
+        cache: &mut KVCache<T>,
+    ) -> Array<T, Ix3> {
+        let (batch, seq_len) = ids.dim();
+        assert_eq!(mask.shape(), &[batch, cache.len() + seq_len]);
+
+        // the positions only enter through the rotary embeddings of the attention
+        let max_position = position_ids(mask).iter().copied().max().unwrap_or(0);
+        assert!(
+            max_position < self.n_positions(),
+            "{} tokens don't fit in the context of {} positions",
+            max_position + 1,
+            self.n_positions()
+        );
+
+        let flat_ids: Vec<usize> = ids.iter().copied().collect();
+        let mut output = self.embed_in.view().select(Axis(0), &flat_ids);
+        for (block, layer_cache) in self.blocks.iter().zip(cache.layers_mut()) {
+            output = block.forward_batch_with_cache(&output, mask, layer_cache);
+        }
+
+        let output = self
+            .embed_out
+            .forward(&self.final_layer_norm.forward(&output));
+        let vocab_size = output.shape()[1];
+        output.into_shape((batch, seq_len, vocab_size)).unwrap()
+    }
+
+    fn new_cache(&self) -> KVCache<T> {
+        KVCache::new(self.blocks.len())
+    }
+
+    fn config(&self) -> ModelConfig {
+        ModelConfig::NeoX(self.config.clone())
+    }
+
+    fn n_positions(&self) -> usize {
+        self.config.max_position_embeddings
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.config.vocab_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batch::Batch;
+    use crate::testing;
+    use ndarray::s;
+    use safetensors::SafeTensors;
+
+    fn tiny_config() -> NeoXConfig {
+        NeoXConfig::from_json(
+            r#"{
+                "hidden_size": 16,
+                "intermediate_size": 32,
+                "num_hidden_layers": 2,
+                "num_attention_heads": 4,
+                "max_position_embeddings": 32,
+                "rotary_pct": 0.5,
+                "vocab_size": 12
+            }"#,
+        )
+        .unwrap()
+    }
+
+    // a tiny random checkpoint with the Hugging Face names, in the order of
+    // scripts/reference_logits.py that computed the reference logits
+    fn tiny_checkpoint() -> Vec<u8> {
+        let (h, i, v) = (16, 32, 12);
+        let mut shapes = vec![("gpt_neox.embed_in.weight".to_string(), vec![v, h])];
+        for l in 0..2 {
+            let layer =
+                |name: &str, shape: Vec<usize>| (format!("gpt_neox.layers.{}.{}", l, name), shape);
+            shapes.extend([
+                layer("input_layernorm.weight", vec![h]),
+                layer("input_layernorm.bias", vec![h]),
+                layer("post_attention_layernorm.weight", vec![h]),
+                layer("post_attention_layernorm.bias", vec![h]),
+                layer("attention.query_key_value.weight", vec![3 * h, h]),
+                layer("attention.query_key_value.bias", vec![3 * h]),
+                layer("attention.dense.weight", vec![h, h]),
+                layer("attention.dense.bias", vec![h]),
+                layer("mlp.dense_h_to_4h.weight", vec![i, h]),
+                layer("mlp.dense_h_to_4h.bias", vec![i]),
+                layer("mlp.dense_4h_to_h.weight", vec![h, i]),
+                layer("mlp.dense_4h_to_h.bias", vec![h]),
+            ]);
+        }
+        shapes.push(("gpt_neox.final_layer_norm.weight".to_string(), vec![h]));
+        shapes.push(("gpt_neox.final_layer_norm.bias".to_string(), vec![h]));
+        shapes.push(("embed_out.weight".to_string(), vec![v, h]));
+
+        // layer norm weights around 1, biases small, larger embeddings and output layer
+        testing::tiny_checkpoint(&shapes, |name, x| match name {
+            _ if name.ends_with("norm.weight") => x + 1.0,
+            "gpt_neox.embed_in.weight" => x * 5.0,
+            "embed_out.weight" => x * 10.0,
+            _ if name.ends_with("bias") => x * 0.1,
+            _ => x,
+        })
+    }
+
+    fn assert_close(a: &ArrayView<f32, Ix1>, b: &ArrayView<f32, Ix1>) {
+        let max_diff = (a - b).mapv(f32::abs).fold(0.0, |acc: f32, &x| acc.max(x));
+        assert!(max_diff < 1e-4, "max diff {}", max_diff);
+    }
+
+    #[test]
+    fn test_reference_logits() {
+        let buffer = tiny_checkpoint();
+        let tensors = SafeTensors::deserialize(&buffer).unwrap();
+        let neox = GPTNeoX::<f32>::load_from_safe_tensors(&tensors, &tiny_config()).unwrap();
+
+        // python3 scripts/reference_logits.py neox
+        let expected = ndarray::array![
+            [
+                -0.584704, -0.054348, 0.535048, 0.543198, -0.0387528, -0.578605, -0.489892,
+                0.131012, 0.609592, 0.425945, -0.220425, -0.627338
+            ],
+            [
+                -0.602235, -0.134404, 0.479436, 0.572443, 0.0435796, -0.532626, -0.530217,
+                0.0481912, 0.574247, 0.476472, -0.138915, -0.603393
+            ],
+            [
+                -0.647395, -0.339498, 0.337211, 0.647592, 0.254465, -0.415099, -0.633723,
+                -0.163905, 0.48397, 0.606087, 0.0697842, -0.542328
+            ],
+            [
+                -0.614492,
+                -0.189234,
+                0.441597,
+                0.592702,
+                0.0999281,
+                -0.501402,
+                -0.558036,
+                -0.00845125,
+                0.550315,
+                0.511249,
+                -0.0832092,
+                -0.587274
+            ],
+            [
+                -0.582951, -0.0459726, 0.540948, 0.540213, -0.0473798, -0.583502, -0.485739,
+                0.139703, 0.61338, 0.420715, -0.228992, -0.629934
+            ],
+        ];
+        let logits = neox.forward(&[1, 5, 9, 2, 7]);
+        assert_eq!(logits.shape(), &[5, 12]);
+        for i in 0..5 {
+            assert_close(&logits.row(i), &expected.row(i));
+        }
+    }
+
+    #[test]
+    fn test_sequential_residual() {
+        let buffer = tiny_checkpoint();
+        let tensors = SafeTensors::deserialize(&buffer).unwrap();
+        let config = NeoXConfig {
+            use_parallel_residual: false,
+            ..tiny_config()
+        };
+        let neox = GPTNeoX::<f32>::load_from_safe_tensors(&tensors, &config).unwrap();
+
+        // python3 scripts/reference_logits.py neox-sequential
+        let expected = ndarray::array![
+            -0.651813, -0.359758, 0.323118, 0.654976, 0.275305, -0.403443, -0.643912, -0.184871,
+            0.475004, 0.618861, 0.0904217, -0.536246
+        ];
+        let logits = neox.forward(&[1, 5, 9, 2, 7]);
+        assert_close(&logits.row(2), &expected.view());
+    }
+
+    #[test]
+    fn test_cache_and_batch_match_full_forward() {
+        let buffer = tiny_checkpoint();
+        let tensors = SafeTensors::deserialize(&buffer).unwrap();
+        let neox = GPTNeoX::<f32>::load_from_safe_tensors(&tensors, &tiny_config()).unwrap();
+
+        let ids = vec![3, 1, 4, 1, 5, 9, 2];
+        let full = neox.forward(&ids);
+
+        let mut cache = neox.new_cache();
+        neox.forward_with_cache(&ids[..4], &mut cache);
+        for i in 4..ids.len() {
+            let step = neox.forward_with_cache(&ids[i..i + 1], &mut cache);
+            assert_close(&step.row(0), &full.row(i));
+        }
+
+        let batch = Batch::new(&[ids.clone(), vec![8, 6]]);
+        let logits = neox.forward_batch(&batch);
+        let short = neox.forward(&[8, 6]);
+        assert_close(&logits.slice(s![0, 6, ..]), &full.row(6));
+        assert_close(&logits.slice(s![1, 6, ..]), &short.row(1));
+
+        let config = ModelConfig::NeoX(tiny_config());
+        let model = crate::model::load_from_safe_tensors::<f32, _>(&tensors, &config).unwrap();
+        assert_eq!(model.config().model_type(), "gpt_neox");
+        assert_eq!(model.generate(&ids), neox.generate(&ids));
+    }
+}
@@ -1,10 +1,13 @@
 use crate::float::MyFloat;
+use crate::nn::cache::LayerCache;
 use crate::nn::head::CausalHead;
+use crate::nn::hooks::{Hook, HookPoint, Hooks};
 use crate::nn::layer_norm::LayerNorm;
 use crate::nn::linear::Linear;
 // use crate::time_it;
 
-use ndarray::{Array, Ix2};
+use ndarray::{Array, ArrayView, Ix2};
+use serde::Deserialize;
 use std::f32::consts::PI;
 
 pub fn new_gelu_inplace<'a, T: MyFloat>(x: &'a mut Array<T, Ix2>) {
@@ -29,6 +32,52 @@ pub fn new_gelu_par_inplace<'a, T: MyFloat>(x: &'a mut Array<T, Ix2>) {
     });
 }
 
+pub fn gelu_par_inplace<T: MyFloat>(x: &mut Array<T, Ix2>) {
+    // exact gelu, 0.5 * x * (1 + erf(x / sqrt(2)))
+    x.par_mapv_inplace(|v| {
+        T::from(0.5).unwrap() * v * (T::from(1.0).unwrap() + erf(v / T::from(2.0).unwrap().sqrt()))
+    });
+}
+
+fn erf<T: MyFloat>(x: T) -> T {
+    // Abramowitz and Stegun 7.1.26, max error 1.5e-7
+    let one = T::from(1.0).unwrap();
+    let sign = x.signum();
+    let x = x.abs();
+    let t = one / (one + T::from(0.3275911).unwrap() * x);
+    let poly = [1.0614054, -1.4531521, 1.4214138, -0.28449672, 0.2548296]
+        .iter()
+        .fold(T::from(0.0).unwrap(), |acc, &c| {
+            (acc + T::from(c).unwrap()) * t
+        });
+    sign * (one - poly * (-x * x).exp())
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+pub enum Activation {
+    #[default]
+    #[serde(rename = "gelu_new", alias = "gelu_fast", alias = "gelu_pytorch_tanh")]
+    GeluNew,
+    #[serde(rename = "gelu")]
+    Gelu,
+    #[serde(rename = "relu")]
+    Relu,
+    #[serde(rename = "silu")]
+    Silu,
+}
+
+impl Activation {
+    pub fn apply_inplace<T: MyFloat>(&self, x: &mut Array<T, Ix2>) {
+        match self {
+            Activation::GeluNew => new_gelu_par_inplace(x),
+            Activation::Gelu => gelu_par_inplace(x),
+            Activation::Relu => x.par_mapv_inplace(|v| v.max(T::from(0.0).unwrap())),
+            // x * sigmoid(x)
+            Activation::Silu => x.par_mapv_inplace(|v| v / (T::from(1.0).unwrap() + (-v).exp())),
+        }
+    }
+}
+
 pub struct Block<T>
 where
     T: MyFloat,
@@ -38,6 +87,10 @@ where
     ln_2: LayerNorm<T>,
     fc: Linear<T>,
     proj: Linear<T>,
+    activation: Activation,
+    // the attention and the MLP both read the input of the block, as in GPT-J and GPT-NeoX
+    parallel_residual: bool,
+    hooks: Hooks<T>,
 }
 
 impl<T> Block<T>
@@ -45,32 +98,55 @@ where
     T: MyFloat,
 {
     pub fn forward(&self, x: &Array<T, Ix2>) -> Array<T, Ix2> {
+        self.forward_with_cache(x, &mut LayerCache::new())
+    }
+
+    pub fn forward_with_cache(
+        &self,
+        x: &Array<T, Ix2>,
+        cache: &mut LayerCache<T>,
+    ) -> Array<T, Ix2> {
+        let mask = Array::from_elem((1, cache.len() + x.shape()[0]), true);
+        self.forward_batch_with_cache(x, &mask.view(), cache)
+    }
+
+    pub fn forward_batch_with_cache(
+        &self,
+        x: &Array<T, Ix2>,
+        mask: &ArrayView<bool, Ix2>,
+        cache: &mut LayerCache<T>,
+    ) -> Array<T, Ix2> {
+        // x is (batch * seq, embed), only the attention needs to know where the sequences start
+        let batch = mask.shape()[0];
+        let (rows, embed_dim) = x.dim();
+
         // time_it!("ln_1", let y = self.ln_1.forward(x));
         let y = self.ln_1.forward(x);
 
         //time_it!("attn", let y = self.head.attention(&y));
-        let y = self.head.attention(&y);
-        let x = x + y;
-        let x_skip = &x;
-        //time_it!("ln_2", let x = self.ln_2.forward(&x));
-        let x = self.ln_2.forward(&x);
-
-        // let mlp = |x| { let mut x = self.fc.forward(&x);
-        //     new_gelu_inplace(&mut x);
-        //     let x = self.proj.forward(&x);
-        //     x
-        // };
-        //
-        // time_it!("mlp", let x = mlp(x));
-
-        let mut x = self.fc.forward(&x);
-        new_gelu_par_inplace(&mut x);
-        let x = self.proj.forward(&x);
-
-        let x = x_skip + x;
+        let y = y.into_shape((batch, rows / batch, embed_dim)).unwrap();
+        let y = self.head.attention_batch_with_cache(&y.view(), mask, cache);
+        let y = y.into_shape((rows, embed_dim)).unwrap();
+
+        let mut x = if self.parallel_residual {
+            x + &y + self.mlp(&self.ln_2.forward(x))
+        } else {
+            let x = x + y;
+            //time_it!("ln_2", let x = self.ln_2.forward(&x));
+            let mlp = self.mlp(&self.ln_2.forward(&x));
+            x + mlp
+        };
+        self.hooks.run(HookPoint::BlockOutput, &mut x);
         x
     }
 
+    fn mlp(&self, x: &Array<T, Ix2>) -> Array<T, Ix2> {
+        let mut x = self.fc.forward(x);
+        self.activation.apply_inplace(&mut x);
+        self.hooks.run(HookPoint::MlpActivation, &mut x);
+        self.proj.forward(&x)
+    }
+
     pub fn new_zeros(embed_dim: usize) -> Block<T> {
         let ln_1 = LayerNorm::<T>::new_zeros(embed_dim);
         let head = CausalHead::<T>::new_zeros(embed_dim);
@@ -86,6 +162,17 @@ where
         ln_2: LayerNorm<T>,
         fc: Linear<T>,
         proj: Linear<T>,
+    ) -> Block<T> {
+        Block::new_with_activation(ln_1, head, ln_2, fc, proj, Activation::GeluNew)
+    }
+
+    pub fn new_with_activation(
+        ln_1: LayerNorm<T>,
+        head: CausalHead<T>,
+        ln_2: LayerNorm<T>,
+        fc: Linear<T>,
+        proj: Linear<T>,
+        activation: Activation,
     ) -> Block<T> {
         Block::<T> {
             ln_1,
@@ -93,8 +180,51 @@ where
             ln_2,
             fc,
             proj,
+            activation,
+            parallel_residual: false,
+            hooks: Hooks::new(),
         }
     }
+
+    pub fn new_parallel(
+        ln_1: LayerNorm<T>,
+        head: CausalHead<T>,
+        ln_2: LayerNorm<T>,
+        fc: Linear<T>,
+        proj: Linear<T>,
+        activation: Activation,
+    ) -> Block<T> {
+        Block {
+            parallel_residual: true,
+            ..Block::new_with_activation(ln_1, head, ln_2, fc, proj, activation)
+        }
+    }
+
+    pub fn num_head(&self) -> usize {
+        self.head.num_head()
+    }
+
+    pub fn inner_dim(&self) -> usize {
+        self.fc.dim_out()
+    }
+
+    pub fn activation(&self) -> Activation {
+        self.activation
+    }
+
+    pub fn register_hook(&mut self, point: HookPoint, hook: Hook<T>) {
+        match point {
+            HookPoint::Query | HookPoint::Key | HookPoint::Value | HookPoint::AttentionProbs => {
+                self.head.register_hook(point, hook)
+            }
+            HookPoint::MlpActivation | HookPoint::BlockOutput => self.hooks.register(point, hook),
+        }
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.head.clear_hooks();
+        self.hooks.clear();
+    }
 }
 
 #[cfg(test)]
@@ -113,4 +243,32 @@ mod tests {
 
         block.forward(&embed);
     }
+
+    #[test]
+    fn test_gelu() {
+        let mut x = array![[-3.0, -1.0, 0.0, 0.5, 2.0]];
+        gelu_par_inplace(&mut x);
+
+        // torch.nn.functional.gelu
+        let expected = array![[-0.0040497, -0.1586553, 0.0, 0.3457312, 1.9544997]];
+        assert!((x - expected).mapv(f32::abs).iter().all(|&d| d < 1e-6));
+    }
+
+    #[test]
+    fn test_activation_names() {
+        let activation: Activation = serde_json::from_str("\"gelu_pytorch_tanh\"").unwrap();
+        assert_eq!(activation, Activation::GeluNew);
+        let activation: Activation = serde_json::from_str("\"silu\"").unwrap();
+        assert_eq!(activation, Activation::Silu);
+    }
+
+    #[test]
+    fn test_silu() {
+        let mut x = array![[-2.0f32, 0.0, 1.0]];
+        Activation::Silu.apply_inplace(&mut x);
+
+        // torch.nn.functional.silu
+        let expected = array![[-0.2384058, 0.0, 0.7310586]];
+        assert!((x - expected).mapv(f32::abs).iter().all(|&d| d < 1e-6));
+    }
 }
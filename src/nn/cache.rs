@@ -0,0 +1,212 @@
+use crate::float::MyFloat;
+use ndarray::{s, Array, ArrayView, Axis, Ix3};
+
+pub struct LayerCache<T>
+where
+    T: MyFloat,
+{
+    // the buffers have room for more positions than are cached, only the first len are used
+    k: Option<Array<T, Ix3>>, // (batch * num_kv_head, capacity, head_dim)
+    v: Option<Array<T, Ix3>>, // (batch * num_kv_head, capacity, head_dim)
+    len: usize,
+}
+
+impl<T> LayerCache<T>
+where
+    T: MyFloat,
+{
+    pub fn new() -> LayerCache<T> {
+        LayerCache {
+            k: None,
+            v: None,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn append(&mut self, k: &ArrayView<T, Ix3>, v: &ArrayView<T, Ix3>) {
+        // new keys and values are written after the cached ones. The buffers double when they are
+        // full, so a generation only copies the cache a logarithmic number of times
+        let (start, end) = (self.len, self.len + k.shape()[1]);
+        for (buffer, new) in [(&mut self.k, k.view()), (&mut self.v, v.view())] {
+            let buffer = reserve(buffer, start, end, &new);
+            buffer.slice_mut(s![.., start..end, ..]).assign(&new);
+        }
+        self.len = end;
+    }
+
+    // keeps the sequences at the given indices of the batch, in this order. An index can be
+    // repeated, this is how the beams of a beam search share the cache of their common prefix
+    pub fn select(&mut self, indices: &[usize], batch: usize) {
+        let select = |x: Array<T, Ix3>| {
+            let num_head = x.shape()[0] / batch;
+            let rows: Vec<usize> = indices
+                .iter()
+                .flat_map(|&i| i * num_head..(i + 1) * num_head)
+                .collect();
+            x.select(Axis(0), &rows)
+        };
+        self.k = self.k.take().map(select);
+        self.v = self.v.take().map(select);
+    }
+
+    pub fn keys(&self) -> ArrayView<'_, T, Ix3> {
+        let k = self.k.as_ref().expect("empty cache");
+        k.slice(s![.., ..self.len, ..])
+    }
+
+    pub fn values(&self) -> ArrayView<'_, T, Ix3> {
+        let v = self.v.as_ref().expect("empty cache");
+        v.slice(s![.., ..self.len, ..])
+    }
+}
+
+// a buffer with room for end positions that keeps the first start ones
+fn reserve<'a, T: MyFloat>(
+    buffer: &'a mut Option<Array<T, Ix3>>,
+    start: usize,
+    end: usize,
+    new: &ArrayView<T, Ix3>,
+) -> &'a mut Array<T, Ix3> {
+    let capacity = buffer.as_ref().map_or(0, |buffer| buffer.shape()[1]);
+    if end > capacity {
+        let (rows, _, head_dim) = new.dim();
+        let mut grown = Array::zeros((rows, end.max(2 * capacity), head_dim));
+        if let Some(old) = buffer.take() {
+            grown
+                .slice_mut(s![.., ..start, ..])
+                .assign(&old.slice(s![.., ..start, ..]));
+        }
+        *buffer = Some(grown);
+    }
+    buffer.as_mut().unwrap()
+}
+
+impl<T> Default for LayerCache<T>
+where
+    T: MyFloat,
+{
+    fn default() -> Self {
+        LayerCache::new()
+    }
+}
+
+pub struct KVCache<T>
+where
+    T: MyFloat,
+{
+    layers: Vec<LayerCache<T>>,
+    start: usize, // position in the sequence of the first cached token
+}
+
+impl<T> KVCache<T>
+where
+    T: MyFloat,
+{
+    pub fn new(num_layer: usize) -> KVCache<T> {
+        KVCache {
+            layers: (0..num_layer).map(|_| LayerCache::new()).collect(),
+            start: 0,
+        }
+    }
+
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn reset(&mut self, start: usize) {
+        // the cached keys and values depend on the positions, they can't be shifted and are dropped
+        self.layers
+            .iter_mut()
+            .for_each(|layer| *layer = LayerCache::new());
+        self.start = start;
+    }
+
+    pub fn len(&self) -> usize {
+        // every layer sees the same tokens, so the first one tells the number of cached positions
+        self.layers.first().map_or(0, |layer| layer.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn select(&mut self, indices: &[usize], batch: usize) {
+        self.layers
+            .iter_mut()
+            .for_each(|layer| layer.select(indices, batch));
+    }
+
+    pub fn layers_mut(&mut self) -> std::slice::IterMut<'_, LayerCache<T>> {
+        self.layers.iter_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append() {
+        let mut cache = LayerCache::<f32>::new();
+        assert!(cache.is_empty());
+
+        let k = Array::<f32, _>::ones((2, 3, 4));
+        let v = Array::<f32, _>::zeros((2, 3, 4));
+        cache.append(&k.view(), &v.view());
+
+        let k = Array::<f32, _>::ones((2, 1, 4));
+        let v = Array::<f32, _>::zeros((2, 1, 4));
+        cache.append(&k.view(), &v.view());
+
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.keys().shape(), &[2, 4, 4]);
+        assert_eq!(cache.values().shape(), &[2, 4, 4]);
+    }
+
+    #[test]
+    fn test_append_in_place() {
+        let mut cache = LayerCache::<f32>::new();
+        let token =
+            |t: usize| Array::from_shape_fn((2, 1, 3), |(i, _, j)| (t * 10 + i * 3 + j) as f32);
+
+        cache.append(&token(0).view(), &token(0).view());
+        cache.append(&token(1).view(), &token(1).view());
+        // the buffer has room for 4 positions, the next tokens don't move it
+        cache.append(&token(2).view(), &token(2).view());
+        let buffer = cache.keys().as_ptr();
+        cache.append(&token(3).view(), &token(3).view());
+        assert_eq!(cache.keys().as_ptr(), buffer);
+
+        let tokens: Vec<_> = (0..4).map(token).collect();
+        let views: Vec<_> = tokens.iter().map(|x| x.view()).collect();
+        let expected = ndarray::concatenate(Axis(1), &views).unwrap();
+        assert_eq!(cache.keys(), expected);
+        assert_eq!(cache.values(), expected);
+
+        cache.append(&token(4).view(), &token(4).view());
+        assert_eq!(cache.keys().shape(), &[2, 5, 3]);
+        assert_eq!(cache.keys().slice(s![.., ..4, ..]), expected);
+    }
+
+    #[test]
+    fn test_select() {
+        // a batch of 2 sequences with 2 heads each
+        let mut cache = LayerCache::<f32>::new();
+        let k = Array::from_shape_fn((4, 3, 2), |(i, _, _)| i as f32);
+        cache.append(&k.view(), &k.view());
+
+        cache.select(&[1, 1, 0], 2);
+        assert_eq!(cache.keys().shape(), &[6, 3, 2]);
+        let rows: Vec<f32> = cache.keys().outer_iter().map(|x| x[[0, 0]]).collect();
+        assert_eq!(rows, vec![2.0, 3.0, 2.0, 3.0, 0.0, 1.0]);
+        assert_eq!(cache.values(), cache.keys());
+    }
+}
@@ -1,16 +1,92 @@
+use crate::batch::position_ids;
 use crate::float::MyFloat;
+use crate::nn::cache::LayerCache;
 use crate::nn::dot::dot_3d_3d_par;
+use crate::nn::hooks::{Hook, HookPoint, Hooks};
 use crate::nn::linear::Linear;
-use crate::nn::utils::{fill_tril_3d, softmax_inplace_3d};
-use ndarray::{Array, ArrayView, Axis, CowArray, Ix2, Ix3, Slice};
+use crate::nn::rope::Rope;
+use crate::nn::utils::{fill_attention_mask_3d, softmax_inplace_3d};
+use ndarray::{s, Array, ArrayView, Axis, CowArray, Ix2, Ix3, Slice};
+use std::sync::Arc;
+
+// the queries, keys and values come from a single Linear whose output is [Q | K | V] (GPT-2,
+// Falcon) or from one Linear each
+pub enum QkvProjection<T>
+where
+    T: MyFloat,
+{
+    Fused(Linear<T>),
+    Split(Box<(Linear<T>, Linear<T>, Linear<T>)>),
+}
+
+impl<T> QkvProjection<T>
+where
+    T: MyFloat,
+{
+    // (rows, embed) -> (rows, num_head * head_dim), (rows, num_kv_head * head_dim) twice
+    fn forward(
+        &self,
+        input: &CowArray<T, Ix2>,
+        q_dim: usize,
+    ) -> (Array<T, Ix2>, Array<T, Ix2>, Array<T, Ix2>) {
+        match self {
+            QkvProjection::Fused(qkv) => {
+                let qkv = qkv.forward_cow(input);
+                let kv_dim = (qkv.shape()[1] - q_dim) / 2;
+                let part = |start: usize, end: usize| {
+                    qkv.slice_axis(Axis(1), Slice::from(start..end)).to_owned()
+                };
+                (
+                    part(0, q_dim),
+                    part(q_dim, q_dim + kv_dim),
+                    part(q_dim + kv_dim, q_dim + 2 * kv_dim),
+                )
+            }
+            QkvProjection::Split(qkv) => {
+                let (q, k, v) = &**qkv;
+                (
+                    q.forward_cow(input),
+                    k.forward_cow(input),
+                    v.forward_cow(input),
+                )
+            }
+        }
+    }
+
+    fn dim_out(&self) -> usize {
+        match self {
+            QkvProjection::Fused(qkv) => qkv.dim_out(),
+            QkvProjection::Split(qkv) => qkv.0.dim_out() + qkv.1.dim_out() + qkv.2.dim_out(),
+        }
+    }
+}
+
+impl<T: MyFloat> From<Linear<T>> for QkvProjection<T> {
+    fn from(qkv: Linear<T>) -> Self {
+        QkvProjection::Fused(qkv)
+    }
+}
+
+impl<T: MyFloat> From<(Linear<T>, Linear<T>, Linear<T>)> for QkvProjection<T> {
+    fn from(qkv: (Linear<T>, Linear<T>, Linear<T>)) -> Self {
+        QkvProjection::Split(Box::new(qkv))
+    }
+}
 
 pub struct CausalHead<T>
 where
     T: MyFloat,
 {
-    qkv: Linear<T>, // Q, K, V at the same time
+    qkv: QkvProjection<T>,
     proj: Linear<T>,
     num_head: usize,
+    // groups of num_head / num_kv_head query heads share a key and value head, which is
+    // grouped-query attention, or multi-query attention with a single one. Only these are cached.
+    num_kv_head: usize,
+    head_dim: usize,
+    // rotary position embeddings, shared by the layers. GPT-2 has learned position embeddings instead
+    rope: Option<Arc<Rope<T>>>,
+    hooks: Hooks<T>,
 }
 
 impl<T> CausalHead<T>
@@ -25,67 +101,197 @@ where
         CausalHead::new(qkv, proj, 2)
     }
     pub fn new(qkv: Linear<T>, proj: Linear<T>, num_head: usize) -> CausalHead<T> {
+        CausalHead::new_grouped(qkv, proj, num_head, num_head, None)
+    }
+
+    pub fn new_with_rope(
+        qkv: Linear<T>,
+        proj: Linear<T>,
+        num_head: usize,
+        rope: Arc<Rope<T>>,
+    ) -> CausalHead<T> {
+        CausalHead::new_grouped(qkv, proj, num_head, num_head, Some(rope))
+    }
+
+    // the projection gives num_head query heads and num_kv_head key and value heads of the same size
+    pub fn new_grouped(
+        qkv: impl Into<QkvProjection<T>>,
+        proj: Linear<T>,
+        num_head: usize,
+        num_kv_head: usize,
+        rope: Option<Arc<Rope<T>>>,
+    ) -> CausalHead<T> {
+        let qkv = qkv.into();
+        assert!(
+            num_kv_head > 0 && num_head.is_multiple_of(num_kv_head),
+            "num_head ({}) must be a multiple of num_kv_head ({})",
+            num_head,
+            num_kv_head
+        );
+        let num_rows = num_head + 2 * num_kv_head;
+        let head_dim = qkv.dim_out() / num_rows;
+        assert_eq!(
+            qkv.dim_out(),
+            num_rows * head_dim,
+            "the projection doesn't split into {} query and {} key and value heads",
+            num_head,
+            num_kv_head
+        );
+        if let QkvProjection::Split(split) = &qkv {
+            let (q, k, v) = &**split;
+            assert_eq!(q.dim_out(), num_head * head_dim);
+            assert_eq!(
+                (k.dim_out(), v.dim_out()),
+                (num_kv_head * head_dim, num_kv_head * head_dim)
+            );
+        }
         CausalHead {
             qkv,
             proj,
             num_head,
+            num_kv_head,
+            head_dim,
+            rope,
+            hooks: Hooks::new(),
         }
     }
 
-    fn reshape_m<'a>(&'a self, m: &'a ArrayView<T, Ix2>) -> CowArray<T, Ix3> {
-        let embed_dim = m.shape()[1];
-        let seq_len = m.shape()[0];
-
-        let mut m = m
-            .to_shape((seq_len, self.num_head, embed_dim / self.num_head))
-            .unwrap();
-
-        m.swap_axes(0, 1);
-        m
+    pub fn num_head(&self) -> usize {
+        self.num_head
     }
 
-    pub fn attention(&self, input: &Array<T, Ix2>) -> Array<T, Ix2> {
-        let embed_dim = input.shape()[1];
-
-        let qkv = self.qkv.forward(&input); // (seq, 3* embed) = (seq, embed) @ (embed, 3* embed)
-
-        let q = qkv.slice_axis(Axis(1), Slice::from(..embed_dim));
-        let k = qkv.slice_axis(Axis(1), Slice::from(embed_dim..2 * embed_dim));
-        let v = qkv.slice_axis(Axis(1), Slice::from(2 * embed_dim..));
-
-        let seq_len = q.shape()[0];
-
-        let q = self.reshape_m(&q);
-        let mut k = self.reshape_m(&k);
+    pub fn num_kv_head(&self) -> usize {
+        self.num_kv_head
+    }
 
-        k.swap_axes(2, 1);
+    pub fn register_hook(&mut self, point: HookPoint, hook: Hook<T>) {
+        self.hooks.register(point, hook);
+    }
 
-        let qk = dot_3d_3d_par(&q.view(), &k.view());
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear();
+    }
 
-        let norm = 1.0 / (k.shape()[1] as f32).sqrt();
+    fn split_heads(&self, m: Array<T, Ix2>, batch: usize, num_head: usize) -> Array<T, Ix3> {
+        // (batch * seq, num_head * head_dim) -> (batch * num_head, seq, head_dim)
+        let seq_len = m.shape()[0] / batch;
 
-        let mut scores = qk * T::from(norm).unwrap();
+        let m = m
+            .into_shape((batch, seq_len, num_head, self.head_dim))
+            .unwrap();
+        let m = m.permuted_axes([0, 2, 1, 3]);
+        m.as_standard_layout()
+            .into_owned()
+            .into_shape((batch * num_head, seq_len, self.head_dim))
+            .unwrap()
+    }
 
-        let mut mask_scores = fill_tril_3d(&mut scores, T::from(-1e9).unwrap());
-        softmax_inplace_3d(&mut mask_scores);
+    pub fn attention(&self, input: &Array<T, Ix2>) -> Array<T, Ix2> {
+        self.attention_with_cache(input, &mut LayerCache::new())
+    }
 
-        let v = self.reshape_m(&v);
+    pub fn attention_with_cache(
+        &self,
+        input: &Array<T, Ix2>,
+        cache: &mut LayerCache<T>,
+    ) -> Array<T, Ix2> {
+        // a single sequence is a batch of one without padding
+        let mask = Array::from_elem((1, cache.len() + input.shape()[0]), true);
+        let input = input.view().insert_axis(Axis(0));
+        self.attention_batch_with_cache(&input, &mask.view(), cache)
+            .index_axis_move(Axis(0), 0)
+    }
 
-        let mut output = dot_3d_3d_par(&mask_scores.view(), &v.view());
+    pub fn attention_batch_with_cache(
+        &self,
+        input: &ArrayView<T, Ix3>,
+        mask: &ArrayView<bool, Ix2>,
+        cache: &mut LayerCache<T>,
+    ) -> Array<T, Ix3> {
+        // input (batch, seq, embed) only holds the new tokens, the keys and values of the previous
+        // ones come from the cache. mask (batch, cached + seq) is false for the padding tokens.
+        let (batch, seq_len, embed_dim) = input.dim();
+
+        let flat_input = input.to_shape((batch * seq_len, embed_dim)).unwrap();
+        let q_dim = self.num_head * self.head_dim;
+        let (q, k, v) = self.qkv.forward(&flat_input, q_dim);
+
+        let mut q = self.split_heads(q, batch, self.num_head);
+        let mut k = self.split_heads(k, batch, self.num_kv_head);
+        let mut v = self.split_heads(v, batch, self.num_kv_head);
+        if let Some(rope) = &self.rope {
+            // the new tokens come after the cached ones
+            let positions = position_ids(mask);
+            let positions = positions.slice(s![.., cache.len()..]);
+            rope.apply(&mut q, &positions);
+            rope.apply(&mut k, &positions);
+        }
+        self.hooks.run(HookPoint::Query, &mut q);
+        self.hooks.run(HookPoint::Key, &mut k);
+        self.hooks.run(HookPoint::Value, &mut v);
+        cache.append(&k.view(), &v.view());
 
-        output.swap_axes(0, 1);
+        let output = cached_attention(&q, cache, mask, |probs| {
+            self.hooks.run(HookPoint::AttentionProbs, probs)
+        }); // (batch * num_head, seq, head_dim)
 
+        let output = output
+            .into_shape((batch, self.num_head, seq_len, self.head_dim))
+            .unwrap();
+        let output = output.permuted_axes([0, 2, 1, 3]);
         let output = output.as_standard_layout();
+        let output = output.to_shape((batch * seq_len, q_dim)).unwrap();
 
-        let output = output.to_shape((seq_len, embed_dim)).unwrap();
-
-        self.proj.forward_cow(&output) // (embed, seq) = (embed, embed) @ (embed, seq )
+        let output = self.proj.forward_cow(&output); // (batch * seq, embed) = (batch * seq, num_head * head_dim) @ (num_head * head_dim, embed)
+        let out_dim = output.shape()[1];
+        output.into_shape((batch, seq_len, out_dim)).unwrap()
     }
 }
 
+// q (batch * num_head, seq, head_dim) holds the queries of the new tokens, they attend to the keys
+// and values of the cache (batch * num_kv_head, cached + seq, head_dim) which already include
+// the new tokens. Groups of num_head / num_kv_head query heads share the same key and value
+// head. on_probs can read or change the attention probabilities.
+pub fn cached_attention<T: MyFloat>(
+    q: &Array<T, Ix3>,
+    cache: &LayerCache<T>,
+    mask: &ArrayView<bool, Ix2>,
+    on_probs: impl FnOnce(&mut Array<T, Ix3>),
+) -> Array<T, Ix3> {
+    let (k, v) = (cache.keys(), cache.values());
+    let (rows, seq_len, head_dim) = q.dim();
+    let (kv_rows, total_len) = (k.shape()[0], k.shape()[1]);
+    let past_len = total_len - seq_len;
+
+    // the query heads of a group are consecutive rows, so the queries of a group are stacked into
+    // one matrix that multiplies its key and value head: the cache is never copied
+    let group = rows / kv_rows;
+    let q = q.to_shape((kv_rows, group * seq_len, head_dim)).unwrap();
+    let qk = dot_3d_3d_par(&q.view(), &k.permuted_axes([0, 2, 1]));
+
+    let norm = 1.0 / (head_dim as f32).sqrt();
+
+    // back to one row per query head for the mask and the hooks
+    let mut scores = (qk * T::from(norm).unwrap())
+        .into_shape((rows, seq_len, total_len))
+        .unwrap();
+
+    let mask_scores = fill_attention_mask_3d(&mut scores, mask, T::from(-1e9).unwrap(), past_len);
+    softmax_inplace_3d(mask_scores);
+    on_probs(&mut scores);
+
+    let probs = scores
+        .to_shape((kv_rows, group * seq_len, total_len))
+        .unwrap();
+    dot_3d_3d_par(&probs.view(), &v)
+        .into_shape((rows, seq_len, head_dim))
+        .unwrap()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing;
     use ndarray::prelude::*;
 
     #[test]
@@ -160,4 +366,107 @@ mod tests {
 
         assert_eq!(output.mean().unwrap(), -0.05929202);
     }
+
+    // the shared pseudo random values, larger so that the attention is not uniform
+    fn scaled_random(shape: (usize, usize), seed: usize) -> Array<f32, Ix2> {
+        testing::pseudo_random(shape, seed) * 2.5
+    }
+
+    fn columns(x: &Array<f32, Ix2>, columns: &[usize]) -> Array<f32, Ix2> {
+        x.select(Axis(1), columns)
+    }
+
+    #[test]
+    fn test_split_projection() {
+        let (embed_dim, seq_len) = (8, 5);
+        let input = scaled_random((seq_len, embed_dim), 3);
+        let (weight, bias) = (
+            scaled_random((embed_dim, 3 * embed_dim), 1),
+            Array::linspace(-0.5, 0.5, 3 * embed_dim),
+        );
+        let proj = || {
+            Linear::new(
+                scaled_random((embed_dim, embed_dim), 2),
+                Array::zeros(embed_dim),
+            )
+        };
+
+        let fused = CausalHead::new(Linear::new(weight.clone(), bias.clone()), proj(), 2);
+        let part = |i: usize| {
+            let range = i * embed_dim..(i + 1) * embed_dim;
+            Linear::new(
+                weight.slice(s![.., range.clone()]).to_owned(),
+                bias.slice(s![range]).to_owned(),
+            )
+        };
+        let split = CausalHead::new_grouped((part(0), part(1), part(2)), proj(), 2, 2, None);
+
+        let diff = (fused.attention(&input) - split.attention(&input)).mapv(f32::abs);
+        assert!(diff.iter().all(|&d| d < 1e-6));
+    }
+
+    #[test]
+    fn test_grouped_query_attention() {
+        // 4 query heads of dimension 2, with 2 key and value heads (GQA) or a single one (MQA)
+        let (embed_dim, num_head, head_dim, seq_len) = (8, 4, 2, 6);
+        let input = scaled_random((seq_len, embed_dim), 3);
+        let proj = || {
+            Linear::new(
+                scaled_random((embed_dim, embed_dim), 2),
+                Array::zeros(embed_dim),
+            )
+        };
+
+        for num_kv_head in [2, 1] {
+            let kv_dim = num_kv_head * head_dim;
+            let weight = scaled_random((embed_dim, embed_dim + 2 * kv_dim), 1);
+            let bias = Array::linspace(-0.5, 0.5, embed_dim + 2 * kv_dim);
+            let grouped = CausalHead::new_grouped(
+                Linear::new(weight.clone(), bias.clone()),
+                proj(),
+                num_head,
+                num_kv_head,
+                None,
+            );
+            assert_eq!(grouped.num_kv_head(), num_kv_head);
+
+            // the same attention with every key and value head copied for each query head of its group
+            let group = num_head / num_kv_head;
+            let mut full: Vec<usize> = (0..embed_dim).collect();
+            for offset in [embed_dim, embed_dim + kv_dim] {
+                full.extend(
+                    (0..embed_dim)
+                        .map(|c| offset + (c / head_dim / group) * head_dim + c % head_dim),
+                );
+            }
+            let bias = bias.select(Axis(0), &full);
+            let multi_head =
+                CausalHead::new(Linear::new(columns(&weight, &full), bias), proj(), num_head);
+
+            let expected = multi_head.attention(&input);
+            let diff = (grouped.attention(&input) - &expected).mapv(f32::abs);
+            assert!(diff.iter().all(|&d| d < 1e-5));
+
+            // the cache only holds the key and value heads
+            let mut cache = LayerCache::new();
+            grouped.attention_with_cache(&input.slice(s![..4, ..]).to_owned(), &mut cache);
+            assert_eq!(cache.keys().shape(), &[num_kv_head, 4, head_dim]);
+            let output =
+                grouped.attention_with_cache(&input.slice(s![4.., ..]).to_owned(), &mut cache);
+            let diff = (output - expected.slice(s![4.., ..])).mapv(f32::abs);
+            assert!(diff.iter().all(|&d| d < 1e-5));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "num_head (4) must be a multiple of num_kv_head (3)")]
+    fn test_invalid_num_kv_head() {
+        CausalHead::<f32>::new_grouped(
+            Linear::new_zeros(8, 20),
+            Linear::new_zeros(8, 8),
+            4,
+            3,
+            None,
+        );
+    }
 }
@@ -0,0 +1,94 @@
+use crate::float::MyFloat;
+use ndarray::{Array, ArrayViewMut, Dimension, IxDyn};
+
+// the intermediate tensors of a block that hooks can read and change
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HookPoint {
+    // (batch * num_head, seq, head_dim) for the new tokens, before the keys and values are cached.
+    // The keys and values have num_kv_head heads, fewer than the queries with grouped-query attention
+    Query,
+    Key,
+    Value,
+    // (batch * num_head, seq, cached + seq), after the softmax
+    AttentionProbs,
+    // (batch * seq, inner), after the activation function, silu(gate) * up for LLaMA. The mixtures
+    // of experts of Mixtral have none
+    MlpActivation,
+    // (batch * seq, embed), output of the block added to the residual stream
+    BlockOutput,
+}
+
+// a hook gets the tensor and can change it in place. It is shared with the threads running the
+// model, so state has to go through a Mutex or an atomic
+pub type Hook<T> = Box<dyn Fn(&mut ArrayViewMut<T, IxDyn>) + Send + Sync>;
+
+pub struct Hooks<T>
+where
+    T: MyFloat,
+{
+    hooks: Vec<(HookPoint, Hook<T>)>,
+}
+
+impl<T> Hooks<T>
+where
+    T: MyFloat,
+{
+    pub fn new() -> Hooks<T> {
+        Hooks { hooks: Vec::new() }
+    }
+
+    pub fn register(&mut self, point: HookPoint, hook: Hook<T>) {
+        self.hooks.push((point, hook));
+    }
+
+    pub fn clear(&mut self) {
+        self.hooks.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    // the hooks of the point are called in the order they were registered. Without hooks this is
+    // only a loop over an empty vector
+    pub fn run<D: Dimension>(&self, point: HookPoint, x: &mut Array<T, D>) {
+        for (_, hook) in self.hooks.iter().filter(|(p, _)| *p == point) {
+            hook(&mut x.view_mut().into_dyn());
+        }
+    }
+}
+
+impl<T> Default for Hooks<T>
+where
+    T: MyFloat,
+{
+    fn default() -> Self {
+        Hooks::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_run() {
+        let mut hooks = Hooks::<f32>::new();
+        assert!(hooks.is_empty());
+
+        hooks.register(HookPoint::Key, Box::new(|x| x.mapv_inplace(|v| v * 2.0)));
+        hooks.register(HookPoint::Value, Box::new(|x| x.fill(0.0)));
+        hooks.register(HookPoint::Key, Box::new(|x| x.mapv_inplace(|v| v + 1.0)));
+
+        let mut x = array![[1.0f32, 2.0], [3.0, 4.0]];
+        hooks.run(HookPoint::Key, &mut x);
+        assert_eq!(x, array![[3.0, 5.0], [7.0, 9.0]]);
+        hooks.run(HookPoint::Query, &mut x);
+        assert_eq!(x, array![[3.0, 5.0], [7.0, 9.0]]);
+
+        hooks.clear();
+        hooks.run(HookPoint::Key, &mut x);
+        assert_eq!(x, array![[3.0, 5.0], [7.0, 9.0]]);
+    }
+}
@@ -1,24 +1,42 @@
 use crate::float::MyFloat;
+use crate::nn::weight::Weight;
 use ndarray::{Array, Axis, Ix1, Ix2};
 
 pub struct LayerNorm<T>
 where
     T: MyFloat,
 {
-    weight: Array<T, Ix1>,
-    bias: Array<T, Ix1>,
+    weight: Weight<T, Ix1>,
+    bias: Weight<T, Ix1>,
+    eps: T,
 }
 
 impl<T> LayerNorm<T>
 where
     T: MyFloat,
 {
-    pub fn new(weight: Array<T, Ix1>, bias: Array<T, Ix1>) -> LayerNorm<T> {
-        LayerNorm { weight, bias }
+    pub fn new(weight: impl Into<Weight<T, Ix1>>, bias: impl Into<Weight<T, Ix1>>) -> LayerNorm<T> {
+        LayerNorm::new_with_eps(weight, bias, T::from(1e-5).unwrap())
+    }
+
+    pub fn new_with_eps(
+        weight: impl Into<Weight<T, Ix1>>,
+        bias: impl Into<Weight<T, Ix1>>,
+        eps: T,
+    ) -> LayerNorm<T> {
+        LayerNorm {
+            weight: weight.into(),
+            bias: bias.into(),
+            eps,
+        }
+    }
+
+    pub fn eps(&self) -> T {
+        self.eps
     }
 
     pub fn forward(&self, x: &Array<T, Ix2>) -> Array<T, Ix2> {
-        let eps = T::from(1e-5).unwrap();
+        let eps = self.eps;
         let mut var = x.var_axis(Axis(1), T::from(0).unwrap());
         var.mapv_inplace(|v| (v + eps).sqrt());
 
@@ -41,7 +59,7 @@ where
 
         let y = x / var;
 
-        y * &self.weight + &self.bias
+        y * self.weight.view() + self.bias.view()
     }
 
     pub fn new_zeros(embed_dim: usize) -> LayerNorm<T> {
@@ -1,12 +1,14 @@
 use crate::float::MyFloat;
+use crate::nn::weight::Weight;
 use ndarray::{Array, CowArray, Ix1, Ix2};
 
 pub struct Linear<T>
 where
     T: MyFloat,
 {
-    weight: Array<T, Ix2>,
-    bias: Array<T, Ix1>,
+    weight: Weight<T, Ix2>,
+    // the projections of LLaMA have none
+    bias: Option<Weight<T, Ix1>>,
 }
 
 impl<T> Linear<T>
@@ -18,12 +20,29 @@ where
     }
 
     pub fn forward_cow(&self, input: &CowArray<T, Ix2>) -> Array<T, Ix2> {
-        let output = input.dot(&self.weight);
-        output + &self.bias // todo could be optimize to do inplace
+        let output = input.dot(&self.weight.view());
+        match &self.bias {
+            Some(bias) => output + bias.view(), // todo could be optimize to do inplace
+            None => output,
+        }
     }
 
-    pub fn new(weight: Array<T, Ix2>, bias: Array<T, Ix1>) -> Linear<T> {
-        Linear { weight, bias }
+    pub fn new(weight: impl Into<Weight<T, Ix2>>, bias: impl Into<Weight<T, Ix1>>) -> Linear<T> {
+        Linear {
+            weight: weight.into(),
+            bias: Some(bias.into()),
+        }
+    }
+
+    pub fn new_no_bias(weight: impl Into<Weight<T, Ix2>>) -> Linear<T> {
+        Linear {
+            weight: weight.into(),
+            bias: None,
+        }
+    }
+
+    pub fn dim_out(&self) -> usize {
+        self.weight.shape()[1]
     }
 
     pub fn new_zeros(dim_in: usize, dim_out: usize) -> Linear<T> {
@@ -38,7 +57,7 @@ pub struct LinearNoBias<T>
 where
     T: MyFloat,
 {
-    weight: Array<T, Ix2>,
+    weight: Weight<T, Ix2>,
 }
 
 impl<T> LinearNoBias<T>
@@ -46,11 +65,13 @@ where
     T: MyFloat,
 {
     pub fn forward(&self, input: &Array<T, Ix2>) -> Array<T, Ix2> {
-        input.dot(&self.weight.t()) // contrary to Linear we do a transpose. This is because of gpt2 weight, might change in the future
+        input.dot(&self.weight.view().t()) // contrary to Linear we do a transpose. This is because of gpt2 weight, might change in the future
     }
 
-    pub fn new(weight: Array<T, Ix2>) -> LinearNoBias<T> {
-        LinearNoBias { weight }
+    pub fn new(weight: impl Into<Weight<T, Ix2>>) -> LinearNoBias<T> {
+        LinearNoBias {
+            weight: weight.into(),
+        }
     }
 
     pub fn new_zeros(dim_in: usize, dim_out: usize) -> LinearNoBias<T> {
@@ -89,6 +110,19 @@ mod tests {
         linear.forward(&input);
     }
 
+    #[test]
+    fn test_forward_without_bias() {
+        let weight = ndarray::array![[1.0, 2.0], [3.0, 4.0], [5.0, 6.0]];
+        let input = ndarray::array![[1.0, 0.0, -1.0]];
+
+        let linear = Linear::<f32>::new_no_bias(weight.clone());
+        assert_eq!(linear.forward(&input), ndarray::array![[-4.0, -4.0]]);
+        assert_eq!(linear.dim_out(), 2);
+
+        let linear = Linear::<f32>::new(weight, ndarray::array![0.5, 1.0]);
+        assert_eq!(linear.forward(&input), ndarray::array![[-3.5, -3.0]]);
+    }
+
     #[test]
     fn test_f16() {
         let input_dim = 3;
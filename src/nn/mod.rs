@@ -1,6 +1,12 @@
 pub mod block;
+pub mod cache;
 pub mod dot;
 pub mod head;
+pub mod hooks;
 pub mod layer_norm;
 pub mod linear;
+pub mod moe;
+pub mod rms_norm;
+pub mod rope;
 pub mod utils;
+pub mod weight;
@@ -0,0 +1,192 @@
+use crate::float::MyFloat;
+use crate::nn::linear::LinearNoBias;
+use crate::nn::utils::softmax;
+use ndarray::{Array, Axis, Ix1, Ix2};
+use rayon::prelude::*;
+
+// a feed-forward network that can be one of the experts of a SparseMoe
+pub trait Expert<T>: Send + Sync
+where
+    T: MyFloat,
+{
+    fn forward(&self, x: &Array<T, Ix2>) -> Array<T, Ix2>;
+}
+
+// Sparse mixture of experts, like the feed-forward of Mixtral: the gate scores every expert for
+// every token, and the output of a token is the sum of its top_k experts weighted by the softmax
+// of their scores. The other experts don't run on that token at all.
+pub struct SparseMoe<T, E>
+where
+    T: MyFloat,
+    E: Expert<T>,
+{
+    gate: LinearNoBias<T>,
+    experts: Vec<E>,
+    top_k: usize,
+}
+
+impl<T, E> SparseMoe<T, E>
+where
+    T: MyFloat,
+    E: Expert<T>,
+{
+    pub fn new(gate: LinearNoBias<T>, experts: Vec<E>, top_k: usize) -> SparseMoe<T, E> {
+        assert!(
+            top_k >= 1 && top_k <= experts.len(),
+            "top_k ({}) must be between 1 and the number of experts ({})",
+            top_k,
+            experts.len()
+        );
+        SparseMoe {
+            gate,
+            experts,
+            top_k,
+        }
+    }
+
+    pub fn num_experts(&self) -> usize {
+        self.experts.len()
+    }
+
+    pub fn top_k(&self) -> usize {
+        self.top_k
+    }
+
+    // for every expert, the rows of x it is picked for and the weight of its output for each of them
+    pub fn route(&self, x: &Array<T, Ix2>) -> Vec<Vec<(usize, T)>> {
+        let logits = self.gate.forward(x);
+        let mut routes = vec![Vec::new(); self.experts.len()];
+        for (row, scores) in logits.outer_iter().enumerate() {
+            // compared as f32, which is exact for every MyFloat and has a total order even with NaN
+            let keys: Vec<f32> = scores.iter().map(|score| score.to_f32().unwrap()).collect();
+            let mut order: Vec<usize> = (0..scores.len()).collect();
+            order.sort_by(|&a, &b| keys[b].total_cmp(&keys[a]));
+            order.truncate(self.top_k);
+
+            // the softmax over the chosen experts is the softmax over all of them, renormalized
+            let top: Array<T, Ix1> = order.iter().map(|&e| scores[e]).collect();
+            for (&expert, &weight) in order.iter().zip(softmax(&top.view()).iter()) {
+                routes[expert].push((row, weight));
+            }
+        }
+        routes
+    }
+
+    // x is (tokens, embed). The tokens are grouped by expert so that every expert does a single
+    // matrix product, and the experts run in parallel.
+    pub fn forward(&self, x: &Array<T, Ix2>) -> Array<T, Ix2> {
+        let routes = self.route(x);
+        let outputs: Vec<Option<Array<T, Ix2>>> = self
+            .experts
+            .par_iter()
+            .zip(routes.par_iter())
+            .map(|(expert, route)| {
+                if route.is_empty() {
+                    return None;
+                }
+                let rows: Vec<usize> = route.iter().map(|&(row, _)| row).collect();
+                Some(expert.forward(&x.select(Axis(0), &rows)))
+            })
+            .collect();
+
+        let mut output = Array::zeros(x.raw_dim());
+        for (route, expert_output) in routes.iter().zip(outputs) {
+            let Some(expert_output) = expert_output else {
+                continue;
+            };
+            for (&(row, weight), y) in route.iter().zip(expert_output.outer_iter()) {
+                output
+                    .row_mut(row)
+                    .zip_mut_with(&y, |o, &y| *o = *o + weight * y);
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    // an expert that only scales its input
+    struct Scale(f32);
+
+    impl Expert<f32> for Scale {
+        fn forward(&self, x: &Array<f32, Ix2>) -> Array<f32, Ix2> {
+            x * self.0
+        }
+    }
+
+    fn moe(top_k: usize) -> SparseMoe<f32, Scale> {
+        // (experts, embed) like the Hugging Face gate, the scores of a token are x @ gate.T
+        let gate = array![[1.0, 0.0], [0.0, 1.0], [-1.0, -1.0]];
+        let experts = vec![Scale(1.0), Scale(10.0), Scale(100.0)];
+        SparseMoe::new(LinearNoBias::new(gate), experts, top_k)
+    }
+
+    #[test]
+    fn test_route() {
+        let x = array![[2.0, 0.0], [0.0, 1.0], [-3.0, 0.5]];
+        let routes = moe(2).route(&x);
+
+        // every token goes to two experts, with weights that sum to one
+        assert_eq!(routes.iter().map(Vec::len).sum::<usize>(), 6);
+        for row in 0..3 {
+            let total: f32 = routes
+                .iter()
+                .flatten()
+                .filter(|&&(r, _)| r == row)
+                .map(|&(_, w)| w)
+                .sum();
+            assert!((total - 1.0).abs() < 1e-6);
+        }
+        // scores [2, 0, -2], [0, 1, -1] and [-3, 0.5, 2.5]
+        assert_eq!(
+            routes
+                .iter()
+                .map(|r| r.iter().map(|&(row, _)| row).collect())
+                .collect::<Vec<Vec<usize>>>(),
+            vec![vec![0, 1], vec![0, 1, 2], vec![2]]
+        );
+        let expected = 1.0 / (1.0 + (-2f32).exp());
+        assert!((routes[0][0].1 - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_forward() {
+        let x = array![[2.0, 0.0], [0.0, 1.0], [-3.0, 0.5]];
+
+        // with a single expert per token the output is the output of that expert
+        let output = moe(1).forward(&x);
+        assert_eq!(output, array![[2.0, 0.0], [0.0, 10.0], [-300.0, 50.0]]);
+
+        // with all of them it is the average weighted by the softmax of the scores
+        let output = moe(3).forward(&x);
+        for (row, scores) in [[2.0f32, 0.0, -2.0], [0.0, 1.0, -1.0], [-3.0, 0.5, 2.5]]
+            .iter()
+            .enumerate()
+        {
+            let weights = softmax(&Array::from(scores.to_vec()).view());
+            let scale = weights[0] + 10.0 * weights[1] + 100.0 * weights[2];
+            let expected = x.row(row).mapv(|v| v * scale);
+            let diff = (&output.row(row) - &expected).mapv(f32::abs).sum();
+            assert!(diff < 1e-3, "row {} differs by {}", row, diff);
+        }
+    }
+
+    #[test]
+    fn test_nan_scores() {
+        // a NaN score doesn't panic, it ranks above every number like in torch.topk
+        let x = array![[f32::NAN, 0.0], [0.0, 1.0]];
+        let routes = moe(1).route(&x);
+        assert_eq!(routes.iter().map(Vec::len).sum::<usize>(), 2);
+        assert_eq!(routes[1], vec![(1, 1.0)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "top_k (4) must be between 1 and the number of experts (3)")]
+    fn test_invalid_top_k() {
+        moe(4);
+    }
+}
@@ -0,0 +1,61 @@
+use crate::float::MyFloat;
+use crate::nn::weight::Weight;
+use ndarray::{Array, Axis, Ix1, Ix2};
+
+// layer norm without the mean and the bias, used by the LLaMA family
+pub struct RmsNorm<T>
+where
+    T: MyFloat,
+{
+    weight: Weight<T, Ix1>,
+    eps: T,
+}
+
+impl<T> RmsNorm<T>
+where
+    T: MyFloat,
+{
+    pub fn new(weight: impl Into<Weight<T, Ix1>>) -> RmsNorm<T> {
+        RmsNorm::new_with_eps(weight, T::from(1e-6).unwrap())
+    }
+
+    pub fn new_with_eps(weight: impl Into<Weight<T, Ix1>>, eps: T) -> RmsNorm<T> {
+        RmsNorm {
+            weight: weight.into(),
+            eps,
+        }
+    }
+
+    pub fn new_zeros(embed_dim: usize) -> RmsNorm<T> {
+        RmsNorm::new(Array::<T, _>::zeros(embed_dim))
+    }
+
+    pub fn forward(&self, x: &Array<T, Ix2>) -> Array<T, Ix2> {
+        // x / sqrt(mean(x^2) + eps) * weight
+        let eps = self.eps;
+        let rms = x
+            .mapv(|v| v * v)
+            .mean_axis(Axis(1))
+            .unwrap()
+            .mapv(|v| (v + eps).sqrt())
+            .insert_axis(Axis(1));
+        x / &rms * self.weight.view()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_exact_forward() {
+        let x = array![[3.0f32, 4.0], [1.0, -1.0]];
+        let norm = RmsNorm::<f32>::new_with_eps(array![1.0, 2.0], 0.0);
+
+        // the rms of the rows are sqrt(12.5) and 1
+        let expected = array![[3.0 / 12.5f32.sqrt(), 8.0 / 12.5f32.sqrt()], [1.0, -2.0]];
+        let output = norm.forward(&x);
+        assert!((output - expected).iter().all(|d| d.abs() < 1e-6));
+    }
+}
@@ -0,0 +1,103 @@
+use crate::float::MyFloat;
+use ndarray::{Array, ArrayView, Ix2, Ix3};
+
+// Rotary position embeddings: the queries and keys are rotated by an angle that depends on
+// their position, so that their dot product only depends on the distance between them.
+// The first half of the rotated dimensions is paired with the second half, like the
+// rotate_half of the Hugging Face models.
+pub struct Rope<T>
+where
+    T: MyFloat,
+{
+    cos: Array<T, Ix2>, // (max_positions, rotary_dim / 2)
+    sin: Array<T, Ix2>, // (max_positions, rotary_dim / 2)
+}
+
+impl<T> Rope<T>
+where
+    T: MyFloat,
+{
+    // only the first rotary_dim dimensions of every head are rotated, the others are left as is
+    pub fn new(rotary_dim: usize, max_positions: usize, theta: f32) -> Rope<T> {
+        let half = rotary_dim / 2;
+        let angle = |position: usize, i: usize| {
+            let inv_freq = 1.0 / (theta as f64).powf(2.0 * i as f64 / rotary_dim as f64);
+            position as f64 * inv_freq
+        };
+        Rope {
+            cos: Array::from_shape_fn((max_positions, half), |(p, i)| {
+                T::from(angle(p, i).cos()).unwrap()
+            }),
+            sin: Array::from_shape_fn((max_positions, half), |(p, i)| {
+                T::from(angle(p, i).sin()).unwrap()
+            }),
+        }
+    }
+
+    pub fn rotary_dim(&self) -> usize {
+        2 * self.cos.shape()[1]
+    }
+
+    pub fn max_positions(&self) -> usize {
+        self.cos.shape()[0]
+    }
+
+    // x is (batch * num_head, seq, head_dim) and positions (batch, seq)
+    pub fn apply(&self, x: &mut Array<T, Ix3>, positions: &ArrayView<usize, Ix2>) {
+        let half = self.cos.shape()[1];
+        let num_head = x.shape()[0] / positions.shape()[0];
+        for (k, mut head) in x.outer_iter_mut().enumerate() {
+            let positions = positions.row(k / num_head);
+            for (mut row, &position) in head.outer_iter_mut().zip(positions.iter()) {
+                for i in 0..half {
+                    let (cos, sin) = (self.cos[[position, i]], self.sin[[position, i]]);
+                    let (x1, x2) = (row[i], row[i + half]);
+                    row[i] = x1 * cos - x2 * sin;
+                    row[i + half] = x2 * cos + x1 * sin;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_relative_positions() {
+        let rope = Rope::<f32>::new(4, 16, 10000.0);
+        let q = array![[[0.3f32, -0.2, 0.5, 0.1]]];
+        let k = array![[[-0.4f32, 0.6, 0.2, 0.7]]];
+
+        let score = |q_position: usize, k_position: usize| {
+            let (mut q, mut k) = (q.clone(), k.clone());
+            rope.apply(&mut q, &array![[q_position]].view());
+            rope.apply(&mut k, &array![[k_position]].view());
+            (q * k).sum()
+        };
+        // the score only depends on the distance between the tokens
+        assert!((score(5, 3) - score(9, 7)).abs() < 1e-5);
+        assert!((score(5, 3) - score(5, 4)).abs() > 1e-3);
+        // position 0 is not rotated
+        assert!((score(0, 0) - (&q * &k).sum()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_partial_rotation() {
+        let rope = Rope::<f32>::new(2, 8, 10000.0);
+        assert_eq!(rope.rotary_dim(), 2);
+
+        let mut x = array![[[1.0f32, 0.0, 5.0, 6.0]], [[1.0, 0.0, 5.0, 6.0]]];
+        rope.apply(&mut x, &array![[1], [2]].view());
+        // the first pair is rotated by 1 and 2 radians, the last dimensions are left as is
+        assert!((x[[0, 0, 0]] - 1f32.cos()).abs() < 1e-6);
+        assert!((x[[0, 0, 1]] - 1f32.sin()).abs() < 1e-6);
+        assert!((x[[1, 0, 0]] - 2f32.cos()).abs() < 1e-6);
+        assert_eq!(
+            x.slice(ndarray::s![.., 0, 2..]),
+            array![[5.0, 6.0], [5.0, 6.0]]
+        );
+    }
+}
@@ -16,10 +16,43 @@ pub fn fill_tril<'a, T: MyFloat>(x: &'a mut Array<T, Ix2>, val: T) -> &'a mut Ar
 
 pub fn fill_tril_3d<'a, T: MyFloat>(x: &'a mut Array<T, Ix3>, val: T) -> &'a mut Array<T, Ix3> {
     // similar to numpy or torch tril
+    fill_tril_3d_offset(x, val, 0)
+}
+
+pub fn fill_tril_3d_offset<T: MyFloat>(
+    x: &mut Array<T, Ix3>,
+    val: T,
+    offset: usize,
+) -> &mut Array<T, Ix3> {
+    // similar to torch tril with diagonal=offset. Row i is the query at position offset + i,
+    // so it can see every cached position plus itself.
+    for k in 0..x.shape()[0] {
+        for i in 0..x.shape()[1] {
+            for j in 0..x.shape()[2] {
+                if j > i + offset {
+                    x[[k, i, j]] = val;
+                }
+            }
+        }
+    }
+
+    x
+}
+
+pub fn fill_attention_mask_3d<'a, T: MyFloat>(
+    x: &'a mut Array<T, Ix3>,
+    mask: &ArrayView<bool, Ix2>,
+    val: T,
+    offset: usize,
+) -> &'a mut Array<T, Ix3> {
+    // x is (batch * num_head, seq, offset + seq) and mask (batch, offset + seq) is false for the
+    // padding tokens. On top of the causal mask of fill_tril_3d_offset, nobody attends to padding.
+    let num_head = x.shape()[0] / mask.shape()[0];
     for k in 0..x.shape()[0] {
+        let mask = mask.row(k / num_head);
         for i in 0..x.shape()[1] {
             for j in 0..x.shape()[2] {
-                if j > i {
+                if j > i + offset || !mask[j] {
                     x[[k, i, j]] = val;
                 }
             }
@@ -119,6 +152,32 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_trill_3d_offset() {
+        let mut mat1 = Array::<f32, Ix3>::ones((1, 2, 4).f());
+        fill_tril_3d_offset(&mut mat1, 0.0, 2);
+
+        assert_eq!(
+            mat1,
+            Array::<f32, Ix3>::from(vec![[[1.0, 1.0, 1.0, 0.0], [1.0, 1.0, 1.0, 1.0]]])
+        );
+    }
+
+    #[test]
+    fn test_attention_mask_3d() {
+        // 2 sequences with 2 heads each, the first one starts with a padding token
+        let mut mat1 = Array::<f32, Ix3>::ones((4, 2, 3));
+        let mask = array![[false, true, true], [true, true, true]];
+        fill_attention_mask_3d(&mut mat1, &mask.view(), 0.0, 1);
+
+        let padded = array![[0.0, 1.0, 0.0], [0.0, 1.0, 1.0]];
+        let full = array![[1.0, 1.0, 0.0], [1.0, 1.0, 1.0]];
+        assert_eq!(mat1.slice(s![0, .., ..]), padded);
+        assert_eq!(mat1.slice(s![1, .., ..]), padded);
+        assert_eq!(mat1.slice(s![2, .., ..]), full);
+        assert_eq!(mat1.slice(s![3, .., ..]), full);
+    }
+
     #[test]
     fn test_argmax() {
         let index = Array::<f32, _>::from(vec![1.0, 2.0, 3.0, 12.0, 1.0]);
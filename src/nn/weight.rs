@@ -0,0 +1,137 @@
+use crate::float::MyFloat;
+use memmap2::Mmap;
+use ndarray::{Array, ArrayView, Dimension};
+use std::sync::Arc;
+
+// the storage is private so that `mapped`, which checks the bounds and the alignment, is the only
+// way to point a weight into a file
+pub struct Weight<T, D>
+where
+    T: MyFloat,
+    D: Dimension,
+{
+    storage: Storage<T, D>,
+}
+
+enum Storage<T, D>
+where
+    T: MyFloat,
+    D: Dimension,
+{
+    Owned(Array<T, D>),
+    // values living directly in a memory mapped safetensors file
+    Mapped {
+        mmap: Arc<Mmap>,
+        offset: usize,
+        shape: D,
+    },
+}
+
+impl<T, D> Weight<T, D>
+where
+    T: MyFloat,
+    D: Dimension,
+{
+    pub fn mapped(mmap: Arc<Mmap>, offset: usize, shape: D) -> Weight<T, D> {
+        let end = shape
+            .size_checked()
+            .and_then(|size| size.checked_mul(std::mem::size_of::<T>()))
+            .and_then(|len| offset.checked_add(len));
+        assert!(
+            end.is_some_and(|end| end <= mmap.len()),
+            "weight out of the mapped file"
+        );
+        // offset is within the mapping thanks to the assert above
+        let ptr = unsafe { mmap.as_ptr().add(offset) };
+        assert_eq!(
+            ptr.align_offset(std::mem::align_of::<T>()),
+            0,
+            "misaligned weight"
+        );
+        Weight {
+            storage: Storage::Mapped {
+                mmap,
+                offset,
+                shape,
+            },
+        }
+    }
+
+    pub fn view(&self) -> ArrayView<'_, T, D> {
+        match &self.storage {
+            Storage::Owned(array) => array.view(),
+            Storage::Mapped {
+                mmap,
+                offset,
+                shape,
+            } => {
+                // safe because the bounds and the alignment are checked in `mapped` and the
+                // mapping is kept alive by the Arc for as long as self is borrowed
+                unsafe {
+                    let ptr = mmap.as_ptr().add(*offset) as *const T;
+                    ArrayView::from_shape_ptr(shape.clone(), ptr)
+                }
+            }
+        }
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.storage, Storage::Mapped { .. })
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        match &self.storage {
+            Storage::Owned(array) => array.shape(),
+            Storage::Mapped { shape, .. } => shape.slice(),
+        }
+    }
+}
+
+impl<T, D> From<Array<T, D>> for Weight<T, D>
+where
+    T: MyFloat,
+    D: Dimension,
+{
+    fn from(array: Array<T, D>) -> Self {
+        Weight {
+            storage: Storage::Owned(array),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memmap2::MmapOptions;
+    use ndarray::Ix2;
+
+    fn anonymous_mmap(len: usize) -> Arc<Mmap> {
+        Arc::new(
+            MmapOptions::new()
+                .len(len)
+                .map_anon()
+                .unwrap()
+                .make_read_only()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_mapped_view() {
+        let weight = Weight::<f32, Ix2>::mapped(anonymous_mmap(64), 16, Ix2(2, 6));
+        assert!(weight.is_mapped());
+        assert_eq!(weight.view(), Array::<f32, _>::zeros((2, 6)));
+    }
+
+    #[test]
+    #[should_panic(expected = "weight out of the mapped file")]
+    fn test_mapped_out_of_bounds() {
+        Weight::<f32, Ix2>::mapped(anonymous_mmap(64), 20, Ix2(2, 6));
+    }
+
+    #[test]
+    #[should_panic(expected = "weight out of the mapped file")]
+    fn test_mapped_offset_overflow() {
+        Weight::<f32, Ix2>::mapped(anonymous_mmap(64), usize::MAX, Ix2(1, 1));
+    }
+}
@@ -0,0 +1,297 @@
+use crate::float::MyFloat;
+use crate::nn::utils::argmax;
+use ndarray::{ArrayView, Ix1};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+pub trait Sampler<T>
+where
+    T: MyFloat,
+{
+    // pick the next token from the logits of the last position
+    fn sample(&mut self, logits: &ArrayView<T, Ix1>) -> usize;
+}
+
+pub struct Greedy;
+
+impl<T> Sampler<T> for Greedy
+where
+    T: MyFloat,
+{
+    fn sample(&mut self, logits: &ArrayView<T, Ix1>) -> usize {
+        argmax(&logits.into_dyn())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SamplerConfig {
+    pub temperature: f32,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f32>,
+    pub min_p: Option<f32>,
+    pub typical_p: Option<f32>,
+    pub seed: Option<u64>,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        // temperature 0 means greedy decoding, like before samplers existed
+        SamplerConfig {
+            temperature: 0.0,
+            top_k: None,
+            top_p: None,
+            min_p: None,
+            typical_p: None,
+            seed: None,
+        }
+    }
+}
+
+impl SamplerConfig {
+    pub fn build<T: MyFloat>(&self) -> Box<dyn Sampler<T>> {
+        if self.temperature <= 0.0 {
+            Box::new(Greedy)
+        } else {
+            Box::new(RandomSampler::new(self.clone()))
+        }
+    }
+}
+
+pub struct RandomSampler {
+    config: SamplerConfig,
+    rng: StdRng,
+}
+
+impl RandomSampler {
+    pub fn new(config: SamplerConfig) -> RandomSampler {
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        RandomSampler { config, rng }
+    }
+
+    fn candidates<T: MyFloat>(&self, logits: &ArrayView<T, Ix1>) -> Vec<(usize, f32)> {
+        // returns the (token, prob) pairs left after filtering, sorted by decreasing prob.
+        // The filters are applied in the same order as in Hugging Face transformers.
+        let temperature = self.config.temperature;
+        let logits: Vec<f32> = logits
+            .iter()
+            .map(|x| x.to_f32().unwrap() / temperature)
+            .collect();
+
+        let max_ = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let mut candidates: Vec<(usize, f32)> = logits
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (i, (x - max_).exp()))
+            .collect();
+        normalize(&mut candidates);
+        candidates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        if let Some(top_k) = self.config.top_k {
+            candidates.truncate(top_k.max(1));
+        }
+
+        if let Some(top_p) = self.config.top_p {
+            normalize(&mut candidates);
+            let keep = smallest_prefix_with_mass(&candidates, top_p);
+            candidates.truncate(keep);
+        }
+
+        if let Some(min_p) = self.config.min_p {
+            let threshold = candidates[0].1 * min_p;
+            candidates.retain(|(_, p)| *p >= threshold);
+        }
+
+        if let Some(typical_p) = self.config.typical_p {
+            candidates = typical_filter(candidates, typical_p);
+        }
+
+        normalize(&mut candidates);
+        candidates
+    }
+}
+
+impl<T> Sampler<T> for RandomSampler
+where
+    T: MyFloat,
+{
+    fn sample(&mut self, logits: &ArrayView<T, Ix1>) -> usize {
+        let candidates = self.candidates(logits);
+
+        let mut r: f32 = self.rng.gen();
+        for (index, p) in candidates.iter() {
+            if r < *p {
+                return *index;
+            }
+            r -= p;
+        }
+        // rounding errors can leave a tiny bit of mass unused, fall back on the last candidate
+        candidates[candidates.len() - 1].0
+    }
+}
+
+fn normalize(candidates: &mut [(usize, f32)]) {
+    let sum: f32 = candidates.iter().map(|(_, p)| p).sum();
+    candidates.iter_mut().for_each(|(_, p)| *p /= sum);
+}
+
+fn smallest_prefix_with_mass(candidates: &[(usize, f32)], mass: f32) -> usize {
+    // candidates must be sorted, always keep at least one token
+    let mut cumsum = 0.0;
+    for (i, (_, p)) in candidates.iter().enumerate() {
+        cumsum += p;
+        if cumsum >= mass {
+            return i + 1;
+        }
+    }
+    candidates.len()
+}
+
+fn typical_filter(mut candidates: Vec<(usize, f32)>, typical_p: f32) -> Vec<(usize, f32)> {
+    // locally typical sampling (Meister et al. 2022): keep the tokens whose information content
+    // is the closest to the entropy of the distribution
+    normalize(&mut candidates);
+    let entropy: f32 = candidates
+        .iter()
+        .filter(|(_, p)| *p > 0.0)
+        .map(|(_, p)| -p * p.ln())
+        .sum();
+
+    candidates.sort_by(|(_, a), (_, b)| {
+        let a = (-a.ln() - entropy).abs();
+        let b = (-b.ln() - entropy).abs();
+        a.total_cmp(&b)
+    });
+    let keep = smallest_prefix_with_mass(&candidates, typical_p);
+    candidates.truncate(keep);
+
+    candidates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::prelude::*;
+
+    fn config(temperature: f32) -> SamplerConfig {
+        SamplerConfig {
+            temperature,
+            seed: Some(42),
+            ..Default::default()
+        }
+    }
+
+    fn kept(sampler: &RandomSampler, logits: &Array<f32, Ix1>) -> Vec<usize> {
+        let mut kept: Vec<usize> = sampler
+            .candidates(&logits.view())
+            .iter()
+            .map(|(i, _)| *i)
+            .collect();
+        kept.sort();
+        kept
+    }
+
+    #[test]
+    fn test_greedy() {
+        let logits = array![1.0, 2.0, 3.0, 12.0, 1.0];
+        let mut sampler = config(0.0).build::<f32>();
+        assert_eq!(sampler.sample(&logits.view()), 3);
+    }
+
+    #[test]
+    fn test_seed_is_reproducible() {
+        let logits = Array::<f32, _>::linspace(0.0, 2.0, 20);
+
+        let mut sampler_1 = config(1.0).build::<f32>();
+        let mut sampler_2 = config(1.0).build::<f32>();
+
+        let samples_1: Vec<usize> = (0..20).map(|_| sampler_1.sample(&logits.view())).collect();
+        let samples_2: Vec<usize> = (0..20).map(|_| sampler_2.sample(&logits.view())).collect();
+
+        assert_eq!(samples_1, samples_2);
+    }
+
+    #[test]
+    fn test_top_k() {
+        let logits = array![1.0, 4.0, 3.0, 2.0, 0.0];
+        let sampler = RandomSampler::new(SamplerConfig {
+            top_k: Some(2),
+            ..config(1.0)
+        });
+        assert_eq!(kept(&sampler, &logits), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_top_p() {
+        // probs are 0.5, 0.25, 0.125, 0.125
+        let logits = array![3.0, 2.0, 1.0, 1.0].mapv(|x: f32| x * 2.0_f32.ln());
+        let sampler = RandomSampler::new(SamplerConfig {
+            top_p: Some(0.7),
+            ..config(1.0)
+        });
+        assert_eq!(kept(&sampler, &logits), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_min_p() {
+        // probs are 0.5, 0.25, 0.125, 0.125
+        let logits = array![3.0, 2.0, 1.0, 1.0].mapv(|x: f32| x * 2.0_f32.ln());
+        let sampler = RandomSampler::new(SamplerConfig {
+            min_p: Some(0.3),
+            ..config(1.0)
+        });
+        assert_eq!(kept(&sampler, &logits), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_typical() {
+        // the entropy is 1.75 bits, the two 0.125 tokens carry 3 bits and the 0.25 one 2 bits,
+        // so the 0.25 token is the most typical one
+        let logits = array![3.0, 2.0, 1.0, 1.0].mapv(|x: f32| x * 2.0_f32.ln());
+        let sampler = RandomSampler::new(SamplerConfig {
+            typical_p: Some(0.2),
+            ..config(1.0)
+        });
+        assert_eq!(kept(&sampler, &logits), vec![1]);
+    }
+
+    #[test]
+    fn test_filter_order() {
+        // like Hugging Face, top_p runs before typical_p: it keeps the 0.5 token alone, while
+        // typical_p on its own would only keep the 0.25 one
+        let logits = array![3.0, 2.0, 1.0, 1.0].mapv(|x: f32| x * 2.0_f32.ln());
+        let sampler = RandomSampler::new(SamplerConfig {
+            top_p: Some(0.5),
+            typical_p: Some(0.2),
+            ..config(1.0)
+        });
+        assert_eq!(kept(&sampler, &logits), vec![0]);
+    }
+
+    #[test]
+    fn test_nan_logits() {
+        let logits = array![1.0, f32::NAN, 2.0];
+        let mut sampler = RandomSampler::new(SamplerConfig {
+            top_k: Some(2),
+            typical_p: Some(0.9),
+            ..config(1.0)
+        });
+        assert!(sampler.sample(&logits.view()) < 3);
+    }
+
+    #[test]
+    fn test_sample_only_from_candidates() {
+        let logits = array![1.0, 4.0, 3.0, 2.0, 0.0];
+        let mut sampler = RandomSampler::new(SamplerConfig {
+            top_k: Some(2),
+            ..config(1.0)
+        });
+        for _ in 0..50 {
+            let index = Sampler::<f32>::sample(&mut sampler, &logits.view());
+            assert!(index == 1 || index == 2);
+        }
+    }
+}
@@ -0,0 +1,469 @@
+// OpenAI compatible completion server.
+//
+// A single worker thread owns the model and processes the requests one after the other, a fixed
+// pool of http threads only parse the requests and write the responses. This way several clients
+// can share one loaded model without ever running two forward passes at the same time, and the
+// connections beyond the pool wait in the queue of tiny_http instead of getting a thread each.
+
+use crate::error::GenerateError;
+use crate::float::MyFloat;
+use crate::generation::{FinishReason, GeneratedToken, GenerationConfig, Generator};
+use crate::model::CausalLM;
+use crate::sampling::SamplerConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::io;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Method, Request, Response};
+use tokenizers::Tokenizer;
+
+#[derive(Deserialize)]
+pub struct CompletionRequest {
+    pub model: Option<String>,
+    pub prompt: String,
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: usize,
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    pub top_p: Option<f32>,
+    pub seed: Option<u64>,
+    pub stop: Option<Stop>,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChatCompletionRequest {
+    pub model: Option<String>,
+    pub messages: Vec<ChatMessage>,
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: usize,
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    pub top_p: Option<f32>,
+    pub seed: Option<u64>,
+    pub stop: Option<Stop>,
+    #[serde(default)]
+    pub stream: bool,
+}
+
+// OpenAI accepts a single stop string or a list of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Stop {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl From<Stop> for Vec<String> {
+    fn from(stop: Stop) -> Self {
+        match stop {
+            Stop::One(stop) => vec![stop],
+            Stop::Many(stop) => stop,
+        }
+    }
+}
+
+fn default_max_tokens() -> usize {
+    16
+}
+
+fn default_temperature() -> f32 {
+    1.0
+}
+
+#[derive(Serialize)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+// number of requests answered at the same time, the others wait for a free http thread
+const HTTP_THREADS: usize = 8;
+
+struct Job {
+    prompt_ids: Vec<usize>,
+    config: GenerationConfig,
+    // the error is sent instead of the tokens when the generation can't start
+    tokens: Sender<Result<GeneratedToken, GenerateError>>,
+}
+
+fn run_worker<T: MyFloat>(model: Arc<dyn CausalLM<T>>, shared: Arc<Shared>, jobs: Receiver<Job>) {
+    let generator = Generator::new(&*model, &shared.tokenizer);
+    for job in jobs {
+        let stream = match generator.stream_ids(job.prompt_ids, &job.config) {
+            Ok(stream) => stream,
+            Err(e) => {
+                let _ = job.tokens.send(Err(e));
+                continue;
+            }
+        };
+        for token in stream {
+            if job.tokens.send(Ok(token)).is_err() {
+                break; // the client went away, no need to keep generating
+            }
+        }
+    }
+}
+
+struct Generation {
+    prompt: String,
+    config: GenerationConfig,
+    stream: bool,
+}
+
+enum Kind {
+    Completion,
+    Chat,
+}
+
+struct Shared {
+    tokenizer: Tokenizer,
+    model_name: String,
+    next_id: AtomicUsize,
+}
+
+pub struct Server {
+    http: Arc<tiny_http::Server>,
+    shared: Arc<Shared>,
+    jobs: Sender<Job>,
+}
+
+impl Server {
+    pub fn bind<T: MyFloat>(
+        addr: &str,
+        model: impl CausalLM<T> + 'static,
+        tokenizer: Tokenizer,
+        model_name: &str,
+    ) -> io::Result<Server> {
+        let http = tiny_http::Server::http(addr).map_err(|e| io::Error::other(e.to_string()))?;
+
+        let shared = Arc::new(Shared {
+            tokenizer,
+            model_name: model_name.to_string(),
+            next_id: AtomicUsize::new(0),
+        });
+
+        let (jobs, receiver) = channel();
+        let model: Arc<dyn CausalLM<T>> = Arc::new(model);
+        let worker_shared = shared.clone();
+        thread::spawn(move || run_worker(model, worker_shared, receiver));
+
+        Ok(Server {
+            http: Arc::new(http),
+            shared,
+            jobs,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.http.server_addr().to_ip().unwrap()
+    }
+
+    pub fn run(self) {
+        let threads: Vec<_> = (0..HTTP_THREADS)
+            .map(|_| {
+                let http = self.http.clone();
+                let shared = self.shared.clone();
+                let jobs = self.jobs.clone();
+                thread::spawn(move || {
+                    for request in http.incoming_requests() {
+                        if let Err(e) = handle(request, &shared, &jobs) {
+                            eprintln!("error while answering a request: {}", e);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn json_response(status: u16, body: &serde_json::Value) -> Response<io::Cursor<Vec<u8>>> {
+    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+    Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header)
+}
+
+fn error_response(status: u16, message: &str) -> Response<io::Cursor<Vec<u8>>> {
+    json_response(
+        status,
+        &json!({"error": {"message": message, "type": "invalid_request_error"}}),
+    )
+}
+
+fn chat_prompt(messages: &[ChatMessage]) -> String {
+    // GPT-2 has no chat template, the conversation is written as a transcript that the model
+    // has to continue
+    let mut prompt = String::new();
+    for message in messages {
+        prompt.push_str(&format!("{}: {}\n", message.role, message.content));
+    }
+    prompt.push_str("assistant:");
+    prompt
+}
+
+fn parse(kind: &Kind, body: &str) -> Result<Generation, String> {
+    let config = |max_tokens, temperature, top_p, seed, stop: Option<Stop>| GenerationConfig {
+        max_tokens,
+        stop: stop.map(Vec::from).unwrap_or_default(),
+        sampler: SamplerConfig {
+            temperature,
+            top_p,
+            seed,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    match kind {
+        Kind::Completion => {
+            let request: CompletionRequest =
+                serde_json::from_str(body).map_err(|e| e.to_string())?;
+            Ok(Generation {
+                prompt: request.prompt,
+                config: config(
+                    request.max_tokens,
+                    request.temperature,
+                    request.top_p,
+                    request.seed,
+                    request.stop,
+                ),
+                stream: request.stream,
+            })
+        }
+        Kind::Chat => {
+            let request: ChatCompletionRequest =
+                serde_json::from_str(body).map_err(|e| e.to_string())?;
+            Ok(Generation {
+                prompt: chat_prompt(&request.messages),
+                config: config(
+                    request.max_tokens,
+                    request.temperature,
+                    request.top_p,
+                    request.seed,
+                    request.stop,
+                ),
+                stream: request.stream,
+            })
+        }
+    }
+}
+
+fn handle(mut request: Request, shared: &Shared, jobs: &Sender<Job>) -> io::Result<()> {
+    let kind = match (request.method(), request.url()) {
+        (Method::Post, "/v1/completions") => Kind::Completion,
+        (Method::Post, "/v1/chat/completions") => Kind::Chat,
+        _ => return request.respond(error_response(404, "unknown endpoint")),
+    };
+
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body)?;
+
+    let generation = match parse(&kind, &body) {
+        Ok(generation) => generation,
+        Err(e) => return request.respond(error_response(400, &e)),
+    };
+
+    let prompt_ids: Vec<usize> = match shared.tokenizer.encode(generation.prompt.as_str(), false) {
+        Ok(encoding) => encoding.get_ids().iter().map(|&x| x as usize).collect(),
+        Err(e) => return request.respond(error_response(400, &e.to_string())),
+    };
+
+    let (sender, tokens) = channel();
+    let prompt_tokens = prompt_ids.len();
+    jobs.send(Job {
+        prompt_ids,
+        config: generation.config,
+        tokens: sender,
+    })
+    .map_err(|_| io::Error::other("the worker stopped"))?;
+
+    // the worker starts with the error when the prompt can't be used, for instance because it is
+    // empty or doesn't fit in the context. Nothing was written yet, it is a bad request
+    let first = match tokens.recv() {
+        Ok(Err(e)) => return request.respond(error_response(400, &e.to_string())),
+        Ok(Ok(token)) => Some(token),
+        Err(_) => None,
+    };
+    let tokens = first.into_iter().map(Ok).chain(tokens);
+
+    let id = format!("cmpl-{}", shared.next_id.fetch_add(1, Ordering::Relaxed));
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let meta = Meta {
+        id: &id,
+        created,
+        model: &shared.model_name,
+    };
+
+    if generation.stream {
+        stream(request, &kind, &meta, tokens)
+    } else {
+        let mut text = String::new();
+        let mut completion_tokens = 0;
+        let mut finish_reason = "length";
+        for token in tokens {
+            let token = match token {
+                Ok(token) => token,
+                Err(e) => return request.respond(error_response(500, &e.to_string())),
+            };
+            completion_tokens += 1;
+            text.push_str(&token.text);
+            if let Some(reason) = token.finish_reason {
+                finish_reason = openai_finish_reason(reason);
+            }
+        }
+        let usage = Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        };
+        let body = match kind {
+            Kind::Completion => json!({
+                "id": meta.id,
+                "object": "text_completion",
+                "created": meta.created,
+                "model": meta.model,
+                "choices": [{
+                    "text": text,
+                    "index": 0,
+                    "logprobs": null,
+                    "finish_reason": finish_reason,
+                }],
+                "usage": usage,
+            }),
+            Kind::Chat => json!({
+                "id": meta.id,
+                "object": "chat.completion",
+                "created": meta.created,
+                "model": meta.model,
+                "choices": [{
+                    "index": 0,
+                    "message": ChatMessage { role: "assistant".to_string(), content: text },
+                    "finish_reason": finish_reason,
+                }],
+                "usage": usage,
+            }),
+        };
+        request.respond(json_response(200, &body))
+    }
+}
+
+fn openai_finish_reason(reason: FinishReason) -> &'static str {
+    // OpenAI does not tell an end of sequence token from a stop string
+    match reason {
+        FinishReason::Length => "length",
+        FinishReason::Eos | FinishReason::Stop => "stop",
+    }
+}
+
+struct Meta<'a> {
+    id: &'a str,
+    created: u64,
+    model: &'a str,
+}
+
+fn completion_chunk(meta: &Meta, text: &str, finish_reason: Option<&str>) -> serde_json::Value {
+    json!({
+        "id": meta.id,
+        "object": "text_completion",
+        "created": meta.created,
+        "model": meta.model,
+        "choices": [{
+            "text": text,
+            "index": 0,
+            "logprobs": null,
+            "finish_reason": finish_reason,
+        }],
+    })
+}
+
+fn chat_chunk(
+    meta: &Meta,
+    delta: serde_json::Value,
+    finish_reason: Option<&str>,
+) -> serde_json::Value {
+    json!({
+        "id": meta.id,
+        "object": "chat.completion.chunk",
+        "created": meta.created,
+        "model": meta.model,
+        "choices": [{"index": 0, "delta": delta, "finish_reason": finish_reason}],
+    })
+}
+
+fn write_event(writer: &mut dyn Write, data: &serde_json::Value) -> io::Result<()> {
+    write!(writer, "data: {}\n\n", data)?;
+    writer.flush()
+}
+
+fn stream(
+    request: Request,
+    kind: &Kind,
+    meta: &Meta,
+    tokens: impl Iterator<Item = Result<GeneratedToken, GenerateError>>,
+) -> io::Result<()> {
+    // tiny_http buffers chunked bodies, the events are written by hand on the raw connection
+    // so that every token reaches the client as soon as it is generated
+    let mut writer = request.into_writer();
+    write!(
+        writer,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    )?;
+
+    if let Kind::Chat = kind {
+        write_event(
+            &mut writer,
+            &chat_chunk(meta, json!({"role": "assistant"}), None),
+        )?;
+    }
+
+    // an error while writing means the client disconnected, returning drops `tokens` and the
+    // worker stops the generation
+    let mut finish_reason = "length";
+    for token in tokens {
+        // the status is already sent, the error can only be reported as an event
+        let token = match token {
+            Ok(token) => token,
+            Err(e) => {
+                let error = json!({"error": {"message": e.to_string(), "type": "server_error"}});
+                return write_event(&mut writer, &error);
+            }
+        };
+        let data = match kind {
+            Kind::Completion => completion_chunk(meta, &token.text, None),
+            Kind::Chat => chat_chunk(meta, json!({"content": token.text}), None),
+        };
+        write_event(&mut writer, &data)?;
+        if let Some(reason) = token.finish_reason {
+            finish_reason = openai_finish_reason(reason);
+        }
+    }
+
+    let data = match kind {
+        Kind::Completion => completion_chunk(meta, "", Some(finish_reason)),
+        Kind::Chat => chat_chunk(meta, json!({}), Some(finish_reason)),
+    };
+    write_event(&mut writer, &data)?;
+
+    write!(writer, "data: [DONE]\n\n")?;
+    writer.flush()
+}
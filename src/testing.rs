@@ -0,0 +1,51 @@
+// helpers shared by the tests to build models with deterministic weights. The integration tests
+// include this file too, so it only depends on external crates.
+
+use ndarray::{Array, Ix1, Ix2, IxDyn};
+use safetensors::tensor::{Dtype, TensorView};
+
+// same values as the pseudo_random of scripts/reference_logits.py
+pub fn pseudo_random(shape: (usize, usize), seed: usize) -> Array<f32, Ix2> {
+    Array::from_shape_fn(shape, |(i, j)| {
+        ((i * 31 + j * 17 + seed) as f32 * 0.37).sin() * 0.2
+    })
+}
+
+pub fn pseudo_random_1d(dim: usize, seed: usize) -> Array<f32, Ix1> {
+    pseudo_random((1, dim), seed).into_shape(dim).unwrap()
+}
+
+// a safetensors checkpoint of the tensors in the given order, tensor k holds the pseudo random
+// values of seed 7 * k + 1 changed by init, like the checkpoints of scripts/reference_logits.py
+pub fn tiny_checkpoint(
+    shapes: &[(String, Vec<usize>)],
+    init: impl Fn(&str, Array<f32, IxDyn>) -> Array<f32, IxDyn>,
+) -> Vec<u8> {
+    let data: Vec<Vec<u8>> = shapes
+        .iter()
+        .enumerate()
+        .map(|(k, (name, shape))| {
+            let seed = 7 * k + 1;
+            let values = match shape[..] {
+                [d] => pseudo_random_1d(d, seed).into_dyn(),
+                [r, c] => pseudo_random((r, c), seed).into_dyn(),
+                _ => panic!("{} is neither a vector nor a matrix", name),
+            };
+            init(name, values)
+                .iter()
+                .flat_map(|x| x.to_le_bytes())
+                .collect()
+        })
+        .collect();
+    let tensors = shapes
+        .iter()
+        .zip(data.iter())
+        .map(|((name, shape), data)| {
+            (
+                name.as_str(),
+                TensorView::new(Dtype::F32, shape.clone(), data).unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    safetensors::serialize(tensors, &None).unwrap()
+}
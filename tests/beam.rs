@@ -0,0 +1,131 @@
+mod common;
+
+use rusty_llm::beam::{BeamSearch, BeamSearchConfig};
+use rusty_llm::generation::{FinishReason, GenerationConfig, Generator};
+use rusty_llm::model::CausalLM;
+
+// log probability of the generated tokens computed without any cache
+fn logprob(gpt: &rusty_llm::gpt2::GPT<f32>, prompt: &[usize], generated: &[usize]) -> f32 {
+    let ids: Vec<usize> = prompt.iter().chain(generated).copied().collect();
+    let logits = gpt.forward(&ids);
+    generated
+        .iter()
+        .enumerate()
+        .map(|(i, &id)| {
+            let row = logits.row(prompt.len() + i - 1);
+            let max = row.fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+            let sum: f32 = row.iter().map(|x| (x - max).exp()).sum();
+            row[id] - max - sum.ln()
+        })
+        .sum()
+}
+
+#[test]
+fn test_single_beam_is_greedy() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+
+    let config = BeamSearchConfig {
+        num_beams: 1,
+        max_tokens: 6,
+        ..Default::default()
+    };
+    let hypotheses = BeamSearch::new(&gpt, &tokenizer)
+        .search("the cat sat", &config)
+        .unwrap();
+
+    let greedy = GenerationConfig {
+        max_tokens: 6,
+        ..Default::default()
+    };
+    let tokens: Vec<_> = Generator::new(&gpt, &tokenizer)
+        .stream("the cat sat", &greedy)
+        .unwrap()
+        .collect();
+
+    assert_eq!(hypotheses.len(), 1);
+    let ids: Vec<usize> = tokens.iter().map(|token| token.id).collect();
+    let text: String = tokens.iter().map(|token| token.text.as_str()).collect();
+    assert_eq!(hypotheses[0].ids, ids);
+    assert_eq!(hypotheses[0].text, text);
+    assert_eq!(hypotheses[0].finish_reason, FinishReason::Length);
+}
+
+#[test]
+fn test_n_best() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let search = BeamSearch::new(&gpt, &tokenizer);
+
+    let config = BeamSearchConfig {
+        num_beams: 4,
+        num_return: 3,
+        max_tokens: 5,
+        length_penalty: 0.0,
+        ..Default::default()
+    };
+    let hypotheses = search.search_ids(vec![8, 9], &config).unwrap();
+    assert_eq!(hypotheses.len(), 3);
+
+    for (i, hypothesis) in hypotheses.iter().enumerate() {
+        assert_eq!(hypothesis.ids.len(), 5);
+        assert_eq!(hypothesis.score, hypothesis.logprob);
+        if i > 0 {
+            assert!(hypothesis.score <= hypotheses[i - 1].score);
+            assert_ne!(hypothesis.ids, hypotheses[i - 1].ids);
+        }
+        // the beams share their cache rows, their scores must match a forward pass from scratch
+        let expected = logprob(&gpt, &[8, 9], &hypothesis.ids);
+        assert!((hypothesis.logprob - expected).abs() < 1e-4);
+    }
+
+    // the best beam is at least as likely as the greedy sequence
+    let greedy = search
+        .search_ids(
+            vec![8, 9],
+            &BeamSearchConfig {
+                num_beams: 1,
+                ..config.clone()
+            },
+        )
+        .unwrap();
+    assert!(hypotheses[0].logprob >= greedy[0].logprob - 1e-6);
+}
+
+#[test]
+fn test_eos_and_early_stopping() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let search = BeamSearch::new(&gpt, &tokenizer);
+
+    let first = gpt.generate(&[1, 2]);
+    let config = BeamSearchConfig {
+        num_beams: 2,
+        num_return: 2,
+        max_tokens: 8,
+        eos_tokens: Some(vec![first]),
+        early_stopping: true,
+        ..Default::default()
+    };
+    let hypotheses = search.search("hello world", &config).unwrap();
+    assert_eq!(hypotheses.len(), 2);
+
+    let eos = hypotheses
+        .iter()
+        .find(|hypothesis| hypothesis.finish_reason == FinishReason::Eos)
+        .unwrap();
+    assert_eq!(eos.ids.last(), Some(&first));
+    assert!(!eos.text.contains(common::WORDS[first]));
+    for hypothesis in &hypotheses {
+        assert!(hypothesis.ids.len() <= 8);
+        let expected = hypothesis.logprob / hypothesis.ids.len() as f32;
+        assert!((hypothesis.score - expected).abs() < 1e-6);
+    }
+
+    // the context of the tiny model ends after 64 tokens
+    let prompt = vec!["dog"; 62].join(" ");
+    let hypotheses = search.search(&prompt, &config).unwrap();
+    assert!(hypotheses
+        .iter()
+        .all(|hypothesis| hypothesis.ids.len() <= 3));
+}
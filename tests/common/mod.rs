@@ -0,0 +1,89 @@
+// helpers shared by the integration tests, they build a tiny model with deterministic weights
+// so the tests don't need the real GPT-2 checkpoint.
+
+use rusty_llm::config::GPTConfig;
+use rusty_llm::gpt2::GPT;
+use safetensors::SafeTensors;
+use std::str::FromStr;
+use tokenizers::Tokenizer;
+
+pub const WORDS: [&str; 16] = [
+    "<unk>", "hello", "world", "the", "cat", "sat", "on", "mat", "a", "dog", "ran", "and", "is",
+    "big", "small", "?",
+];
+
+pub fn tiny_tokenizer() -> Tokenizer {
+    let vocab: Vec<String> = WORDS
+        .iter()
+        .enumerate()
+        .map(|(i, word)| format!("\"{}\": {}", word, i))
+        .collect();
+    let json = format!(
+        r#"{{
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": {{"type": "Whitespace"}},
+            "post_processor": null,
+            "decoder": null,
+            "model": {{"type": "WordLevel", "vocab": {{{}}}, "unk_token": "<unk>"}}
+        }}"#,
+        vocab.join(", ")
+    );
+    Tokenizer::from_str(&json).unwrap()
+}
+
+// the deterministic weights of the tests of the library
+#[path = "../../src/testing.rs"]
+mod testing;
+
+// a GPT-2 of two blocks loaded from a tiny checkpoint with the Hugging Face names
+pub fn tiny_gpt() -> GPT<f32> {
+    let (embed_dim, vocab_size, n_positions) = (8, WORDS.len(), 64);
+    let mut shapes = vec![
+        ("wte.weight".to_string(), vec![vocab_size, embed_dim]),
+        ("wpe.weight".to_string(), vec![n_positions, embed_dim]),
+    ];
+    for l in 0..2 {
+        let layer = |name: &str, shape: Vec<usize>| (format!("h.{}.{}", l, name), shape);
+        shapes.extend([
+            layer("ln_1.weight", vec![embed_dim]),
+            layer("ln_1.bias", vec![embed_dim]),
+            layer("attn.c_attn.weight", vec![embed_dim, 3 * embed_dim]),
+            layer("attn.c_attn.bias", vec![3 * embed_dim]),
+            layer("attn.c_proj.weight", vec![embed_dim, embed_dim]),
+            layer("attn.c_proj.bias", vec![embed_dim]),
+            layer("ln_2.weight", vec![embed_dim]),
+            layer("ln_2.bias", vec![embed_dim]),
+            layer("mlp.c_fc.weight", vec![embed_dim, 4 * embed_dim]),
+            layer("mlp.c_fc.bias", vec![4 * embed_dim]),
+            layer("mlp.c_proj.weight", vec![4 * embed_dim, embed_dim]),
+            layer("mlp.c_proj.bias", vec![embed_dim]),
+        ]);
+    }
+    shapes.push(("ln_f.weight".to_string(), vec![embed_dim]));
+    shapes.push(("ln_f.bias".to_string(), vec![embed_dim]));
+
+    // the layer norm weights are around 1
+    let buffer = testing::tiny_checkpoint(&shapes, |name, x| {
+        match name.ends_with("ln_1.weight")
+            || name.ends_with("ln_2.weight")
+            || name == "ln_f.weight"
+        {
+            true => x + 1.0,
+            false => x,
+        }
+    });
+    let config = GPTConfig {
+        n_layer: 2,
+        n_head: 2,
+        n_embd: embed_dim,
+        n_positions,
+        vocab_size,
+        ..Default::default()
+    };
+    let tensors = SafeTensors::deserialize(&buffer).unwrap();
+    GPT::<f32>::load_from_safe_tensors(&tensors, &config).unwrap()
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use rusty_llm::error::GenerateError;
+use rusty_llm::eval::{perplexity, Example, Scorer};
+use rusty_llm::model::CausalLM;
+
+// negative log likelihood of ids[1..] in a single forward pass
+fn nll(gpt: &rusty_llm::gpt2::GPT<f32>, ids: &[usize]) -> f64 {
+    let logits = gpt.forward(ids);
+    (1..ids.len())
+        .map(|i| {
+            let row = logits.row(i - 1);
+            let max = row.fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+            let sum: f32 = row.iter().map(|x| (x - max).exp()).sum();
+            (max + sum.ln() - row[ids[i]]) as f64
+        })
+        .sum()
+}
+
+#[test]
+fn test_single_window() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let text = "the cat sat on the mat and a dog ran";
+    let encoding = tokenizer.encode(text, false).unwrap();
+    let ids: Vec<usize> = encoding.get_ids().iter().map(|&id| id as usize).collect();
+    assert_eq!(ids, [3, 4, 5, 6, 3, 7, 11, 8, 9, 10]);
+
+    let result = perplexity(&gpt, &ids, 64, 32).unwrap();
+    assert_eq!(result.tokens, 9);
+    assert!((result.nll - nll(&gpt, &ids)).abs() < 1e-4);
+    assert!((result.perplexity() - (result.nll / 9.0).exp()).abs() < 1e-6);
+    let bits_per_byte = result.nll / 2f64.ln() / text.len() as f64;
+    assert!((result.bits_per_byte(text.len()) - bits_per_byte).abs() < 1e-9);
+}
+
+#[test]
+fn test_strided_windows() {
+    let gpt = common::tiny_gpt();
+    let ids: Vec<usize> = (0..100).map(|i| (i * 7 + 3) % 16).collect();
+
+    // windows that don't overlap: the first token of every window is not predicted
+    let result = perplexity(&gpt, &ids, 40, 40).unwrap();
+    assert_eq!(result.tokens, 99 - 2);
+    let expected = nll(&gpt, &ids[..40]) + nll(&gpt, &ids[40..80]) + nll(&gpt, &ids[80..]);
+    assert!((result.nll - expected).abs() < 1e-3);
+
+    // overlapping windows predict every token once, with the previous tokens as context
+    let result = perplexity(&gpt, &ids, 40, 10).unwrap();
+    assert_eq!(result.tokens, 99);
+    let second = nll(&gpt, &ids[10..50]) - nll(&gpt, &ids[10..40]);
+    let first = nll(&gpt, &ids[..40]);
+    let partial = perplexity(&gpt, &ids[..50], 40, 10).unwrap();
+    assert!((partial.nll - first - second).abs() < 1e-3);
+
+    assert_eq!(
+        perplexity(&gpt, &ids, 65, 10).err(),
+        Some(GenerateError::ContextOverflow { len: 65, max: 64 })
+    );
+    assert_eq!(perplexity(&gpt, &ids[..1], 64, 64).unwrap().tokens, 0);
+
+    assert_eq!(
+        perplexity(&gpt, &ids, 40, 0).err(),
+        Some(GenerateError::InvalidStride {
+            stride: 0,
+            window: 40
+        })
+    );
+    assert_eq!(
+        perplexity(&gpt, &ids, 40, 41).err(),
+        Some(GenerateError::InvalidStride {
+            stride: 41,
+            window: 40
+        })
+    );
+}
+
+#[test]
+fn test_score() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let scorer = Scorer::new(&gpt, &tokenizer);
+
+    let score = scorer.score("the cat", " sat on the mat").unwrap();
+    assert_eq!(score.token_logprobs.len(), 4);
+    let sum: f32 = score.token_logprobs.iter().sum();
+    assert!((score.logprob - sum).abs() < 1e-6);
+    let expected = nll(&gpt, &[3, 4]) - nll(&gpt, &[3, 4, 5, 6, 3, 7]);
+    assert!((score.logprob as f64 - expected).abs() < 1e-4);
+
+    // the continuations of different lengths are padded in the batch
+    let continuations = [" sat on the mat", " ran", "", " is big and small"];
+    let scores = scorer.score_batch("the cat", &continuations).unwrap();
+    assert_eq!(scores.len(), 4);
+    for (continuation, batched) in continuations.iter().zip(&scores) {
+        let single = scorer.score("the cat", continuation).unwrap();
+        assert_eq!(batched.token_logprobs.len(), single.token_logprobs.len());
+        assert!((batched.logprob - single.logprob).abs() < 1e-4);
+        assert_eq!(batched.is_greedy, single.is_greedy);
+    }
+    assert_eq!(scores[2].logprob, 0.0);
+    assert!(scores[2].is_greedy);
+
+    assert_eq!(
+        scorer.score("", " cat").err(),
+        Some(GenerateError::EmptyPrompt)
+    );
+}
+
+#[test]
+fn test_score_is_greedy() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let scorer = Scorer::new(&gpt, &tokenizer);
+
+    let mut ids = vec![8, 9];
+    for _ in 0..3 {
+        ids.push(gpt.generate(&ids));
+    }
+    let greedy = ids[2..].to_vec();
+    let mut other = greedy.clone();
+    other[1] = (other[1] + 1) % common::WORDS.len();
+
+    let scores = scorer.score_batch_ids(&[8, 9], &[greedy, other]).unwrap();
+    assert!(scores[0].is_greedy);
+    assert!(!scores[1].is_greedy);
+    assert!(scores[0].token_logprobs[1] > scores[1].token_logprobs[1]);
+}
+
+#[test]
+fn test_predict() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let scorer = Scorer::new(&gpt, &tokenizer);
+
+    let example = Example {
+        context: "the cat".to_string(),
+        choices: vec![
+            "sat on the mat".to_string(),
+            " ran".to_string(),
+            "is big".to_string(),
+        ],
+        label: 1,
+    };
+    let prediction = scorer.predict(&example).unwrap();
+    assert_eq!(prediction.label, 1);
+    assert_eq!(prediction.logprobs.len(), 3);
+
+    let scored = [" sat on the mat", " ran", " is big"];
+    let scores = scorer.score_batch("the cat", &scored).unwrap();
+    for (logprob, score) in prediction.logprobs.iter().zip(&scores) {
+        assert!((logprob - score.logprob).abs() < 1e-6);
+    }
+
+    let best = |values: Vec<f32>| {
+        (0..values.len())
+            .max_by(|&a, &b| values[a].total_cmp(&values[b]))
+            .unwrap()
+    };
+    assert_eq!(prediction.prediction, best(prediction.logprobs.clone()));
+    let normalized = prediction
+        .logprobs
+        .iter()
+        .zip(scored)
+        .map(|(logprob, choice)| logprob / choice.len() as f32)
+        .collect();
+    assert_eq!(prediction.normalized_prediction, best(normalized));
+}
@@ -0,0 +1,262 @@
+mod common;
+
+use rusty_llm::error::GenerateError;
+use rusty_llm::generation::{FinishReason, GeneratedToken, GenerationConfig, Generator};
+use rusty_llm::gpt2::ContextOverflow;
+use rusty_llm::model::CausalLM;
+use rusty_llm::sampling::{Greedy, SamplerConfig};
+
+fn greedy(max_tokens: usize) -> GenerationConfig {
+    GenerationConfig {
+        max_tokens,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_stream_matches_generate() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let generator = Generator::new(&gpt, &tokenizer);
+
+    let tokens: Vec<_> = generator
+        .stream("the cat sat", &greedy(5))
+        .unwrap()
+        .collect();
+    assert_eq!(tokens.len(), 5);
+
+    let mut ids = vec![3, 4, 5];
+    for (i, token) in tokens.iter().enumerate() {
+        assert_eq!(token.id, gpt.generate(&ids));
+        // the tiny tokenizer has no decoder, its words are joined with spaces
+        assert_eq!(token.text, format!(" {}", common::WORDS[token.id]));
+        assert!(token.logprob <= 0.0);
+
+        let last = i == tokens.len() - 1;
+        assert_eq!(token.finish_reason.is_some(), last);
+        ids.push(token.id);
+    }
+    assert_eq!(tokens[4].finish_reason, Some(FinishReason::Length));
+}
+
+#[test]
+fn test_top_logprobs() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let generator = Generator::new(&gpt, &tokenizer);
+
+    let config = GenerationConfig {
+        top_logprobs: 3,
+        ..greedy(4)
+    };
+    for token in generator.stream("the cat sat", &config).unwrap() {
+        let top = &token.top_logprobs;
+        assert_eq!(top.len(), 3);
+        // greedy decoding picks the most likely token
+        assert_eq!(top[0].id, token.id);
+        assert_eq!(top[0].logprob, token.logprob);
+        assert_eq!(top[1].text, common::WORDS[top[1].id]);
+        assert!(top[0].logprob >= top[1].logprob && top[1].logprob >= top[2].logprob);
+    }
+
+    let tokens: Vec<_> = generator
+        .stream("the cat sat", &greedy(4))
+        .unwrap()
+        .collect();
+    assert!(tokens.iter().all(|token| token.top_logprobs.is_empty()));
+}
+
+#[test]
+fn test_eos_token() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let generator = Generator::new(&gpt, &tokenizer);
+
+    let first = gpt.generate(&[1, 2]);
+    let config = GenerationConfig {
+        eos_tokens: Some(vec![first]),
+        ..greedy(5)
+    };
+    let tokens: Vec<_> = generator.stream("hello world", &config).unwrap().collect();
+
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0].id, first);
+    assert_eq!(tokens[0].text, "");
+    assert_eq!(tokens[0].finish_reason, Some(FinishReason::Eos));
+}
+
+#[test]
+fn test_stop_strings() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let generator = Generator::new(&gpt, &tokenizer);
+
+    let tokens: Vec<_> = generator.stream("a dog", &greedy(8)).unwrap().collect();
+    let full: String = tokens.iter().map(|token| token.text.as_str()).collect();
+
+    // the stop string starts in the middle of the third token and ends in the fourth one
+    let third = tokens[2].text.as_str();
+    let fourth = tokens[3].text.as_str();
+    let stop = format!("{}{}", &third[2..], &fourth[..2]);
+    let expected = &full[..full.find(&stop).unwrap()];
+
+    let config = GenerationConfig {
+        stop: vec!["not generated".to_string(), stop],
+        ..greedy(8)
+    };
+    let stopped: Vec<_> = generator.stream("a dog", &config).unwrap().collect();
+
+    let text: String = stopped.iter().map(|token| token.text.as_str()).collect();
+    assert_eq!(text, expected);
+    assert!(stopped.len() <= 4);
+    assert_eq!(
+        stopped.last().unwrap().finish_reason,
+        Some(FinishReason::Stop)
+    );
+
+    // text that may start a stop string is held back until it is known not to
+    let config = GenerationConfig {
+        stop: vec![format!("{}not generated", fourth)],
+        ..greedy(8)
+    };
+    let held: Vec<_> = generator.stream("a dog", &config).unwrap().collect();
+    let text: String = held.iter().map(|token| token.text.as_str()).collect();
+    assert_eq!(text, full);
+    assert_eq!(
+        held.last().unwrap().finish_reason,
+        Some(FinishReason::Length)
+    );
+}
+
+#[test]
+fn test_seeded_sampling_is_reproducible() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let generator = Generator::new(&gpt, &tokenizer);
+
+    let config = GenerationConfig {
+        sampler: SamplerConfig {
+            temperature: 1.5,
+            seed: Some(7),
+            ..Default::default()
+        },
+        ..greedy(8)
+    };
+    let run = || -> Vec<usize> {
+        generator
+            .stream("a dog", &config)
+            .unwrap()
+            .map(|token| token.id)
+            .collect()
+    };
+    assert_eq!(run(), run());
+}
+
+#[test]
+fn test_seeded_batch_rows_sample_differently() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let generator = Generator::new(&gpt, &tokenizer);
+
+    let config = GenerationConfig {
+        sampler: SamplerConfig {
+            temperature: 1.5,
+            seed: Some(7),
+            ..Default::default()
+        },
+        ..greedy(8)
+    };
+    let ids = |tokens: &Vec<GeneratedToken>| -> Vec<usize> {
+        tokens.iter().map(|token| token.id).collect()
+    };
+
+    let batch = generator.generate_batch(&["a dog"; 4], &config).unwrap();
+    let rows: Vec<Vec<usize>> = batch.iter().map(ids).collect();
+    assert_ne!(rows[0], rows[1]);
+
+    // the first row samples with the seed itself and the batch is reproducible
+    let stream: Vec<GeneratedToken> = generator.stream("a dog", &config).unwrap().collect();
+    assert_eq!(rows[0], ids(&stream));
+    let again = generator.generate_batch(&["a dog"; 4], &config).unwrap();
+    assert_eq!(again.iter().map(ids).collect::<Vec<_>>(), rows);
+}
+
+#[test]
+fn test_context_limits() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let generator = Generator::new(&gpt, &tokenizer);
+
+    // 62 prompt tokens leave room for 3 tokens in the 64 positions of the tiny model
+    let prompt = vec!["dog"; 62].join(" ");
+    let tokens: Vec<_> = generator.stream(&prompt, &greedy(10)).unwrap().collect();
+    assert_eq!(tokens.len(), 3);
+    assert_eq!(tokens[2].finish_reason, Some(FinishReason::Length));
+
+    let config = GenerationConfig {
+        context_overflow: ContextOverflow::SlidingWindow,
+        ..greedy(10)
+    };
+    let stream = generator.stream(&prompt, &config).unwrap();
+    assert_eq!(stream.count(), 10);
+
+    let prompt = vec!["dog"; 65].join(" ");
+    let error = generator.stream(&prompt, &greedy(10)).err();
+    assert_eq!(
+        error,
+        Some(GenerateError::ContextOverflow { len: 65, max: 64 })
+    );
+
+    assert_eq!(
+        generator.stream("", &greedy(10)).err(),
+        Some(GenerateError::EmptyPrompt)
+    );
+    assert_eq!(generator.stream("hello", &greedy(0)).unwrap().count(), 0);
+
+    // the stream gives the same tokens as the lower level api
+    let mut ids = vec![1];
+    let mut cache = gpt.new_cache();
+    let next = gpt
+        .generate_next(&ids, &mut cache, &mut Greedy, ContextOverflow::Error)
+        .unwrap();
+    ids.push(next);
+    let stream = generator.stream_ids(vec![1], &greedy(1)).unwrap();
+    assert_eq!(stream.map(|token| token.id).collect::<Vec<_>>(), &ids[1..]);
+}
+
+#[test]
+fn test_batch_matches_streams() {
+    let gpt = common::tiny_gpt();
+    let tokenizer = common::tiny_tokenizer();
+    let generator = Generator::new(&gpt, &tokenizer);
+
+    let prompts = ["the cat sat on the mat", "hello", "a dog ran and"];
+    let first = gpt.generate(&[1]);
+    let config = GenerationConfig {
+        // the second sequence stops after its first token, the others keep going around it
+        eos_tokens: Some(vec![first]),
+        ..greedy(6)
+    };
+
+    let batch = generator.generate_batch(&prompts, &config).unwrap();
+    assert_eq!(batch.len(), 3);
+
+    for (prompt, generated) in prompts.iter().zip(&batch) {
+        let stream: Vec<_> = generator.stream(prompt, &config).unwrap().collect();
+        assert_eq!(generated.len(), stream.len());
+        for (a, b) in generated.iter().zip(&stream) {
+            assert_eq!(a.id, b.id);
+            assert_eq!(a.text, b.text);
+            assert_eq!(a.finish_reason, b.finish_reason);
+            assert!((a.logprob - b.logprob).abs() < 1e-4);
+        }
+    }
+    assert_eq!(batch[1].len(), 1);
+    assert_eq!(batch[1][0].finish_reason, Some(FinishReason::Eos));
+
+    let long = vec!["dog"; 65].join(" ");
+    assert_eq!(
+        generator.generate_batch(&["hello", &long], &config).err(),
+        Some(GenerateError::ContextOverflow { len: 65, max: 64 })
+    );
+}
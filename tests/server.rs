@@ -0,0 +1,244 @@
+mod common;
+
+use rusty_llm::server::Server;
+use serde_json::Value;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+
+fn start_server() -> SocketAddr {
+    let server = Server::bind(
+        "127.0.0.1:0",
+        common::tiny_gpt(),
+        common::tiny_tokenizer(),
+        "tiny",
+    )
+    .unwrap();
+    let addr = server.local_addr();
+    thread::spawn(move || server.run());
+    addr
+}
+
+fn post(addr: SocketAddr, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+fn events(body: &str) -> Vec<String> {
+    body.split("\n\n")
+        .filter_map(|event| event.strip_prefix("data: "))
+        .map(|data| data.to_string())
+        .collect()
+}
+
+#[test]
+fn test_completion() {
+    let addr = start_server();
+
+    let (status, body) = post(
+        addr,
+        "/v1/completions",
+        r#"{"model": "tiny", "prompt": "the cat sat", "max_tokens": 5, "temperature": 0}"#,
+    );
+    assert_eq!(status, 200);
+
+    let response: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(response["object"], "text_completion");
+    assert_eq!(response["model"], "tiny");
+    assert_eq!(response["choices"][0]["finish_reason"], "length");
+    assert_eq!(response["usage"]["prompt_tokens"], 3);
+    assert_eq!(response["usage"]["completion_tokens"], 5);
+    assert_eq!(response["usage"]["total_tokens"], 8);
+    assert!(response["choices"][0]["text"].is_string());
+}
+
+#[test]
+fn test_completion_stream_matches_blocking() {
+    let addr = start_server();
+    let request =
+        r#"{"prompt": "hello world", "max_tokens": 4, "temperature": 0, "stream": STREAM}"#;
+
+    let (_, body) = post(addr, "/v1/completions", &request.replace("STREAM", "false"));
+    let blocking: Value = serde_json::from_str(&body).unwrap();
+
+    let (status, body) = post(addr, "/v1/completions", &request.replace("STREAM", "true"));
+    assert_eq!(status, 200);
+
+    let events = events(&body);
+    assert_eq!(events.last().unwrap(), "[DONE]");
+
+    let chunks: Vec<Value> = events[..events.len() - 1]
+        .iter()
+        .map(|data| serde_json::from_str(data).unwrap())
+        .collect();
+    // one chunk per token and a last one carrying the finish reason
+    assert_eq!(chunks.len(), 5);
+    assert_eq!(chunks[4]["choices"][0]["finish_reason"], "length");
+
+    let text: String = chunks
+        .iter()
+        .map(|chunk| chunk["choices"][0]["text"].as_str().unwrap())
+        .collect();
+    assert_eq!(text, blocking["choices"][0]["text"].as_str().unwrap());
+}
+
+#[test]
+fn test_chat_completion() {
+    let addr = start_server();
+
+    let request = r#"{
+        "model": "tiny",
+        "messages": [{"role": "user", "content": "is the dog big ?"}],
+        "max_tokens": 3,
+        "temperature": 0
+    }"#;
+    let (status, body) = post(addr, "/v1/chat/completions", request);
+    assert_eq!(status, 200);
+
+    let response: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(response["object"], "chat.completion");
+    assert_eq!(response["choices"][0]["message"]["role"], "assistant");
+    assert_eq!(response["usage"]["completion_tokens"], 3);
+
+    let (status, body) = post(
+        addr,
+        "/v1/chat/completions",
+        &request.replace("\"temperature\": 0", "\"temperature\": 0, \"stream\": true"),
+    );
+    assert_eq!(status, 200);
+
+    let events = events(&body);
+    let first: Value = serde_json::from_str(&events[0]).unwrap();
+    assert_eq!(first["object"], "chat.completion.chunk");
+    assert_eq!(first["choices"][0]["delta"]["role"], "assistant");
+    assert_eq!(events.last().unwrap(), "[DONE]");
+}
+
+#[test]
+fn test_stop() {
+    let addr = start_server();
+    let request = r#"{"prompt": "the cat sat", "max_tokens": 8, "temperature": 0}"#;
+
+    let (_, body) = post(addr, "/v1/completions", request);
+    let response: Value = serde_json::from_str(&body).unwrap();
+    let full = response["choices"][0]["text"].as_str().unwrap().to_string();
+
+    // stop on the second generated word
+    let stop = full.split(' ').nth(2).unwrap();
+    for stop in [format!(r#""{}""#, stop), format!(r#"["?!", "{}"]"#, stop)] {
+        let (status, body) = post(
+            addr,
+            "/v1/completions",
+            &request.replace(
+                "\"temperature\"",
+                &format!(r#""stop": {}, "temperature""#, stop),
+            ),
+        );
+        assert_eq!(status, 200);
+
+        let response: Value = serde_json::from_str(&body).unwrap();
+        let text = response["choices"][0]["text"].as_str().unwrap();
+        assert!(full.starts_with(text));
+        assert!(text.len() < full.len());
+        assert_eq!(response["choices"][0]["finish_reason"], "stop");
+    }
+}
+
+#[test]
+fn test_concurrent_clients() {
+    let addr = start_server();
+
+    // more clients than http threads, the last ones wait for a free thread
+    let clients: Vec<_> = (0..12)
+        .map(|_| {
+            thread::spawn(move || {
+                post(
+                    addr,
+                    "/v1/completions",
+                    r#"{"prompt": "a dog ran", "max_tokens": 6, "temperature": 0}"#,
+                )
+            })
+        })
+        .collect();
+
+    let texts: Vec<Value> = clients
+        .into_iter()
+        .map(|client| {
+            let (status, body) = client.join().unwrap();
+            assert_eq!(status, 200);
+            let response: Value = serde_json::from_str(&body).unwrap();
+            response["choices"][0]["text"].clone()
+        })
+        .collect();
+
+    // the requests are queued on the same model, greedy decoding gives the same text to everyone
+    assert!(texts.iter().all(|text| *text == texts[0]));
+}
+
+#[test]
+fn test_context_full() {
+    let addr = start_server();
+
+    // 60 prompt tokens leave room for 5 more tokens in the 64 positions of the tiny model
+    let prompt = vec!["dog"; 60].join(" ");
+    let (status, body) = post(
+        addr,
+        "/v1/completions",
+        &format!(
+            r#"{{"prompt": "{}", "max_tokens": 10, "temperature": 0}}"#,
+            prompt
+        ),
+    );
+    assert_eq!(status, 200);
+
+    let response: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(response["usage"]["completion_tokens"], 5);
+    assert_eq!(response["choices"][0]["finish_reason"], "length");
+}
+
+#[test]
+fn test_invalid_requests() {
+    let addr = start_server();
+
+    let (status, body) = post(addr, "/v1/completions", r#"{"max_tokens": 3}"#);
+    assert_eq!(status, 400);
+    let response: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(response["error"]["type"], "invalid_request_error");
+
+    // the worker reports the prompts it can't start from, before anything is streamed
+    let long_prompt = vec!["the"; 65].join(" ");
+    for stream in [false, true] {
+        let (status, body) = post(
+            addr,
+            "/v1/completions",
+            &format!(r#"{{"prompt": "{}", "stream": {}}}"#, long_prompt, stream),
+        );
+        assert_eq!(status, 400);
+        let response: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            response["error"]["message"],
+            "65 tokens don't fit in the context of 64 positions"
+        );
+    }
+    let (status, body) = post(addr, "/v1/completions", r#"{"prompt": ""}"#);
+    assert_eq!(status, 400);
+    let response: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(response["error"]["message"], "the prompt is empty");
+
+    let (status, _) = post(addr, "/v1/embeddings", "{}");
+    assert_eq!(status, 404);
+}